@@ -1,7 +1,11 @@
 use core::fmt;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 
-use crate::token::token::Token;
+use crate::compiler::symbol_table::Binding;
+use crate::token::token::{Token, TokenType};
 use downcast_rs::{impl_downcast, Downcast};
+use num_bigint::BigInt;
 pub trait Node {
     fn token_literal(&self) -> String;
     fn to_string(&self) -> String;
@@ -73,10 +77,12 @@ impl Program {
         ))
     }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Identifier {
     pub token: Token,
     pub value: String,
+    // 由 resolver 填入的绑定，未解析时为 None
+    pub binding: Cell<Option<Binding>>,
 }
 
 impl Node for Identifier {
@@ -92,7 +98,11 @@ impl Expression for Identifier {
 }
 impl Identifier {
     pub fn new(token: Token, value: String) -> Identifier {
-        Identifier { token, value }
+        Identifier {
+            token,
+            value,
+            binding: Cell::new(None),
+        }
     }
 }
 
@@ -149,6 +159,11 @@ impl LetStatement {
     pub fn new(token: Token, name: Identifier, value: Box<dyn Expression>) -> LetStatement {
         LetStatement { token, name, value }
     }
+
+    // const 和 let 共用一种语句，由关键字区分
+    pub fn is_const(&self) -> bool {
+        self.token.typ == TokenType::CONST
+    }
 }
 
 pub struct ReturnStatement {
@@ -207,8 +222,113 @@ impl ExpressionStatement {
     }
 }
 
+// while (condition) { body }，是语句而不是表达式，本身没有值
+pub struct WhileStatement {
+    pub token: Token,
+    pub condition: Box<dyn Expression>,
+    pub body: BlockStatement,
+}
+
+impl Node for WhileStatement {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        let mut out = String::from("while");
+        out.push_str(self.condition.to_string().as_str());
+        out.push_str(" ");
+        out.push_str(self.body.to_string().as_str());
+        out
+    }
+}
+
+impl Statement for WhileStatement {
+    fn statement_node(&self) {}
+}
+
+impl WhileStatement {
+    pub fn new(
+        token: Token,
+        condition: Box<dyn Expression>,
+        body: BlockStatement,
+    ) -> WhileStatement {
+        WhileStatement {
+            token,
+            condition,
+            body,
+        }
+    }
+}
+
+// for (value in iterable) { body } 或 for (key, value in iterable) { body }，key 是迭代协议返回的键，目前都是下标
+pub struct ForStatement {
+    pub token: Token,
+    pub key: Option<Identifier>,
+    pub value: Identifier,
+    pub iterable: Box<dyn Expression>,
+    pub body: BlockStatement,
+}
+
+impl Node for ForStatement {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        let mut out = String::from("for(");
+        if let Some(key) = &self.key {
+            out.push_str(key.to_string().as_str());
+            out.push_str(", ");
+        }
+        out.push_str(self.value.to_string().as_str());
+        out.push_str(" in ");
+        out.push_str(self.iterable.to_string().as_str());
+        out.push_str(") ");
+        out.push_str(self.body.to_string().as_str());
+        out
+    }
+}
+
+impl Statement for ForStatement {
+    fn statement_node(&self) {}
+}
+
+// break 和 continue 只能出现在循环体中，由解析器检查
+pub struct BreakStatement {
+    pub token: Token,
+}
+
+impl Node for BreakStatement {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        String::from("break;")
+    }
+}
+
+impl Statement for BreakStatement {
+    fn statement_node(&self) {}
+}
+
+pub struct ContinueStatement {
+    pub token: Token,
+}
+
+impl Node for ContinueStatement {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        String::from("continue;")
+    }
+}
+
+impl Statement for ContinueStatement {
+    fn statement_node(&self) {}
+}
+
 pub struct IntegerLiteral {
-    token: Token,
+    pub token: Token,
     pub value: i64,
 }
 
@@ -230,6 +350,108 @@ impl IntegerLiteral {
         IntegerLiteral { token, value }
     }
 }
+
+pub struct FloatLiteral {
+    pub token: Token,
+    pub value: f64,
+}
+
+impl Node for FloatLiteral {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        self.token.literal.clone()
+    }
+}
+
+impl Expression for FloatLiteral {
+    fn expression_node(&self) {}
+}
+
+pub struct StringLiteral {
+    pub token: Token,
+    pub value: String,
+}
+
+impl Node for StringLiteral {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        format!("\"{}\"", escape(&self.value))
+    }
+}
+
+impl Expression for StringLiteral {
+    fn expression_node(&self) {}
+}
+
+// 带插值的字符串，如 "a${x}b"，strings 比 expressions 多一个，两者交替拼接
+pub struct TemplateLiteral {
+    pub token: Token,
+    pub strings: Vec<String>,
+    pub expressions: Vec<Box<dyn Expression>>,
+}
+
+impl Node for TemplateLiteral {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        let mut out = String::from("\"");
+        for (s, e) in self.strings.iter().zip(self.expressions.iter()) {
+            out.push_str(&escape(s));
+            out.push_str(&format!("${{{}}}", e.to_string()));
+        }
+        if let Some(last) = self.strings.last() {
+            out.push_str(&escape(last));
+        }
+        out.push('"');
+        out
+    }
+}
+
+impl Expression for TemplateLiteral {
+    fn expression_node(&self) {}
+}
+
+// 把字符串写回源码中的形式
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// 超出 64 位的带 n 后缀的整数字面量，如 18446744073709551616n
+pub struct BigIntegerLiteral {
+    pub token: Token,
+    pub value: BigInt,
+}
+
+impl Node for BigIntegerLiteral {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        self.token.literal.clone()
+    }
+}
+
+impl Expression for BigIntegerLiteral {
+    fn expression_node(&self) {}
+}
 pub struct PrefixExpression {
     pub token: Token,
     pub operator: String,
@@ -263,6 +485,39 @@ impl PrefixExpression {
     }
 }
 
+// target = value 或 target += value 等复合赋值，值是赋给变量的新值
+pub struct AssignExpression {
+    pub token: Token,
+    pub target: Identifier,
+    pub operator: String,
+    pub value: Box<dyn Expression>,
+}
+
+impl Node for AssignExpression {
+    fn token_literal(&self) -> String {
+        self.token.literal.clone()
+    }
+    fn to_string(&self) -> String {
+        format!(
+            "({} {} {})",
+            self.target.to_string(),
+            self.operator,
+            self.value.to_string()
+        )
+    }
+}
+
+impl Expression for AssignExpression {
+    fn expression_node(&self) {}
+}
+
+impl AssignExpression {
+    // 复合赋值对应的二元运算符，普通赋值为 None
+    pub fn binary_operator(&self) -> Option<&str> {
+        self.operator.strip_suffix('=').filter(|op| !op.is_empty())
+    }
+}
+
 pub struct InfixExpression {
     pub token: Token,
     pub left: Box<dyn Expression>,
@@ -387,7 +642,12 @@ impl BlockStatement {
 pub struct FunctionLiteral {
     pub token: Token,
     pub params: Vec<Identifier>,
-    pub body: BlockStatement
+    pub body: Rc<BlockStatement>,
+    // 由 resolver 填入：函数的局部变量个数，捕获的自由变量在外层作用域中的绑定，
+    // 以及被内层函数捕获的局部变量的下标
+    pub num_locals: Cell<usize>,
+    pub free: RefCell<Vec<Binding>>,
+    pub captured: RefCell<Vec<usize>>,
 }
 
 impl Node for FunctionLiteral {
@@ -418,7 +678,14 @@ impl Expression for FunctionLiteral {
 
 impl FunctionLiteral {
     pub fn new(token: Token, params: Vec<Identifier>, body: BlockStatement) -> FunctionLiteral {
-        FunctionLiteral { token, params, body }
+        FunctionLiteral {
+            token,
+            params,
+            body: Rc::new(body),
+            num_locals: Cell::new(0),
+            free: RefCell::new(Vec::new()),
+            captured: RefCell::new(Vec::new()),
+        }
     }
 }
 
@@ -0,0 +1,282 @@
+// 字节码指令定义：每条指令由 1 字节操作码和若干大端序操作数组成
+pub type Instructions = Vec<u8>;
+
+#[allow(clippy::enum_variant_names)]
+#[repr(u8)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Opcode {
+    OpConstant,
+    OpPop,
+    OpAdd,
+    OpSub,
+    OpMul,
+    OpDiv,
+    OpTrue,
+    OpFalse,
+    OpNull,
+    OpEqual,
+    OpNotEqual,
+    OpGreaterThan,
+    OpLessThan,
+    OpMinus,
+    OpBang,
+    OpJumpNotTruthy,
+    OpJump,
+    OpGetGlobal,
+    OpSetGlobal,
+    OpGetLocal,
+    OpSetLocal,
+    OpGetBuiltin,
+    OpGetFree,
+    OpClosure,
+    OpCall,
+    OpReturnValue,
+    OpReturn,
+    // 调用后直接返回被调函数的结果，复用当前调用帧
+    OpTailCall,
+    OpRange,
+    OpRangeInclusive,
+    // 把栈顶的集合换成遍历它的迭代器
+    OpIter,
+    // 从栈顶的迭代器取出下一项，遍历结束时跳转
+    OpIterNext,
+    OpLessEqual,
+    OpGreaterEqual,
+    OpMod,
+    OpPow,
+    OpBitAnd,
+    OpBitOr,
+    OpBitXor,
+    OpShl,
+    OpShr,
+    OpBitNot,
+    // 把栈顶的若干个值转换成字符串后依次拼接，用于字符串插值
+    OpTemplate,
+    // 把局部变量的值装进新的 Cell，之后外层函数和捕获它的闭包通过这个 Cell 读写
+    OpNewCell,
+    // 把栈顶的 Cell 换成它的值
+    OpDeref,
+    // 弹出栈顶的 Cell，再把下面的值存进去
+    OpSetCell,
+}
+
+// 按操作码的数值排列，用于从字节还原 Opcode
+const OPCODES: [Opcode; 46] = [
+    Opcode::OpConstant,
+    Opcode::OpPop,
+    Opcode::OpAdd,
+    Opcode::OpSub,
+    Opcode::OpMul,
+    Opcode::OpDiv,
+    Opcode::OpTrue,
+    Opcode::OpFalse,
+    Opcode::OpNull,
+    Opcode::OpEqual,
+    Opcode::OpNotEqual,
+    Opcode::OpGreaterThan,
+    Opcode::OpLessThan,
+    Opcode::OpMinus,
+    Opcode::OpBang,
+    Opcode::OpJumpNotTruthy,
+    Opcode::OpJump,
+    Opcode::OpGetGlobal,
+    Opcode::OpSetGlobal,
+    Opcode::OpGetLocal,
+    Opcode::OpSetLocal,
+    Opcode::OpGetBuiltin,
+    Opcode::OpGetFree,
+    Opcode::OpClosure,
+    Opcode::OpCall,
+    Opcode::OpReturnValue,
+    Opcode::OpReturn,
+    Opcode::OpTailCall,
+    Opcode::OpRange,
+    Opcode::OpRangeInclusive,
+    Opcode::OpIter,
+    Opcode::OpIterNext,
+    Opcode::OpLessEqual,
+    Opcode::OpGreaterEqual,
+    Opcode::OpMod,
+    Opcode::OpPow,
+    Opcode::OpBitAnd,
+    Opcode::OpBitOr,
+    Opcode::OpBitXor,
+    Opcode::OpShl,
+    Opcode::OpShr,
+    Opcode::OpBitNot,
+    Opcode::OpTemplate,
+    Opcode::OpNewCell,
+    Opcode::OpDeref,
+    Opcode::OpSetCell,
+];
+
+pub struct Definition {
+    pub name: &'static str,
+    // 每个操作数占用的字节数
+    pub operand_widths: &'static [usize],
+}
+
+impl Opcode {
+    pub fn from_u8(b: u8) -> Option<Opcode> {
+        OPCODES.get(b as usize).copied()
+    }
+
+    pub fn definition(&self) -> Definition {
+        let (name, operand_widths): (&'static str, &'static [usize]) = match self {
+            Opcode::OpConstant => ("OpConstant", &[2]),
+            Opcode::OpPop => ("OpPop", &[]),
+            Opcode::OpAdd => ("OpAdd", &[]),
+            Opcode::OpSub => ("OpSub", &[]),
+            Opcode::OpMul => ("OpMul", &[]),
+            Opcode::OpDiv => ("OpDiv", &[]),
+            Opcode::OpTrue => ("OpTrue", &[]),
+            Opcode::OpFalse => ("OpFalse", &[]),
+            Opcode::OpNull => ("OpNull", &[]),
+            Opcode::OpEqual => ("OpEqual", &[]),
+            Opcode::OpNotEqual => ("OpNotEqual", &[]),
+            Opcode::OpGreaterThan => ("OpGreaterThan", &[]),
+            Opcode::OpLessThan => ("OpLessThan", &[]),
+            Opcode::OpMinus => ("OpMinus", &[]),
+            Opcode::OpBang => ("OpBang", &[]),
+            Opcode::OpJumpNotTruthy => ("OpJumpNotTruthy", &[2]),
+            Opcode::OpJump => ("OpJump", &[2]),
+            Opcode::OpGetGlobal => ("OpGetGlobal", &[2]),
+            Opcode::OpSetGlobal => ("OpSetGlobal", &[2]),
+            Opcode::OpGetLocal => ("OpGetLocal", &[1]),
+            Opcode::OpSetLocal => ("OpSetLocal", &[1]),
+            Opcode::OpGetBuiltin => ("OpGetBuiltin", &[1]),
+            Opcode::OpGetFree => ("OpGetFree", &[1]),
+            // 常量池中函数的下标，以及捕获的自由变量个数
+            Opcode::OpClosure => ("OpClosure", &[2, 1]),
+            Opcode::OpCall => ("OpCall", &[1]),
+            Opcode::OpReturnValue => ("OpReturnValue", &[]),
+            Opcode::OpReturn => ("OpReturn", &[]),
+            Opcode::OpTailCall => ("OpTailCall", &[1]),
+            Opcode::OpRange => ("OpRange", &[]),
+            Opcode::OpRangeInclusive => ("OpRangeInclusive", &[]),
+            Opcode::OpIter => ("OpIter", &[]),
+            // 遍历结束后的跳转目标，以及压入栈的值的个数：1 只压入值，2 依次压入值和键
+            Opcode::OpIterNext => ("OpIterNext", &[2, 1]),
+            Opcode::OpLessEqual => ("OpLessEqual", &[]),
+            Opcode::OpGreaterEqual => ("OpGreaterEqual", &[]),
+            Opcode::OpMod => ("OpMod", &[]),
+            Opcode::OpPow => ("OpPow", &[]),
+            Opcode::OpBitAnd => ("OpBitAnd", &[]),
+            Opcode::OpBitOr => ("OpBitOr", &[]),
+            Opcode::OpBitXor => ("OpBitXor", &[]),
+            Opcode::OpShl => ("OpShl", &[]),
+            Opcode::OpShr => ("OpShr", &[]),
+            Opcode::OpBitNot => ("OpBitNot", &[]),
+            // 拼接的值的个数
+            Opcode::OpTemplate => ("OpTemplate", &[2]),
+            Opcode::OpNewCell => ("OpNewCell", &[1]),
+            Opcode::OpDeref => ("OpDeref", &[]),
+            Opcode::OpSetCell => ("OpSetCell", &[]),
+        };
+        Definition {
+            name,
+            operand_widths,
+        }
+    }
+}
+
+pub fn make(op: Opcode, operands: &[usize]) -> Vec<u8> {
+    let def = op.definition();
+    let len: usize = 1 + def.operand_widths.iter().sum::<usize>();
+    let mut ins = Vec::with_capacity(len);
+    ins.push(op as u8);
+    for (operand, width) in operands.iter().zip(def.operand_widths) {
+        match width {
+            2 => ins.extend_from_slice(&(*operand as u16).to_be_bytes()),
+            1 => ins.push(*operand as u8),
+            _ => {}
+        }
+    }
+    ins
+}
+
+// 按定义读出操作数，返回操作数和读取的字节数
+pub fn read_operands(def: &Definition, ins: &[u8]) -> (Vec<usize>, usize) {
+    let mut operands = Vec::with_capacity(def.operand_widths.len());
+    let mut offset = 0;
+    for width in def.operand_widths {
+        match width {
+            2 => operands.push(read_u16(&ins[offset..]) as usize),
+            1 => operands.push(ins[offset] as usize),
+            _ => {}
+        }
+        offset += width;
+    }
+    (operands, offset)
+}
+
+pub fn read_u16(ins: &[u8]) -> u16 {
+    u16::from_be_bytes([ins[0], ins[1]])
+}
+
+// 行号表的一项：从 offset 开始的指令对应源码中的 line 行 column 列
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+// 行号表按 offset 递增排列，取不超过 offset 的最后一项
+pub fn find_position(positions: &[Position], offset: usize) -> Option<Position> {
+    positions.iter().rev().find(|p| p.offset <= offset).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make() {
+        let tests = vec![
+            (
+                Opcode::OpConstant,
+                vec![65534],
+                vec![Opcode::OpConstant as u8, 255, 254],
+            ),
+            (Opcode::OpAdd, vec![], vec![Opcode::OpAdd as u8]),
+            (
+                Opcode::OpGetLocal,
+                vec![255],
+                vec![Opcode::OpGetLocal as u8, 255],
+            ),
+            (
+                Opcode::OpClosure,
+                vec![65534, 255],
+                vec![Opcode::OpClosure as u8, 255, 254, 255],
+            ),
+        ];
+        for (op, operands, expected) in tests {
+            assert_eq!(make(op, &operands), expected);
+        }
+    }
+
+    #[test]
+    fn test_read_operands() {
+        let tests = vec![
+            (Opcode::OpConstant, vec![65535], 2),
+            (Opcode::OpGetLocal, vec![255], 1),
+            (Opcode::OpClosure, vec![65535, 255], 3),
+        ];
+        for (op, operands, bytes_read) in tests {
+            let ins = make(op, &operands);
+            let (read, n) = read_operands(&op.definition(), &ins[1..]);
+            assert_eq!(n, bytes_read);
+            assert_eq!(read, operands);
+        }
+    }
+
+    #[test]
+    fn test_opcode_from_u8() {
+        for (i, op) in OPCODES.iter().enumerate() {
+            assert_eq!(*op as usize, i);
+            assert_eq!(Opcode::from_u8(i as u8), Some(*op));
+        }
+        assert_eq!(Opcode::from_u8(OPCODES.len() as u8), None);
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::compiler::compiler::Bytecode;
+use crate::evaluator::builtins;
+use crate::object::object::{CompiledFunction, Object};
+
+use super::code::{read_operands, Opcode};
+
+// 把字节码翻译成可读的文本：先是顶层指令，再依次是常量池中的每个函数体
+pub fn disassemble(bytecode: &Bytecode) -> String {
+    let mut out = String::from("== main ==\n");
+    out.push_str(&disassemble_instructions(
+        &bytecode.instructions,
+        &bytecode.constants,
+    ));
+    for (i, constant) in bytecode.constants.iter().enumerate() {
+        if let Some(func) = constant.downcast_ref::<CompiledFunction>() {
+            let _ = writeln!(
+                out,
+                "\n== constant {}: {} ({} params, {} locals) ==",
+                i,
+                function_name(func),
+                func.num_params,
+                func.num_locals
+            );
+            out.push_str(&disassemble_instructions(
+                &func.instructions,
+                &bytecode.constants,
+            ));
+        }
+    }
+    out
+}
+
+// 每行一条指令：偏移、操作码名字、操作数，以及操作数引用的常量或内置函数
+pub fn disassemble_instructions(ins: &[u8], constants: &[Rc<dyn Object>]) -> String {
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset < ins.len() {
+        let op = match Opcode::from_u8(ins[offset]) {
+            Some(op) => op,
+            None => {
+                let _ = writeln!(out, "{:04} ERROR: unknown opcode {}", offset, ins[offset]);
+                offset += 1;
+                continue;
+            }
+        };
+        let def = op.definition();
+        let width: usize = def.operand_widths.iter().sum();
+        if offset + 1 + width > ins.len() {
+            let _ = writeln!(out, "{:04} ERROR: truncated {}", offset, def.name);
+            break;
+        }
+        let (operands, read) = read_operands(&def, &ins[offset + 1..]);
+        let mut text = String::from(def.name);
+        for operand in operands.iter() {
+            let _ = write!(text, " {}", operand);
+        }
+        match comment(op, &operands, constants) {
+            Some(c) => {
+                let _ = writeln!(out, "{:04} {:<24} ; {}", offset, text, c);
+            }
+            None => {
+                let _ = writeln!(out, "{:04} {}", offset, text);
+            }
+        }
+        offset += 1 + read;
+    }
+    out
+}
+
+fn comment(op: Opcode, operands: &[usize], constants: &[Rc<dyn Object>]) -> Option<String> {
+    match op {
+        Opcode::OpConstant | Opcode::OpClosure => {
+            let constant = match constants.get(operands[0]) {
+                Some(c) => c,
+                None => return Some(String::from("<missing constant>")),
+            };
+            match constant.downcast_ref::<CompiledFunction>() {
+                Some(func) => Some(function_name(func)),
+                None => Some(constant.inspect()),
+            }
+        }
+        Opcode::OpGetBuiltin => builtins::NAMES.get(operands[0]).map(|n| n.to_string()),
+        _ => None,
+    }
+}
+
+fn function_name(func: &CompiledFunction) -> String {
+    match &func.name {
+        Some(name) => format!("fn {}", name),
+        None => String::from("fn <anonymous>"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::code::make;
+    use crate::compiler::compiler::Compiler;
+    use crate::lexer::lexer::Lexer;
+    use crate::parser::parser::Parser;
+
+    #[test]
+    fn test_disassemble() {
+        let input = "let add = fn(a, b) { a + b };\nputs(add(1, fn() { 2 }()))";
+        let pgm = Parser::new(Lexer::new(input.to_string()))
+            .parse_program()
+            .unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile(&pgm).unwrap();
+        let expected = "\
+== main ==
+0000 OpClosure 0 0            ; fn add
+0004 OpSetGlobal 0
+0007 OpGetBuiltin 0           ; puts
+0009 OpGetGlobal 0
+0012 OpConstant 1             ; 1
+0015 OpClosure 3 0            ; fn <anonymous>
+0019 OpCall 0
+0021 OpCall 2
+0023 OpCall 1
+0025 OpPop
+
+== constant 0: fn add (2 params, 2 locals) ==
+0000 OpGetLocal 0
+0002 OpGetLocal 1
+0004 OpAdd
+0005 OpReturnValue
+
+== constant 3: fn <anonymous> (0 params, 0 locals) ==
+0000 OpConstant 2             ; 2
+0003 OpReturnValue
+";
+        assert_eq!(disassemble(&compiler.bytecode()), expected);
+    }
+
+    #[test]
+    fn test_disassemble_malformed() {
+        let mut ins = make(Opcode::OpTrue, &[]);
+        ins.push(255);
+        ins.extend_from_slice(&make(Opcode::OpConstant, &[1])[..2]);
+        assert_eq!(
+            disassemble_instructions(&ins, &[]),
+            "0000 OpTrue\n0001 ERROR: unknown opcode 255\n0002 ERROR: truncated OpConstant\n"
+        );
+    }
+}
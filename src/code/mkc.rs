@@ -0,0 +1,497 @@
+// .mkc 文件：预先编译好的字节码
+//
+// 所有整数都是大端序，布局如下：
+//   magic     4 字节 "MKC\0"
+//   version   u16
+//   main      顶层代码：指令和行号表
+//   constants u32 个数，之后是每个常量（1 字节类型标记 + 内容）
+//   globals   u32 个数，之后是按下标排列的全局变量名
+//   checksum  u32，前面所有字节的 CRC-32
+//
+// 函数常量依次是：名字（1 字节标记是否存在 + 字符串）、参数个数、局部变量个数、指令和行号表、
+// 函数的文本。
+// 字节码里内置函数和脚本预定义全局变量的下标是固定的，改变它们的顺序时需要升级 VERSION。
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+
+use crate::compiler::compiler::Bytecode;
+use crate::evaluator::builtins;
+use crate::object::object::{self, CompiledFunction, Object};
+
+use super::code::{read_operands, Instructions, Opcode, Position};
+
+pub const MAGIC: &[u8; 4] = b"MKC\0";
+pub const VERSION: u16 = 3;
+
+const TAG_INTEGER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+const TAG_BIG_INTEGER: u8 = 3;
+const TAG_FLOAT: u8 = 4;
+
+pub fn is_mkc(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn serialize(bytecode: &Bytecode) -> Result<Vec<u8>, String> {
+    let mut w = Writer { buf: Vec::new() };
+    w.buf.extend_from_slice(MAGIC);
+    w.u16(VERSION);
+    w.code(&bytecode.instructions, &bytecode.positions)?;
+    w.len(bytecode.constants.len())?;
+    for constant in bytecode.constants.iter() {
+        if let Some(i) = constant.downcast_ref::<object::Integer>() {
+            w.buf.push(TAG_INTEGER);
+            w.buf.extend_from_slice(&i.value.to_be_bytes());
+        } else if let Some(f) = constant.downcast_ref::<object::Float>() {
+            w.buf.push(TAG_FLOAT);
+            w.buf.extend_from_slice(&f.value.to_bits().to_be_bytes());
+        } else if let Some(b) = constant.downcast_ref::<object::BigInteger>() {
+            // 长度加上大端序的补码
+            w.buf.push(TAG_BIG_INTEGER);
+            let bytes = b.value.to_signed_bytes_be();
+            w.len(bytes.len())?;
+            w.buf.extend_from_slice(&bytes);
+        } else if let Some(s) = constant.downcast_ref::<object::Str>() {
+            w.buf.push(TAG_STRING);
+            w.string(&s.value)?;
+        } else if let Some(func) = constant.downcast_ref::<CompiledFunction>() {
+            w.buf.push(TAG_FUNCTION);
+            match &func.name {
+                Some(name) => {
+                    w.buf.push(1);
+                    w.string(name)?;
+                }
+                None => w.buf.push(0),
+            }
+            w.len(func.num_params)?;
+            w.len(func.num_locals)?;
+            w.code(&func.instructions, &func.positions)?;
+            w.string(&func.text)?;
+        } else {
+            return Err(format!(
+                "cannot serialize constant of type {:?}",
+                constant.type_of()
+            ));
+        }
+    }
+    w.len(bytecode.global_names.len())?;
+    for name in bytecode.global_names.iter() {
+        w.string(name)?;
+    }
+    let checksum = crc32(&w.buf);
+    w.u32(checksum);
+    Ok(w.buf)
+}
+
+// 读取 .mkc 文件，任何不合法的内容都返回错误而不是留给虚拟机在执行时崩溃
+pub fn deserialize(data: &[u8]) -> Result<Bytecode, String> {
+    if !is_mkc(data) {
+        return Err(String::from("not a .mkc file (bad magic number)"));
+    }
+    if data.len() < MAGIC.len() + 2 + 4 {
+        return Err(String::from("unexpected end of file"));
+    }
+    let (body, tail) = data.split_at(data.len() - 4);
+    let mut r = Reader {
+        data: body,
+        pos: MAGIC.len(),
+    };
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(format!(
+            "unsupported format version {} (expected {})",
+            version, VERSION
+        ));
+    }
+    let expected = u32::from_be_bytes([tail[0], tail[1], tail[2], tail[3]]);
+    if crc32(body) != expected {
+        return Err(String::from("checksum mismatch, the file is corrupted"));
+    }
+
+    let (instructions, positions) = r.code()?;
+    let count = r.len()?;
+    let mut constants: Vec<Rc<dyn Object>> = Vec::new();
+    for _ in 0..count {
+        let constant: Rc<dyn Object> = match r.u8()? {
+            TAG_INTEGER => Rc::new(object::Integer {
+                value: i64::from_be_bytes(r.bytes(8)?.try_into().unwrap()),
+            }),
+            TAG_FLOAT => Rc::new(object::Float {
+                value: f64::from_bits(u64::from_be_bytes(r.bytes(8)?.try_into().unwrap())),
+            }),
+            TAG_BIG_INTEGER => {
+                let n = r.len()?;
+                Rc::new(object::BigInteger {
+                    value: BigInt::from_signed_bytes_be(r.bytes(n)?),
+                })
+            }
+            TAG_STRING => Rc::new(object::Str { value: r.string()? }),
+            TAG_FUNCTION => {
+                let name = match r.u8()? {
+                    0 => None,
+                    _ => Some(r.string()?),
+                };
+                let num_params = r.len()?;
+                let num_locals = r.len()?;
+                let (instructions, positions) = r.code()?;
+                let text = r.string()?;
+                if num_params > num_locals {
+                    return Err(format!(
+                        "invalid function: {} params but {} locals",
+                        num_params, num_locals
+                    ));
+                }
+                Rc::new(CompiledFunction {
+                    instructions,
+                    positions,
+                    num_locals,
+                    num_params,
+                    name,
+                    text,
+                })
+            }
+            tag => return Err(format!("unknown constant tag: {}", tag)),
+        };
+        constants.push(constant);
+    }
+    let count = r.len()?;
+    let mut global_names = Vec::new();
+    for _ in 0..count {
+        global_names.push(r.string()?);
+    }
+    if r.pos != body.len() {
+        return Err(String::from("trailing bytes after global names"));
+    }
+
+    validate(&instructions, 0, &constants)?;
+    for constant in constants.iter() {
+        if let Some(func) = constant.downcast_ref::<CompiledFunction>() {
+            validate(&func.instructions, func.num_locals, &constants)?;
+        }
+    }
+    Ok(Bytecode {
+        instructions,
+        positions,
+        constants,
+        global_names,
+    })
+}
+
+// 检查操作码、操作数和它们引用的常量、局部变量、内置函数以及跳转目标
+fn validate(ins: &[u8], num_locals: usize, constants: &[Rc<dyn Object>]) -> Result<(), String> {
+    let mut offset = 0;
+    let mut starts = Vec::new();
+    let mut jumps = Vec::new();
+    while offset < ins.len() {
+        let op = Opcode::from_u8(ins[offset])
+            .ok_or_else(|| format!("invalid opcode {} at offset {}", ins[offset], offset))?;
+        let def = op.definition();
+        let width: usize = def.operand_widths.iter().sum();
+        if offset + 1 + width > ins.len() {
+            return Err(format!("truncated {} at offset {}", def.name, offset));
+        }
+        let (operands, read) = read_operands(&def, &ins[offset + 1..]);
+        let valid = match op {
+            Opcode::OpConstant => operands[0] < constants.len(),
+            Opcode::OpClosure => constants
+                .get(operands[0])
+                .is_some_and(|c| c.downcast_ref::<CompiledFunction>().is_some()),
+            Opcode::OpGetLocal | Opcode::OpSetLocal | Opcode::OpNewCell => operands[0] < num_locals,
+            Opcode::OpGetBuiltin => operands[0] < builtins::NAMES.len(),
+            Opcode::OpJump | Opcode::OpJumpNotTruthy => {
+                jumps.push(operands[0]);
+                true
+            }
+            // 第二个操作数是循环变量的个数
+            Opcode::OpIterNext => {
+                if !matches!(operands[1], 1 | 2) {
+                    return Err(format!(
+                        "invalid operand {} for {} at offset {}",
+                        operands[1], def.name, offset
+                    ));
+                }
+                jumps.push(operands[0]);
+                true
+            }
+            _ => true,
+        };
+        if !valid {
+            return Err(format!(
+                "invalid operand {} for {} at offset {}",
+                operands[0], def.name, offset
+            ));
+        }
+        starts.push(offset);
+        offset += 1 + read;
+    }
+    for target in jumps {
+        if target != ins.len() && starts.binary_search(&target).is_err() {
+            return Err(format!("invalid jump target {}", target));
+        }
+    }
+    Ok(())
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn len(&mut self, n: usize) -> Result<(), String> {
+        let n = u32::try_from(n).map_err(|_| format!("value too large: {}", n))?;
+        self.u32(n);
+        Ok(())
+    }
+
+    fn string(&mut self, s: &str) -> Result<(), String> {
+        self.len(s.len())?;
+        self.buf.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn code(&mut self, ins: &[u8], positions: &[Position]) -> Result<(), String> {
+        self.len(ins.len())?;
+        self.buf.extend_from_slice(ins);
+        self.len(positions.len())?;
+        for p in positions {
+            self.len(p.offset)?;
+            self.len(p.line)?;
+            self.len(p.column)?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err(String::from("unexpected end of file"));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let n = self.len()?;
+        String::from_utf8(self.bytes(n)?.to_vec()).map_err(|_| String::from("invalid UTF-8 string"))
+    }
+
+    fn code(&mut self) -> Result<(Instructions, Vec<Position>), String> {
+        let n = self.len()?;
+        let instructions = self.bytes(n)?.to_vec();
+        let count = self.len()?;
+        let mut positions = Vec::new();
+        for _ in 0..count {
+            positions.push(Position {
+                offset: self.len()?,
+                line: self.len()?,
+                column: self.len()?,
+            });
+        }
+        Ok((instructions, positions))
+    }
+}
+
+// CRC-32 (IEEE 802.3)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::disassembler::disassemble;
+    use crate::compiler::compiler::Compiler;
+    use crate::evaluator::budget::Budget;
+    use crate::lexer::lexer::Lexer;
+    use crate::parser::parser::Parser;
+    use crate::script::script::{run_bytecode, Engine, Options};
+
+    fn compile(input: &str) -> Bytecode {
+        let pgm = Parser::new(Lexer::new(input.to_string()))
+            .parse_program()
+            .unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile(&pgm).unwrap();
+        compiler.bytecode()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytecode = compile(
+            "let f = fn(a) {\n  fn(b) { a + b }\n};\nputs(f(1)(-2), 123456789012345678901234567890n, 2.5e-3)",
+        );
+        let data = serialize(&bytecode).unwrap();
+        assert!(is_mkc(&data));
+        let loaded = deserialize(&data).unwrap();
+        assert_eq!(disassemble(&loaded), disassemble(&bytecode));
+        assert_eq!(loaded.positions, bytecode.positions);
+        assert!(loaded
+            .constants
+            .iter()
+            .any(|c| c.inspect() == "123456789012345678901234567890"));
+        assert!(loaded.constants.iter().any(|c| c.inspect() == "0.0025"));
+        for (a, b) in loaded.constants.iter().zip(bytecode.constants.iter()) {
+            if let (Some(a), Some(b)) = (
+                a.downcast_ref::<object::BigInteger>(),
+                b.downcast_ref::<object::BigInteger>(),
+            ) {
+                assert_eq!(a.value, b.value);
+            }
+            if let (Some(a), Some(b)) = (
+                a.downcast_ref::<CompiledFunction>(),
+                b.downcast_ref::<CompiledFunction>(),
+            ) {
+                assert_eq!(a.positions, b.positions);
+                assert_eq!(a.name, b.name);
+                assert_eq!(a.text, b.text);
+            }
+        }
+        assert_eq!(loaded.global_names, vec!["f"]);
+    }
+
+    #[test]
+    fn test_reject_invalid_files() {
+        let data = serialize(&compile("let a = 1; a + 2")).unwrap();
+
+        let mut corrupted = data.clone();
+        corrupted[10] ^= 0xff;
+        let mut version = data.clone();
+        version[5] = 9;
+        let tests = vec![
+            (b"hello".to_vec(), "not a .mkc file (bad magic number)"),
+            (data[..7].to_vec(), "unexpected end of file"),
+            (corrupted, "checksum mismatch, the file is corrupted"),
+            (version, "unsupported format version 9 (expected 3)"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(deserialize(&input).err(), Some(String::from(expected)));
+        }
+    }
+
+    #[test]
+    fn test_reject_invalid_code() {
+        let with_checksum = |mut body: Vec<u8>| {
+            let checksum = crc32(&body);
+            body.extend_from_slice(&checksum.to_be_bytes());
+            body
+        };
+        let file = |ins: &[u8]| {
+            let mut w = Writer { buf: Vec::new() };
+            w.buf.extend_from_slice(MAGIC);
+            w.u16(VERSION);
+            w.code(ins, &[]).unwrap();
+            w.len(0).unwrap();
+            w.len(0).unwrap();
+            with_checksum(w.buf)
+        };
+        let tests = vec![
+            (vec![255], "invalid opcode 255 at offset 0"),
+            (
+                vec![Opcode::OpConstant as u8, 0],
+                "truncated OpConstant at offset 0",
+            ),
+            (
+                vec![Opcode::OpConstant as u8, 0, 0],
+                "invalid operand 0 for OpConstant at offset 0",
+            ),
+            (
+                vec![Opcode::OpGetLocal as u8, 0],
+                "invalid operand 0 for OpGetLocal at offset 0",
+            ),
+            (vec![Opcode::OpJump as u8, 0, 1], "invalid jump target 1"),
+            (
+                vec![Opcode::OpIterNext as u8, 0, 3, 1],
+                "invalid jump target 3",
+            ),
+            (
+                vec![Opcode::OpIterNext as u8, 0, 4, 0],
+                "invalid operand 0 for OpIterNext at offset 0",
+            ),
+        ];
+        for (ins, expected) in tests {
+            assert_eq!(deserialize(&file(&ins)).err(), Some(String::from(expected)));
+        }
+    }
+
+    // 改动任意一个字节并重新计算校验和，能通过检查的文件在虚拟机中执行时只能返回错误，不能崩溃
+    #[test]
+    fn test_fuzz_flipped_bytes() {
+        let bytecode = compile(
+            r#"let counter = fn() { let n = 0; fn() { n = n + 1; "${n}" } };
+let c = counter();
+let s = "";
+for (i, x in 0..3) { s = "${s}${c()}${i}"; }
+let f = fn(n, acc) { if (n == 0) { return acc; } return f(n - 1, acc + int("1")); };
+while (false) { break; }
+"${s} ${f(3, 0)} ${float(2) * -1.5}""#,
+        );
+        let data = serialize(&bytecode).unwrap();
+        let options = Options {
+            budget: Budget {
+                max_steps: Some(10000),
+                max_collection_size: Some(1000),
+                ..Budget::default()
+            },
+            ..Options::new(Engine::Vm)
+        };
+        let result = run_bytecode(deserialize(&data).unwrap(), &[], options.clone());
+        assert_eq!(result.unwrap().inspect(), "102132 3 -3.0");
+
+        let body = data.len() - 4;
+        for i in MAGIC.len() + 2..body {
+            for mask in [0x01, 0x10, 0x80, 0xff] {
+                let mut input = data[..body].to_vec();
+                input[i] ^= mask;
+                let checksum = crc32(&input);
+                input.extend_from_slice(&checksum.to_be_bytes());
+                if let Ok(bytecode) = deserialize(&input) {
+                    let _ = run_bytecode(bytecode, &[], options.clone());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod code;
+pub mod disassembler;
+pub mod mkc;
\ No newline at end of file
@@ -0,0 +1,1001 @@
+use std::rc::Rc;
+
+use crate::ast::ast::{
+    AssignExpression, BigIntegerLiteral, BlockStatement, Boolean, BreakStatement, CallExpression,
+    ContinueStatement, Expression, ExpressionStatement, FloatLiteral, ForStatement,
+    FunctionLiteral, IFExpression, Identifier, InfixExpression, IntegerLiteral, LetStatement,
+    PrefixExpression, Program, ReturnStatement, Statement, StringLiteral, TemplateLiteral,
+    WhileStatement,
+};
+use crate::code::code::{make, read_u16, Instructions, Opcode, Position};
+use crate::evaluator::stack;
+use crate::object::object::{self, Object};
+use crate::token::Token;
+
+use super::resolver::Resolver;
+use super::symbol_table::{Binding, SymbolScope, SymbolTable};
+
+// 编译结果：顶层指令和常量池
+pub struct Bytecode {
+    pub instructions: Instructions,
+    pub positions: Vec<Position>,
+    pub constants: Vec<Rc<dyn Object>>,
+    // 按下标排列的全局变量名，虚拟机读取还没有赋值的全局变量时用来报错
+    pub global_names: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+struct EmittedInstruction {
+    opcode: Opcode,
+    position: usize,
+}
+
+// 正在编译的循环：continue 跳回 start，break 的跳转在循环结束后回填
+struct Loop {
+    start: usize,
+    breaks: Vec<usize>,
+}
+
+// 每个函数体都在自己的作用域中编译
+#[derive(Default)]
+struct CompilationScope {
+    instructions: Instructions,
+    positions: Vec<Position>,
+    last_instruction: Option<EmittedInstruction>,
+    previous_instruction: Option<EmittedInstruction>,
+    loops: Vec<Loop>,
+    // 被内层函数捕获的局部变量，它们的槽位中放的是 Cell
+    cells: Vec<usize>,
+}
+
+pub struct Compiler {
+    constants: Vec<Rc<dyn Object>>,
+    // 编译前先解析整个程序，标识符按 resolver 记录在 AST 上的绑定编译
+    resolver: Resolver,
+    scopes: Vec<CompilationScope>,
+    // 当前正在编译的节点在源码中的行列号，随指令一起记入行号表
+    line: usize,
+    column: usize,
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::new_with_state(Resolver::new().into_symbol_table(), Vec::new())
+    }
+
+    // REPL 中每一行单独编译，但要沿用之前定义的全局变量和常量
+    pub fn new_with_state(symbol_table: SymbolTable, constants: Vec<Rc<dyn Object>>) -> Compiler {
+        Compiler {
+            constants,
+            resolver: Resolver::new_with_symbol_table(symbol_table),
+            scopes: vec![CompilationScope::default()],
+            line: 0,
+            column: 0,
+        }
+    }
+
+    pub fn into_state(self) -> (SymbolTable, Vec<Rc<dyn Object>>) {
+        (self.resolver.into_symbol_table(), self.constants)
+    }
+
+    pub fn bytecode(&self) -> Bytecode {
+        let scope = self.scopes.last().unwrap();
+        Bytecode {
+            instructions: scope.instructions.clone(),
+            positions: scope.positions.clone(),
+            constants: self.constants.clone(),
+            global_names: self.resolver.symbol_table().global_names(),
+        }
+    }
+
+    pub fn compile(&mut self, program: &Program) -> Result<(), String> {
+        self.resolver.resolve(program)?;
+        for stmt in program.statements.iter() {
+            self.compile_statement(stmt.as_ref())?;
+        }
+        Ok(())
+    }
+
+    // 栈空间不足时切换到新的栈段，见 stack::grow
+    fn compile_statement(&mut self, stmt: &dyn Statement) -> Result<(), String> {
+        stack::grow(|| self.compile_statement_inner(stmt))
+    }
+
+    fn compile_statement_inner(&mut self, stmt: &dyn Statement) -> Result<(), String> {
+        if let Some(st) = stmt.downcast_ref::<ExpressionStatement>() {
+            self.compile_expression(st.expression.as_ref())?;
+            self.emit(Opcode::OpPop, &[]);
+        } else if let Some(st) = stmt.downcast_ref::<LetStatement>() {
+            self.set_position(&st.token);
+            match st.value.downcast_ref::<FunctionLiteral>() {
+                Some(func) => self.compile_function_literal(func, Some(&st.name.value))?,
+                None => self.compile_expression(st.value.as_ref())?,
+            }
+            self.set_position(&st.token);
+            self.store(binding(&st.name)?);
+        } else if let Some(st) = stmt.downcast_ref::<ReturnStatement>() {
+            self.compile_expression(st.return_value.as_ref())?;
+            self.set_position(&st.token);
+            self.emit(Opcode::OpReturnValue, &[]);
+        } else if let Some(st) = stmt.downcast_ref::<WhileStatement>() {
+            self.compile_while_statement(st)?;
+        } else if let Some(st) = stmt.downcast_ref::<ForStatement>() {
+            self.compile_for_statement(st)?;
+        } else if let Some(st) = stmt.downcast_ref::<BreakStatement>() {
+            self.set_position(&st.token);
+            let jump = self.emit(Opcode::OpJump, &[9999]);
+            match self.scopes.last_mut().unwrap().loops.last_mut() {
+                Some(l) => l.breaks.push(jump),
+                None => return Err(String::from("break outside of a loop")),
+            }
+        } else if let Some(st) = stmt.downcast_ref::<ContinueStatement>() {
+            self.set_position(&st.token);
+            let start = match self.scopes.last().unwrap().loops.last() {
+                Some(l) => l.start,
+                None => return Err(String::from("continue outside of a loop")),
+            };
+            self.emit(Opcode::OpJump, &[jump_target(start)?]);
+        } else {
+            return Err(format!("unknown statement: {}", stmt.to_string()));
+        }
+        Ok(())
+    }
+
+    fn compile_block_statement(&mut self, block: &BlockStatement) -> Result<(), String> {
+        for stmt in block.statements.iter() {
+            self.compile_statement(stmt.as_ref())?;
+        }
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, exp: &dyn Expression) -> Result<(), String> {
+        stack::grow(|| self.compile_expression_inner(exp))
+    }
+
+    fn compile_expression_inner(&mut self, exp: &dyn Expression) -> Result<(), String> {
+        if let Some(e) = exp.downcast_ref::<IntegerLiteral>() {
+            self.set_position(&e.token);
+            let index = self.add_constant(Rc::new(object::Integer { value: e.value }))?;
+            self.emit(Opcode::OpConstant, &[index]);
+        } else if let Some(e) = exp.downcast_ref::<FloatLiteral>() {
+            self.set_position(&e.token);
+            let index = self.add_constant(Rc::new(object::Float { value: e.value }))?;
+            self.emit(Opcode::OpConstant, &[index]);
+        } else if let Some(e) = exp.downcast_ref::<BigIntegerLiteral>() {
+            self.set_position(&e.token);
+            let index = self.add_constant(Rc::new(object::BigInteger {
+                value: e.value.clone(),
+            }))?;
+            self.emit(Opcode::OpConstant, &[index]);
+        } else if let Some(e) = exp.downcast_ref::<StringLiteral>() {
+            self.set_position(&e.token);
+            let index = self.add_constant(Rc::new(object::Str {
+                value: e.value.clone(),
+            }))?;
+            self.emit(Opcode::OpConstant, &[index]);
+        } else if let Some(e) = exp.downcast_ref::<TemplateLiteral>() {
+            self.compile_template_literal(e)?;
+        } else if let Some(e) = exp.downcast_ref::<Boolean>() {
+            self.set_position(&e.token);
+            if e.value {
+                self.emit(Opcode::OpTrue, &[]);
+            } else {
+                self.emit(Opcode::OpFalse, &[]);
+            }
+        } else if let Some(e) = exp.downcast_ref::<Identifier>() {
+            self.set_position(&e.token);
+            self.load(binding(e)?);
+        } else if let Some(e) = exp.downcast_ref::<PrefixExpression>() {
+            self.compile_expression(e.right.as_ref())?;
+            self.set_position(&e.token);
+            match e.operator.as_str() {
+                "!" => self.emit(Opcode::OpBang, &[]),
+                "-" => self.emit(Opcode::OpMinus, &[]),
+                "~" => self.emit(Opcode::OpBitNot, &[]),
+                op => return Err(format!("unknown operator: {}", op)),
+            };
+        } else if let Some(e) = exp.downcast_ref::<InfixExpression>() {
+            if e.operator == "&&" || e.operator == "||" {
+                return self.compile_logical_expression(e);
+            }
+            self.compile_expression(e.left.as_ref())?;
+            self.compile_expression(e.right.as_ref())?;
+            self.set_position(&e.token);
+            self.emit(binary_opcode(&e.operator)?, &[]);
+        } else if let Some(e) = exp.downcast_ref::<AssignExpression>() {
+            self.compile_assign_expression(e)?;
+        } else if let Some(e) = exp.downcast_ref::<IFExpression>() {
+            self.compile_if_expression(e)?;
+        } else if let Some(e) = exp.downcast_ref::<FunctionLiteral>() {
+            self.compile_function_literal(e, None)?;
+        } else if let Some(e) = exp.downcast_ref::<CallExpression>() {
+            self.compile_expression(e.func.as_ref())?;
+            for arg in e.args.iter() {
+                self.compile_expression(arg.as_ref())?;
+            }
+            self.set_position(&e.token);
+            if e.args.len() > u8::MAX as usize {
+                return Err(format!("too many arguments: {}", e.args.len()));
+            }
+            self.emit(Opcode::OpCall, &[e.args.len()]);
+        } else {
+            return Err(format!("unknown expression: {}", exp.to_string()));
+        }
+        Ok(())
+    }
+
+    fn compile_if_expression(&mut self, ie: &IFExpression) -> Result<(), String> {
+        self.compile_expression(ie.condition.as_ref())?;
+        self.set_position(&ie.token);
+        // 跳转目标先占位，编译完分支后再回填
+        let jump_not_truthy = self.emit(Opcode::OpJumpNotTruthy, &[9999]);
+        self.compile_branch(&ie.consequence)?;
+        let jump = self.emit(Opcode::OpJump, &[9999]);
+        let after_consequence = self.current_instructions().len();
+        self.change_operand(jump_not_truthy, after_consequence)?;
+        // 没有 else 分支时 alternative 是空语句块，结果为 null
+        self.compile_branch(&ie.alternative)?;
+        let after_alternative = self.current_instructions().len();
+        self.change_operand(jump, after_alternative)
+    }
+
+    // 空的字符串段不入栈，最后用 OpTemplate 把栈顶的各部分拼接起来
+    fn compile_template_literal(&mut self, e: &TemplateLiteral) -> Result<(), String> {
+        let mut parts = 0;
+        for (i, s) in e.strings.iter().enumerate() {
+            if !s.is_empty() {
+                self.set_position(&e.token);
+                let index = self.add_constant(Rc::new(object::Str { value: s.clone() }))?;
+                self.emit(Opcode::OpConstant, &[index]);
+                parts += 1;
+            }
+            if let Some(exp) = e.expressions.get(i) {
+                self.compile_expression(exp.as_ref())?;
+                parts += 1;
+            }
+        }
+        if parts > u16::MAX as usize {
+            return Err(format!("too many parts in string: {}", parts));
+        }
+        self.set_position(&e.token);
+        self.emit(Opcode::OpTemplate, &[parts]);
+        Ok(())
+    }
+
+    // 短路求值：a && b 在 a 为假时不再求 b，a || b 在 a 为真时不再求 b，结果总是布尔值
+    fn compile_logical_expression(&mut self, e: &InfixExpression) -> Result<(), String> {
+        self.compile_expression(e.left.as_ref())?;
+        self.set_position(&e.token);
+        let mut to_true = None;
+        let mut to_false = Vec::new();
+        if e.operator == "&&" {
+            to_false.push(self.emit(Opcode::OpJumpNotTruthy, &[9999]));
+        } else {
+            self.emit(Opcode::OpBang, &[]);
+            to_true = Some(self.emit(Opcode::OpJumpNotTruthy, &[9999]));
+        }
+        self.compile_expression(e.right.as_ref())?;
+        self.set_position(&e.token);
+        to_false.push(self.emit(Opcode::OpJumpNotTruthy, &[9999]));
+        if let Some(jump) = to_true {
+            let pos = self.current_instructions().len();
+            self.change_operand(jump, pos)?;
+        }
+        self.emit(Opcode::OpTrue, &[]);
+        let to_end = self.emit(Opcode::OpJump, &[9999]);
+        let pos = self.current_instructions().len();
+        for jump in to_false {
+            self.change_operand(jump, pos)?;
+        }
+        self.emit(Opcode::OpFalse, &[]);
+        let end = self.current_instructions().len();
+        self.change_operand(to_end, end)
+    }
+
+    // 赋值表达式的值是赋给变量的新值，存入后再读出来留在栈上
+    fn compile_assign_expression(&mut self, ae: &AssignExpression) -> Result<(), String> {
+        let target = binding(&ae.target)?;
+        if let Some(op) = ae.binary_operator() {
+            self.set_position(&ae.target.token);
+            self.load(target);
+            self.compile_expression(ae.value.as_ref())?;
+            self.set_position(&ae.token);
+            self.emit(binary_opcode(op)?, &[]);
+        } else {
+            self.compile_expression(ae.value.as_ref())?;
+            self.set_position(&ae.token);
+        }
+        self.store(target);
+        self.load(target);
+        Ok(())
+    }
+
+    // 循环语句没有值，结束后压入 null 再弹出，和表达式语句一样以 OpPop 结尾
+    fn compile_while_statement(&mut self, ws: &WhileStatement) -> Result<(), String> {
+        let start = self.current_instructions().len();
+        self.compile_expression(ws.condition.as_ref())?;
+        self.set_position(&ws.token);
+        let jump_not_truthy = self.emit(Opcode::OpJumpNotTruthy, &[9999]);
+        self.scopes.last_mut().unwrap().loops.push(Loop {
+            start,
+            breaks: Vec::new(),
+        });
+        let body = self.compile_block_statement(&ws.body);
+        let l = self.scopes.last_mut().unwrap().loops.pop().unwrap();
+        body?;
+        self.set_position(&ws.token);
+        self.emit(Opcode::OpJump, &[jump_target(start)?]);
+        let end = self.current_instructions().len();
+        self.change_operand(jump_not_truthy, end)?;
+        for jump in l.breaks {
+            self.change_operand(jump, end)?;
+        }
+        self.emit(Opcode::OpNull, &[]);
+        self.emit(Opcode::OpPop, &[]);
+        Ok(())
+    }
+
+    // 迭代器在整个循环期间留在栈上，break 跳到循环末尾把它弹出
+    fn compile_for_statement(&mut self, fs: &ForStatement) -> Result<(), String> {
+        self.compile_expression(fs.iterable.as_ref())?;
+        self.set_position(&fs.token);
+        self.emit(Opcode::OpIter, &[]);
+        let start = self.current_instructions().len();
+        let count = if fs.key.is_some() { 2 } else { 1 };
+        let iter_next = self.emit(Opcode::OpIterNext, &[9999, count]);
+        self.store(binding(&fs.value)?);
+        if let Some(key) = &fs.key {
+            self.store(binding(key)?);
+        }
+        self.scopes.last_mut().unwrap().loops.push(Loop {
+            start,
+            breaks: Vec::new(),
+        });
+        let body = self.compile_block_statement(&fs.body);
+        let l = self.scopes.last_mut().unwrap().loops.pop().unwrap();
+        body?;
+        self.set_position(&fs.token);
+        self.emit(Opcode::OpJump, &[jump_target(start)?]);
+        let end = self.current_instructions().len();
+        self.change_iter_next(iter_next, end, count)?;
+        for jump in l.breaks {
+            self.change_operand(jump, end)?;
+        }
+        self.emit(Opcode::OpPop, &[]);
+        self.emit(Opcode::OpNull, &[]);
+        self.emit(Opcode::OpPop, &[]);
+        Ok(())
+    }
+
+    // 把栈顶的值存入变量
+    fn store(&mut self, binding: Binding) {
+        if binding.scope == SymbolScope::Global {
+            self.emit(Opcode::OpSetGlobal, &[binding.index]);
+        } else if self.is_cell(binding) {
+            self.load_cell(binding);
+            self.emit(Opcode::OpSetCell, &[]);
+        } else {
+            self.emit(Opcode::OpSetLocal, &[binding.index]);
+        }
+    }
+
+    // 分支的值留在栈上：以表达式结尾时去掉最后的 OpPop，否则压入 null
+    fn compile_branch(&mut self, block: &BlockStatement) -> Result<(), String> {
+        self.compile_block_statement(block)?;
+        if self.last_instruction_is(Opcode::OpPop) {
+            self.remove_last_pop();
+        } else {
+            self.emit(Opcode::OpNull, &[]);
+        }
+        Ok(())
+    }
+
+    fn compile_function_literal(
+        &mut self,
+        func: &FunctionLiteral,
+        name: Option<&str>,
+    ) -> Result<(), String> {
+        self.enter_scope();
+        // 被捕获的局部变量（包括参数）在函数开始时装进 Cell
+        let cells = func.captured.borrow().clone();
+        self.set_position(&func.token);
+        for index in cells.iter() {
+            self.emit(Opcode::OpNewCell, &[*index]);
+        }
+        self.scopes.last_mut().unwrap().cells = cells;
+        let body = self.compile_block_statement(&func.body);
+        if body.is_ok() {
+            if self.last_instruction_is(Opcode::OpPop) {
+                self.replace_last_pop_with_return();
+            }
+            if !self.last_instruction_is(Opcode::OpReturnValue) {
+                self.emit(Opcode::OpReturn, &[]);
+            }
+            self.mark_tail_calls();
+        }
+        let free_symbols = func.free.borrow().clone();
+        let num_locals = func.num_locals.get();
+        let (instructions, positions) = self.leave_scope();
+        body?;
+        if num_locals > u8::MAX as usize + 1 {
+            return Err(format!("too many local variables: {}", num_locals));
+        }
+        if free_symbols.len() > u8::MAX as usize {
+            return Err(format!("too many free variables: {}", free_symbols.len()));
+        }
+
+        self.set_position(&func.token);
+        for binding in free_symbols.iter() {
+            self.load_cell(*binding);
+        }
+        let compiled = object::CompiledFunction {
+            instructions,
+            positions,
+            num_locals,
+            num_params: func.params.len(),
+            name: name.map(|n| n.to_string()),
+            text: object::function_text(&func.params, &func.body),
+        };
+        let index = self.add_constant(Rc::new(compiled))?;
+        self.emit(Opcode::OpClosure, &[index, free_symbols.len()]);
+        Ok(())
+    }
+
+    fn load(&mut self, binding: Binding) {
+        match binding.scope {
+            SymbolScope::Global => self.emit(Opcode::OpGetGlobal, &[binding.index]),
+            SymbolScope::Builtin => self.emit(Opcode::OpGetBuiltin, &[binding.index]),
+            _ if self.is_cell(binding) => {
+                self.load_cell(binding);
+                self.emit(Opcode::OpDeref, &[])
+            }
+            _ => self.emit(Opcode::OpGetLocal, &[binding.index]),
+        };
+    }
+
+    // 把保存变量的 Cell 本身压入栈，用于创建闭包和给变量赋值
+    fn load_cell(&mut self, binding: Binding) {
+        if binding.scope == SymbolScope::Free {
+            self.emit(Opcode::OpGetFree, &[binding.index]);
+        } else {
+            self.emit(Opcode::OpGetLocal, &[binding.index]);
+        }
+    }
+
+    // 自由变量总是保存在 Cell 中
+    fn is_cell(&self, binding: Binding) -> bool {
+        match binding.scope {
+            SymbolScope::Free => true,
+            SymbolScope::Local => self.scopes.last().unwrap().cells.contains(&binding.index),
+            _ => false,
+        }
+    }
+
+    fn add_constant(&mut self, obj: Rc<dyn Object>) -> Result<usize, String> {
+        if self.constants.len() > u16::MAX as usize {
+            return Err(String::from("too many constants"));
+        }
+        self.constants.push(obj);
+        Ok(self.constants.len() - 1)
+    }
+
+    fn emit(&mut self, op: Opcode, operands: &[usize]) -> usize {
+        let ins = make(op, operands);
+        let scope = self.scopes.last_mut().unwrap();
+        let position = scope.instructions.len();
+        let changed = match scope.positions.last() {
+            Some(p) => p.line != self.line || p.column != self.column,
+            None => true,
+        };
+        if self.line > 0 && changed {
+            scope.positions.push(Position {
+                offset: position,
+                line: self.line,
+                column: self.column,
+            });
+        }
+        scope.instructions.extend_from_slice(&ins);
+        scope.previous_instruction = scope.last_instruction;
+        scope.last_instruction = Some(EmittedInstruction {
+            opcode: op,
+            position,
+        });
+        position
+    }
+
+    // 函数体中紧接着返回的调用改成尾调用，包括经过 OpJump 才到达返回的 if 分支
+    fn mark_tail_calls(&mut self) {
+        let ins = &mut self.scopes.last_mut().unwrap().instructions;
+        let mut offset = 0;
+        while offset < ins.len() {
+            let op = Opcode::from_u8(ins[offset]).unwrap();
+            let width: usize = op.definition().operand_widths.iter().sum();
+            if op == Opcode::OpCall && returns_immediately(ins, offset + 1 + width) {
+                ins[offset] = Opcode::OpTailCall as u8;
+            }
+            offset += 1 + width;
+        }
+    }
+
+    fn set_position(&mut self, token: &Token) {
+        self.line = token.line;
+        self.column = token.column;
+    }
+
+    fn current_instructions(&self) -> &Instructions {
+        &self.scopes.last().unwrap().instructions
+    }
+
+    fn last_instruction_is(&self, op: Opcode) -> bool {
+        match self.scopes.last().unwrap().last_instruction {
+            Some(last) => last.opcode == op,
+            None => false,
+        }
+    }
+
+    fn remove_last_pop(&mut self) {
+        let scope = self.scopes.last_mut().unwrap();
+        if let Some(last) = scope.last_instruction {
+            scope.instructions.truncate(last.position);
+            scope.positions.retain(|p| p.offset < last.position);
+            scope.last_instruction = scope.previous_instruction;
+        }
+    }
+
+    fn replace_last_pop_with_return(&mut self) {
+        let scope = self.scopes.last_mut().unwrap();
+        if let Some(last) = scope.last_instruction.as_mut() {
+            scope.instructions[last.position] = Opcode::OpReturnValue as u8;
+            last.opcode = Opcode::OpReturnValue;
+        }
+    }
+
+    fn change_operand(&mut self, position: usize, operand: usize) -> Result<(), String> {
+        let scope = self.scopes.last_mut().unwrap();
+        let op = Opcode::from_u8(scope.instructions[position]).unwrap();
+        let ins = make(op, &[jump_target(operand)?]);
+        scope.instructions[position..position + ins.len()].copy_from_slice(&ins);
+        Ok(())
+    }
+
+    fn change_iter_next(
+        &mut self,
+        position: usize,
+        end: usize,
+        count: usize,
+    ) -> Result<(), String> {
+        let ins = make(Opcode::OpIterNext, &[jump_target(end)?, count]);
+        let scope = self.scopes.last_mut().unwrap();
+        scope.instructions[position..position + ins.len()].copy_from_slice(&ins);
+        Ok(())
+    }
+
+    fn enter_scope(&mut self) {
+        self.scopes.push(CompilationScope::default());
+    }
+
+    fn leave_scope(&mut self) -> (Instructions, Vec<Position>) {
+        let scope = self.scopes.pop().unwrap();
+        (scope.instructions, scope.positions)
+    }
+}
+
+// resolver 记录的绑定，编译前已经解析过整个程序，只有 AST 不完整时才会缺失
+fn binding(ident: &Identifier) -> Result<Binding, String> {
+    ident
+        .binding
+        .get()
+        .ok_or_else(|| format!("identifier not found: {}", ident.value))
+}
+
+// 跳转目标是 2 字节的操作数，make 会截断更大的值，超过 64 KiB 的代码中跳转报错
+fn jump_target(target: usize) -> Result<usize, String> {
+    if target > u16::MAX as usize {
+        return Err(String::from("jump target out of range"));
+    }
+    Ok(target)
+}
+
+fn binary_opcode(operator: &str) -> Result<Opcode, String> {
+    let op = match operator {
+        "+" => Opcode::OpAdd,
+        "-" => Opcode::OpSub,
+        "*" => Opcode::OpMul,
+        "/" => Opcode::OpDiv,
+        ">" => Opcode::OpGreaterThan,
+        "<" => Opcode::OpLessThan,
+        ">=" => Opcode::OpGreaterEqual,
+        "<=" => Opcode::OpLessEqual,
+        "%" => Opcode::OpMod,
+        "**" => Opcode::OpPow,
+        "&" => Opcode::OpBitAnd,
+        "|" => Opcode::OpBitOr,
+        "^" => Opcode::OpBitXor,
+        "<<" => Opcode::OpShl,
+        ">>" => Opcode::OpShr,
+        "==" => Opcode::OpEqual,
+        "!=" => Opcode::OpNotEqual,
+        ".." => Opcode::OpRange,
+        "..=" => Opcode::OpRangeInclusive,
+        op => return Err(format!("unknown operator: {}", op)),
+    };
+    Ok(op)
+}
+
+fn returns_immediately(ins: &[u8], mut offset: usize) -> bool {
+    // 最多跟随 ins.len() 次跳转，避免跳转成环时死循环
+    for _ in 0..ins.len() {
+        match ins.get(offset).and_then(|b| Opcode::from_u8(*b)) {
+            Some(Opcode::OpReturnValue) => return true,
+            Some(Opcode::OpJump) => offset = read_u16(&ins[offset + 1..]) as usize,
+            _ => return false,
+        }
+    }
+    false
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lexer::Lexer;
+    use crate::parser::parser::Parser;
+
+    fn compile(input: &str) -> Bytecode {
+        let l = Lexer::new(input.to_string());
+        let mut p = Parser::new(l);
+        let prgm = p.parse_program().unwrap();
+        let mut c = Compiler::new();
+        c.compile(&prgm).unwrap();
+        c.bytecode()
+    }
+
+    fn concat(ins: Vec<Vec<u8>>) -> Instructions {
+        ins.concat()
+    }
+
+    fn function_constant(obj: &Rc<dyn Object>) -> &object::CompiledFunction {
+        obj.downcast_ref::<object::CompiledFunction>()
+            .expect("constant not CompiledFunction")
+    }
+
+    #[test]
+    fn test_integer_arithmetic() {
+        let bc = compile("1 + 2; -3 < 4");
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpConstant, &[0]),
+                make(Opcode::OpConstant, &[1]),
+                make(Opcode::OpAdd, &[]),
+                make(Opcode::OpPop, &[]),
+                make(Opcode::OpConstant, &[2]),
+                make(Opcode::OpMinus, &[]),
+                make(Opcode::OpConstant, &[3]),
+                make(Opcode::OpLessThan, &[]),
+                make(Opcode::OpPop, &[]),
+            ])
+        );
+        let constants: Vec<String> = bc.constants.iter().map(|c| c.inspect()).collect();
+        assert_eq!(constants, vec!["1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let bc = compile("if (true) { 10 }; 3333;");
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpTrue, &[]),            // 0000
+                make(Opcode::OpJumpNotTruthy, &[10]), // 0001
+                make(Opcode::OpConstant, &[0]),       // 0004
+                make(Opcode::OpJump, &[11]),          // 0007
+                make(Opcode::OpNull, &[]),            // 0010
+                make(Opcode::OpPop, &[]),             // 0011
+                make(Opcode::OpConstant, &[1]),       // 0012
+                make(Opcode::OpPop, &[]),             // 0015
+            ])
+        );
+
+        let bc = compile("if (true) { 10 } else { let a = 1; }");
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpTrue, &[]),            // 0000
+                make(Opcode::OpJumpNotTruthy, &[10]), // 0001
+                make(Opcode::OpConstant, &[0]),       // 0004
+                make(Opcode::OpJump, &[17]),          // 0007
+                make(Opcode::OpConstant, &[1]),       // 0010
+                make(Opcode::OpSetGlobal, &[0]),      // 0013
+                make(Opcode::OpNull, &[]),            // 0016
+                make(Opcode::OpPop, &[]),             // 0017
+            ])
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let bc = compile("while (true) { if (false) { break; } continue; }");
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpTrue, &[]),            // 0000
+                make(Opcode::OpJumpNotTruthy, &[23]), // 0001
+                make(Opcode::OpFalse, &[]),           // 0004
+                make(Opcode::OpJumpNotTruthy, &[15]), // 0005
+                make(Opcode::OpJump, &[23]),          // 0008
+                make(Opcode::OpNull, &[]),            // 0011
+                make(Opcode::OpJump, &[16]),          // 0012
+                make(Opcode::OpNull, &[]),            // 0015
+                make(Opcode::OpPop, &[]),             // 0016
+                make(Opcode::OpJump, &[0]),           // 0017
+                make(Opcode::OpJump, &[0]),           // 0020
+                make(Opcode::OpNull, &[]),            // 0023
+                make(Opcode::OpPop, &[]),             // 0024
+            ])
+        );
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let bc = compile("true && false;");
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpTrue, &[]),            // 0000
+                make(Opcode::OpJumpNotTruthy, &[12]), // 0001
+                make(Opcode::OpFalse, &[]),           // 0004
+                make(Opcode::OpJumpNotTruthy, &[12]), // 0005
+                make(Opcode::OpTrue, &[]),            // 0008
+                make(Opcode::OpJump, &[13]),          // 0009
+                make(Opcode::OpFalse, &[]),           // 0012
+                make(Opcode::OpPop, &[]),             // 0013
+            ])
+        );
+
+        let bc = compile("true || false;");
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpTrue, &[]),            // 0000
+                make(Opcode::OpBang, &[]),            // 0001
+                make(Opcode::OpJumpNotTruthy, &[9]),  // 0002
+                make(Opcode::OpFalse, &[]),           // 0005
+                make(Opcode::OpJumpNotTruthy, &[13]), // 0006
+                make(Opcode::OpTrue, &[]),            // 0009
+                make(Opcode::OpJump, &[14]),          // 0010
+                make(Opcode::OpFalse, &[]),           // 0013
+                make(Opcode::OpPop, &[]),             // 0014
+            ])
+        );
+    }
+
+    #[test]
+    fn test_template_literals() {
+        let bc = compile(r#""a${1}${true}b""#);
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpConstant, &[0]),
+                make(Opcode::OpConstant, &[1]),
+                make(Opcode::OpTrue, &[]),
+                make(Opcode::OpConstant, &[2]),
+                make(Opcode::OpTemplate, &[4]),
+                make(Opcode::OpPop, &[]),
+            ])
+        );
+        let constants: Vec<String> = bc.constants.iter().map(|c| c.inspect()).collect();
+        assert_eq!(constants, vec!["a", "1", "b"]);
+    }
+
+    #[test]
+    fn test_global_let_statements() {
+        let bc = compile("let one = 1; let two = one; two; puts(two);");
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpConstant, &[0]),
+                make(Opcode::OpSetGlobal, &[0]),
+                make(Opcode::OpGetGlobal, &[0]),
+                make(Opcode::OpSetGlobal, &[1]),
+                make(Opcode::OpGetGlobal, &[1]),
+                make(Opcode::OpPop, &[]),
+                make(Opcode::OpGetBuiltin, &[0]),
+                make(Opcode::OpGetGlobal, &[1]),
+                make(Opcode::OpCall, &[1]),
+                make(Opcode::OpPop, &[]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_functions_and_calls() {
+        let bc = compile("let add = fn(a, b) { let c = a + b; return c; }; add(1, 2);");
+        let func = function_constant(&bc.constants[0]);
+        assert_eq!(func.num_params, 2);
+        assert_eq!(func.num_locals, 3);
+        assert_eq!(func.name.as_deref(), Some("add"));
+        assert_eq!(
+            func.instructions,
+            concat(vec![
+                make(Opcode::OpGetLocal, &[0]),
+                make(Opcode::OpGetLocal, &[1]),
+                make(Opcode::OpAdd, &[]),
+                make(Opcode::OpSetLocal, &[2]),
+                make(Opcode::OpGetLocal, &[2]),
+                make(Opcode::OpReturnValue, &[]),
+            ])
+        );
+        assert_eq!(
+            bc.instructions,
+            concat(vec![
+                make(Opcode::OpClosure, &[0, 0]),
+                make(Opcode::OpSetGlobal, &[0]),
+                make(Opcode::OpGetGlobal, &[0]),
+                make(Opcode::OpConstant, &[1]),
+                make(Opcode::OpConstant, &[2]),
+                make(Opcode::OpCall, &[2]),
+                make(Opcode::OpPop, &[]),
+            ])
+        );
+
+        let bc = compile("fn() { }");
+        let func = function_constant(&bc.constants[0]);
+        assert_eq!(func.instructions, make(Opcode::OpReturn, &[]));
+    }
+
+    #[test]
+    fn test_closures() {
+        let bc = compile("fn(a) { fn(b) { a + b } }");
+        let inner = function_constant(&bc.constants[0]);
+        assert_eq!(
+            inner.instructions,
+            concat(vec![
+                make(Opcode::OpGetFree, &[0]),
+                make(Opcode::OpDeref, &[]),
+                make(Opcode::OpGetLocal, &[0]),
+                make(Opcode::OpAdd, &[]),
+                make(Opcode::OpReturnValue, &[]),
+            ])
+        );
+        let outer = function_constant(&bc.constants[1]);
+        assert_eq!(
+            outer.instructions,
+            concat(vec![
+                make(Opcode::OpNewCell, &[0]),
+                make(Opcode::OpGetLocal, &[0]),
+                make(Opcode::OpClosure, &[0, 1]),
+                make(Opcode::OpReturnValue, &[]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_recursive_functions() {
+        let bc = compile(
+            "let wrapper = fn() { let countDown = fn(x) { countDown(x - 1); }; countDown(1); };",
+        );
+        // countDown 是 wrapper 的局部变量，被自己的函数体捕获，放在 Cell 中
+        let count_down = function_constant(&bc.constants[1]);
+        assert_eq!(
+            count_down.instructions,
+            concat(vec![
+                make(Opcode::OpGetFree, &[0]),
+                make(Opcode::OpDeref, &[]),
+                make(Opcode::OpGetLocal, &[0]),
+                make(Opcode::OpConstant, &[0]),
+                make(Opcode::OpSub, &[]),
+                make(Opcode::OpTailCall, &[1]),
+                make(Opcode::OpReturnValue, &[]),
+            ])
+        );
+        let wrapper = function_constant(&bc.constants[3]);
+        assert_eq!(
+            wrapper.instructions,
+            concat(vec![
+                make(Opcode::OpNewCell, &[0]),
+                make(Opcode::OpGetLocal, &[0]),
+                make(Opcode::OpClosure, &[1, 1]),
+                make(Opcode::OpGetLocal, &[0]),
+                make(Opcode::OpSetCell, &[]),
+                make(Opcode::OpGetLocal, &[0]),
+                make(Opcode::OpDeref, &[]),
+                make(Opcode::OpConstant, &[2]),
+                make(Opcode::OpTailCall, &[1]),
+                make(Opcode::OpReturnValue, &[]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let bc = compile(
+            "let g = fn(x) { x }; let f = fn(x) { if (x) { return g(x); } if (x) { g(1) } else { g(g(2)) } };",
+        );
+        let f = function_constant(bc.constants.last().unwrap());
+        let mut calls = Vec::new();
+        let mut offset = 0;
+        while offset < f.instructions.len() {
+            let op = Opcode::from_u8(f.instructions[offset]).unwrap();
+            if op == Opcode::OpCall || op == Opcode::OpTailCall {
+                calls.push((offset, op));
+            }
+            offset += 1 + op.definition().operand_widths.iter().sum::<usize>();
+        }
+        assert_eq!(
+            calls,
+            vec![
+                (10, Opcode::OpTailCall),
+                (30, Opcode::OpTailCall),
+                (44, Opcode::OpCall),
+                (46, Opcode::OpTailCall),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let l = Lexer::new(String::from("let a = b;"));
+        let mut p = Parser::new(l);
+        let prgm = p.parse_program().unwrap();
+        let mut c = Compiler::new();
+        assert_eq!(
+            c.compile(&prgm),
+            Err(String::from("identifier not found: b (line 1, column 9)"))
+        );
+
+        let tests = vec![
+            ("y = 1", "identifier not found: y (line 1, column 1)"),
+            (
+                "puts = 1",
+                "cannot assign to builtin: puts (line 1, column 1)",
+            ),
+            (
+                "const x = 1; x += 1",
+                "cannot assign to constant: x (line 1, column 14)",
+            ),
+        ];
+        for (input, expected) in tests {
+            let prgm = Parser::new(Lexer::new(input.to_string()))
+                .parse_program()
+                .unwrap();
+            let mut c = Compiler::new();
+            assert_eq!(c.compile(&prgm), Err(String::from(expected)), "{}", input);
+        }
+
+        // 跳回超过 64 KiB 之后的循环开头
+        let filler = "x;\n".repeat(17000);
+        for lp in ["while (x < 1) { continue; }", "for (c in \"ab\") { }"] {
+            let input = format!("let x = 0;\n{}{}", filler, lp);
+            let prgm = Parser::new(Lexer::new(input)).parse_program().unwrap();
+            let mut c = Compiler::new();
+            assert_eq!(
+                c.compile(&prgm),
+                Err(String::from("jump target out of range")),
+                "{}",
+                lp
+            );
+        }
+    }
+
+    #[test]
+    fn test_positions() {
+        let bc = compile("let a = 1;\nlet b = a +\n  true;");
+        assert_eq!(
+            bc.positions,
+            vec![
+                Position { offset: 0, line: 1, column: 9 },
+                Position { offset: 3, line: 1, column: 1 },
+                Position { offset: 6, line: 2, column: 9 },
+                Position { offset: 9, line: 3, column: 3 },
+                Position { offset: 10, line: 2, column: 11 },
+                Position { offset: 11, line: 2, column: 1 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,3 @@
+pub mod compiler;
+pub mod resolver;
+pub mod symbol_table;
\ No newline at end of file
@@ -0,0 +1,417 @@
+use crate::ast::ast::{
+    AssignExpression, BlockStatement, CallExpression, Expression, ExpressionStatement,
+    ForStatement, FunctionLiteral, IFExpression, Identifier, InfixExpression, LetStatement,
+    PrefixExpression, Program, ReturnStatement, Statement, TemplateLiteral, WhileStatement,
+};
+use crate::evaluator::{builtins, stack};
+
+use super::symbol_table::{SymbolScope, SymbolTable};
+
+// 在执行前遍历 AST，把每个标识符解析成全局变量、局部变量槽位、自由变量或内置函数，
+// 并记下每个函数字面量的局部变量个数和捕获的变量。编译器按这些记录生成指令。
+pub struct Resolver {
+    symbol_table: SymbolTable,
+    errors: Vec<String>,
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        let mut symbol_table = SymbolTable::new();
+        for (i, name) in builtins::NAMES.iter().enumerate() {
+            symbol_table.define_builtin(i, name);
+        }
+        Resolver::new_with_symbol_table(symbol_table)
+    }
+
+    // 沿用之前定义的全局变量，例如编译器在 REPL 中逐行编译时
+    pub fn new_with_symbol_table(symbol_table: SymbolTable) -> Resolver {
+        Resolver {
+            symbol_table,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn into_symbol_table(self) -> SymbolTable {
+        self.symbol_table
+    }
+
+    pub fn symbol_table(&self) -> &SymbolTable {
+        &self.symbol_table
+    }
+
+    // 由宿主预先放入的全局变量，例如脚本模式的 argc 和 arg
+    pub fn define_global(&mut self, name: &str) {
+        self.symbol_table.define(name);
+    }
+
+    pub fn resolve(&mut self, program: &Program) -> Result<(), String> {
+        self.declare(&program.statements);
+        for stmt in program.statements.iter() {
+            self.resolve_statement(stmt.as_ref());
+        }
+        let errors = std::mem::take(&mut self.errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    // 语句块没有自己的作用域，let 定义的名字在整个函数（或顶层）中可见，
+    // 因此先声明所有的名字，函数体里就可以引用后面才定义的变量，例如互相递归的函数
+    fn declare(&mut self, statements: &[Box<dyn Statement>]) {
+        for stmt in statements.iter() {
+            if let Some(st) = stmt.downcast_ref::<LetStatement>() {
+                self.declare_name(&st.name.value);
+                self.declare_expression(st.value.as_ref());
+            } else if let Some(st) = stmt.downcast_ref::<ExpressionStatement>() {
+                self.declare_expression(st.expression.as_ref());
+            } else if let Some(st) = stmt.downcast_ref::<ReturnStatement>() {
+                self.declare_expression(st.return_value.as_ref());
+            } else if let Some(st) = stmt.downcast_ref::<WhileStatement>() {
+                self.declare_expression(st.condition.as_ref());
+                self.declare(&st.body.statements);
+            } else if let Some(st) = stmt.downcast_ref::<ForStatement>() {
+                // 循环变量和 let 一样定义在所在的函数（或顶层）中
+                if let Some(key) = &st.key {
+                    self.declare_name(&key.value);
+                }
+                self.declare_name(&st.value.value);
+                self.declare_expression(st.iterable.as_ref());
+                self.declare(&st.body.statements);
+            }
+        }
+    }
+
+    fn declare_name(&mut self, name: &str) {
+        let defined = matches!(
+            self.symbol_table.lookup(name),
+            Some(s) if s.scope == SymbolScope::Global || s.scope == SymbolScope::Local
+        );
+        if !defined {
+            self.symbol_table.define(name);
+        }
+    }
+
+    // 函数字面量有自己的作用域，不在这里声明
+    fn declare_expression(&mut self, exp: &dyn Expression) {
+        if let Some(e) = exp.downcast_ref::<IFExpression>() {
+            self.declare_expression(e.condition.as_ref());
+            self.declare(&e.consequence.statements);
+            self.declare(&e.alternative.statements);
+        } else if let Some(e) = exp.downcast_ref::<PrefixExpression>() {
+            self.declare_expression(e.right.as_ref());
+        } else if let Some(e) = exp.downcast_ref::<AssignExpression>() {
+            self.declare_expression(e.value.as_ref());
+        } else if let Some(e) = exp.downcast_ref::<InfixExpression>() {
+            self.declare_expression(e.left.as_ref());
+            self.declare_expression(e.right.as_ref());
+        } else if let Some(e) = exp.downcast_ref::<CallExpression>() {
+            self.declare_expression(e.func.as_ref());
+            for arg in e.args.iter() {
+                self.declare_expression(arg.as_ref());
+            }
+        } else if let Some(e) = exp.downcast_ref::<TemplateLiteral>() {
+            for part in e.expressions.iter() {
+                self.declare_expression(part.as_ref());
+            }
+        }
+    }
+
+    // 栈空间不足时切换到新的栈段，见 stack::grow
+    fn resolve_statement(&mut self, stmt: &dyn Statement) {
+        stack::grow(|| self.resolve_statement_inner(stmt))
+    }
+
+    fn resolve_statement_inner(&mut self, stmt: &dyn Statement) {
+        if let Some(st) = stmt.downcast_ref::<LetStatement>() {
+            self.resolve_expression(st.value.as_ref());
+            let symbol = self.symbol_table.lookup(&st.name.value);
+            st.name.binding.set(symbol.map(|s| s.binding()));
+            self.symbol_table
+                .set_constant(&st.name.value, st.is_const());
+        } else if let Some(st) = stmt.downcast_ref::<ExpressionStatement>() {
+            self.resolve_expression(st.expression.as_ref());
+        } else if let Some(st) = stmt.downcast_ref::<ReturnStatement>() {
+            self.resolve_expression(st.return_value.as_ref());
+        } else if let Some(st) = stmt.downcast_ref::<WhileStatement>() {
+            self.resolve_expression(st.condition.as_ref());
+            self.resolve_block(&st.body);
+        } else if let Some(st) = stmt.downcast_ref::<ForStatement>() {
+            self.resolve_expression(st.iterable.as_ref());
+            for ident in st.key.iter().chain(Some(&st.value)) {
+                let symbol = self.symbol_table.lookup(&ident.value);
+                ident.binding.set(symbol.map(|s| s.binding()));
+            }
+            self.resolve_block(&st.body);
+        }
+    }
+
+    fn resolve_block(&mut self, block: &BlockStatement) {
+        for stmt in block.statements.iter() {
+            self.resolve_statement(stmt.as_ref());
+        }
+    }
+
+    fn resolve_expression(&mut self, exp: &dyn Expression) {
+        stack::grow(|| self.resolve_expression_inner(exp))
+    }
+
+    fn resolve_expression_inner(&mut self, exp: &dyn Expression) {
+        if let Some(e) = exp.downcast_ref::<Identifier>() {
+            self.resolve_identifier(e);
+        } else if let Some(e) = exp.downcast_ref::<PrefixExpression>() {
+            self.resolve_expression(e.right.as_ref());
+        } else if let Some(e) = exp.downcast_ref::<AssignExpression>() {
+            self.resolve_expression(e.value.as_ref());
+            match self.symbol_table.resolve_assignment(&e.target.value) {
+                Ok(symbol) => e.target.binding.set(Some(symbol.binding())),
+                Err(msg) => self.error(&e.target, msg),
+            }
+        } else if let Some(e) = exp.downcast_ref::<InfixExpression>() {
+            self.resolve_expression(e.left.as_ref());
+            self.resolve_expression(e.right.as_ref());
+        } else if let Some(e) = exp.downcast_ref::<IFExpression>() {
+            self.resolve_expression(e.condition.as_ref());
+            self.resolve_block(&e.consequence);
+            self.resolve_block(&e.alternative);
+        } else if let Some(e) = exp.downcast_ref::<FunctionLiteral>() {
+            self.resolve_function(e);
+        } else if let Some(e) = exp.downcast_ref::<CallExpression>() {
+            self.resolve_expression(e.func.as_ref());
+            for arg in e.args.iter() {
+                self.resolve_expression(arg.as_ref());
+            }
+        } else if let Some(e) = exp.downcast_ref::<TemplateLiteral>() {
+            for part in e.expressions.iter() {
+                self.resolve_expression(part.as_ref());
+            }
+        }
+    }
+
+    fn resolve_identifier(&mut self, ident: &Identifier) {
+        match self.symbol_table.resolve(&ident.value) {
+            Some(symbol) => ident.binding.set(Some(symbol.binding())),
+            None => self.error(ident, format!("identifier not found: {}", ident.value)),
+        }
+    }
+
+    // 函数用 let 绑定的名字不在函数内部定义，递归调用时和其他名字一样从外层解析
+    fn resolve_function(&mut self, func: &FunctionLiteral) {
+        let outer = std::mem::take(&mut self.symbol_table);
+        self.symbol_table = SymbolTable::new_enclosed(outer);
+        for (i, param) in func.params.iter().enumerate() {
+            if func.params[..i].iter().any(|p| p.value == param.value) {
+                self.error(param, format!("duplicate parameter name: {}", param.value));
+            }
+            let symbol = self.symbol_table.define(&param.value);
+            param.binding.set(Some(symbol.binding()));
+        }
+        self.declare(&func.body.statements);
+        self.resolve_block(&func.body);
+
+        func.num_locals.set(self.symbol_table.num_definitions);
+        *func.free.borrow_mut() = self
+            .symbol_table
+            .free_symbols
+            .iter()
+            .map(|s| s.binding())
+            .collect();
+        let mut captured: Vec<usize> = self.symbol_table.captured.iter().copied().collect();
+        captured.sort();
+        *func.captured.borrow_mut() = captured;
+        let inner = std::mem::take(&mut self.symbol_table);
+        if let Some(outer) = inner.outer {
+            self.symbol_table = *outer;
+        }
+    }
+
+    fn error(&mut self, ident: &Identifier, message: String) {
+        if ident.token.line > 0 {
+            self.errors.push(format!(
+                "{} (line {}, column {})",
+                message, ident.token.line, ident.token.column
+            ));
+        } else {
+            self.errors.push(message);
+        }
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::symbol_table::Binding;
+    use crate::lexer::lexer::Lexer;
+    use crate::parser::parser::Parser;
+
+    fn parse(input: &str) -> Program {
+        Parser::new(Lexer::new(input.to_string()))
+            .parse_program()
+            .unwrap()
+    }
+
+    fn binding(scope: SymbolScope, index: usize) -> Option<Binding> {
+        Some(Binding { scope, index })
+    }
+
+    fn expression(stmt: &dyn Statement) -> &dyn Expression {
+        match stmt.downcast_ref::<LetStatement>() {
+            Some(st) => st.value.as_ref(),
+            None => stmt
+                .downcast_ref::<ExpressionStatement>()
+                .unwrap()
+                .expression
+                .as_ref(),
+        }
+    }
+
+    fn function(exp: &dyn Expression) -> &FunctionLiteral {
+        exp.downcast_ref::<FunctionLiteral>().unwrap()
+    }
+
+    fn identifier(stmt: &dyn Statement) -> Option<Binding> {
+        let exp = expression(stmt);
+        exp.downcast_ref::<Identifier>().unwrap().binding.get()
+    }
+
+    #[test]
+    fn test_resolve_bindings() {
+        let input = "
+let a = 1;
+let f = fn(b, c) {
+  let d = b;
+  fn(e) { a + b + d + e + f + puts }
+};
+a;
+";
+        let pgm = parse(input);
+        Resolver::new().resolve(&pgm).unwrap();
+
+        let a = pgm.statements[0].downcast_ref::<LetStatement>().unwrap();
+        assert_eq!(a.name.binding.get(), binding(SymbolScope::Global, 0));
+        assert_eq!(
+            identifier(pgm.statements[2].as_ref()),
+            binding(SymbolScope::Global, 0)
+        );
+
+        let f = function(expression(pgm.statements[1].as_ref()));
+        assert_eq!(f.params[1].binding.get(), binding(SymbolScope::Local, 1));
+        assert_eq!(f.num_locals.get(), 3);
+        assert!(f.free.borrow().is_empty());
+        assert_eq!(*f.captured.borrow(), vec![0, 2]);
+        let d = f.body.statements[0].downcast_ref::<LetStatement>().unwrap();
+        assert_eq!(d.name.binding.get(), binding(SymbolScope::Local, 2));
+        assert_eq!(
+            identifier(f.body.statements[0].as_ref()),
+            binding(SymbolScope::Local, 0)
+        );
+
+        // 内层函数捕获外层的 b 和 d，f 是全局变量，不需要捕获
+        let inner = function(expression(f.body.statements[1].as_ref()));
+        assert_eq!(inner.num_locals.get(), 1);
+        assert!(inner.captured.borrow().is_empty());
+        assert_eq!(
+            *inner.free.borrow(),
+            vec![
+                Binding {
+                    scope: SymbolScope::Local,
+                    index: 0
+                },
+                Binding {
+                    scope: SymbolScope::Local,
+                    index: 2
+                },
+            ]
+        );
+        let body = inner.body.statements[0].to_string();
+        assert_eq!(body, "(((((a + b) + d) + e) + f) + puts)");
+        let mut bindings = Vec::new();
+        let mut exp = expression(inner.body.statements[0].as_ref());
+        while let Some(e) = exp.downcast_ref::<InfixExpression>() {
+            let right = e.right.downcast_ref::<Identifier>().unwrap();
+            bindings.push(right.binding.get().unwrap());
+            exp = e.left.as_ref();
+        }
+        bindings.push(
+            exp.downcast_ref::<Identifier>()
+                .unwrap()
+                .binding
+                .get()
+                .unwrap(),
+        );
+        bindings.reverse();
+        let scopes: Vec<(SymbolScope, usize)> =
+            bindings.iter().map(|b| (b.scope, b.index)).collect();
+        assert_eq!(
+            scopes,
+            vec![
+                (SymbolScope::Global, 0),
+                (SymbolScope::Free, 0),
+                (SymbolScope::Free, 1),
+                (SymbolScope::Local, 0),
+                (SymbolScope::Global, 1),
+                (SymbolScope::Builtin, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_forward_references() {
+        let input = "
+let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };
+let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };
+if (true) { let c = 1; }
+c
+";
+        let pgm = parse(input);
+        Resolver::new().resolve(&pgm).unwrap();
+        assert_eq!(
+            identifier(pgm.statements[3].as_ref()),
+            binding(SymbolScope::Global, 2)
+        );
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let tests = vec![
+            ("x", "identifier not found: x (line 1, column 1)"),
+            (
+                "let f = fn(a, b, a) { a };\nf(y, z)",
+                "duplicate parameter name: a (line 1, column 18)\nidentifier not found: y (line 2, column 3)\nidentifier not found: z (line 2, column 6)",
+            ),
+            (
+                "let f = fn() { let g = fn() { h }; g };",
+                "identifier not found: h (line 1, column 31)",
+            ),
+            (
+                "if (false) { fn(x) { x + w } }",
+                "identifier not found: w (line 1, column 26)",
+            ),
+            (
+                "const a = 1;\nlet f = fn(b) { const c = b; fn() { a = c; c += 1 } };",
+                "cannot assign to constant: a (line 2, column 37)\ncannot assign to constant: c (line 2, column 44)",
+            ),
+        ];
+        for (input, expected) in tests {
+            let pgm = parse(input);
+            assert_eq!(
+                Resolver::new().resolve(&pgm),
+                Err(String::from(expected)),
+                "{}",
+                input
+            );
+        }
+
+        let pgm = parse("argc() + 1");
+        let mut resolver = Resolver::new();
+        resolver.define_global("argc");
+        assert_eq!(resolver.resolve(&pgm), Ok(()));
+    }
+}
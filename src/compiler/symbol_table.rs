@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SymbolScope {
+    Global,
+    Local,
+    Builtin,
+    // 闭包从外层函数捕获的变量
+    Free,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub scope: SymbolScope,
+    pub index: usize,
+}
+
+impl Symbol {
+    pub fn binding(&self) -> Binding {
+        Binding {
+            scope: self.scope,
+            index: self.index,
+        }
+    }
+}
+
+// 标识符解析后的绑定，由 resolver 记录在 AST 上
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Binding {
+    pub scope: SymbolScope,
+    pub index: usize,
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    pub outer: Option<Box<SymbolTable>>,
+    store: HashMap<String, Symbol>,
+    // 用 const 定义的名字
+    constants: HashSet<String>,
+    pub num_definitions: usize,
+    // 按下标记录每次定义的名字
+    names: Vec<String>,
+    pub free_symbols: Vec<Symbol>,
+    // 被内层函数捕获的局部变量的下标
+    pub captured: HashSet<usize>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn new_enclosed(outer: SymbolTable) -> SymbolTable {
+        SymbolTable {
+            outer: Some(Box::new(outer)),
+            ..SymbolTable::default()
+        }
+    }
+
+    pub fn define(&mut self, name: &str) -> Symbol {
+        let scope = if self.outer.is_some() {
+            SymbolScope::Local
+        } else {
+            SymbolScope::Global
+        };
+        let symbol = Symbol {
+            name: name.to_string(),
+            scope,
+            index: self.num_definitions,
+        };
+        self.store.insert(name.to_string(), symbol.clone());
+        self.constants.remove(name);
+        self.names.push(name.to_string());
+        self.num_definitions += 1;
+        symbol
+    }
+
+    pub fn set_constant(&mut self, name: &str, constant: bool) {
+        if constant {
+            self.constants.insert(name.to_string());
+        } else {
+            self.constants.remove(name);
+        }
+    }
+
+    // 自由变量是否是常量由定义它的外层作用域决定
+    fn is_constant(&self, name: &str) -> bool {
+        match self.store.get(name) {
+            Some(s) if s.scope != SymbolScope::Free => self.constants.contains(name),
+            _ => self.outer.as_ref().is_some_and(|o| o.is_constant(name)),
+        }
+    }
+
+    // 闭包和外层函数共享捕获的变量，给自由变量赋值会修改外层函数中的变量
+    pub fn resolve_assignment(&mut self, name: &str) -> Result<Symbol, String> {
+        let symbol = match self.resolve(name) {
+            Some(symbol) => symbol,
+            None => return Err(format!("identifier not found: {}", name)),
+        };
+        match symbol.scope {
+            SymbolScope::Builtin => Err(format!("cannot assign to builtin: {}", name)),
+            _ if self.is_constant(name) => Err(format!("cannot assign to constant: {}", name)),
+            _ => Ok(symbol),
+        }
+    }
+
+    pub fn define_builtin(&mut self, index: usize, name: &str) -> Symbol {
+        let symbol = Symbol {
+            name: name.to_string(),
+            scope: SymbolScope::Builtin,
+            index,
+        };
+        self.store.insert(name.to_string(), symbol.clone());
+        symbol
+    }
+
+    fn define_free(&mut self, original: Symbol) -> Symbol {
+        let symbol = Symbol {
+            name: original.name.clone(),
+            scope: SymbolScope::Free,
+            index: self.free_symbols.len(),
+        };
+        self.free_symbols.push(original);
+        self.store.insert(symbol.name.clone(), symbol.clone());
+        symbol
+    }
+
+    // 最外层作用域中定义的全局变量名，下标和全局变量的下标对应
+    pub fn global_names(&self) -> Vec<String> {
+        match &self.outer {
+            Some(outer) => outer.global_names(),
+            None => self.names.clone(),
+        }
+    }
+
+    // 只在当前作用域中查找
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.store.get(name)
+    }
+
+    // 在外层找到的局部变量需要作为自由变量捕获，同时在外层记下它被捕获了
+    pub fn resolve(&mut self, name: &str) -> Option<Symbol> {
+        if let Some(symbol) = self.store.get(name) {
+            return Some(symbol.clone());
+        }
+        let outer = self.outer.as_mut()?;
+        let symbol = outer.resolve(name)?;
+        match symbol.scope {
+            SymbolScope::Global | SymbolScope::Builtin => Some(symbol),
+            SymbolScope::Local => {
+                outer.captured.insert(symbol.index);
+                Some(self.define_free(symbol))
+            }
+            SymbolScope::Free => Some(self.define_free(symbol)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, scope: SymbolScope, index: usize) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            scope,
+            index,
+        }
+    }
+
+    #[test]
+    fn test_resolve_nested() {
+        let mut global = SymbolTable::new();
+        global.define("a");
+        global.define_builtin(0, "puts");
+        let mut first = SymbolTable::new_enclosed(global);
+        first.define("b");
+        let mut second = SymbolTable::new_enclosed(first);
+        second.define("c");
+
+        assert_eq!(
+            second.resolve("a"),
+            Some(symbol("a", SymbolScope::Global, 0))
+        );
+        assert_eq!(
+            second.resolve("puts"),
+            Some(symbol("puts", SymbolScope::Builtin, 0))
+        );
+        assert_eq!(
+            second.resolve("c"),
+            Some(symbol("c", SymbolScope::Local, 0))
+        );
+        assert_eq!(second.resolve("b"), Some(symbol("b", SymbolScope::Free, 0)));
+        assert_eq!(
+            second.free_symbols,
+            vec![symbol("b", SymbolScope::Local, 0)]
+        );
+        assert_eq!(second.resolve("d"), None);
+        let first = second.outer.as_ref().unwrap();
+        assert_eq!(first.captured, HashSet::from([0]));
+        assert!(second.captured.is_empty());
+    }
+}
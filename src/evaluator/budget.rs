@@ -0,0 +1,143 @@
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::object::object::{self, Object};
+
+// 读取时钟和取消标记相对较慢，每执行这么多步才检查一次
+const CHECK_INTERVAL: u64 = 1024;
+
+// 被超出的预算
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps(u64),
+    Deadline,
+    CollectionSize(usize),
+    Cancelled,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps(max) => write!(f, "more than {} steps executed", max),
+            Limit::Deadline => write!(f, "deadline reached"),
+            Limit::CollectionSize(max) => write!(f, "collection larger than {} elements", max),
+            Limit::Cancelled => write!(f, "execution cancelled"),
+        }
+    }
+}
+
+// 可以交给其他线程，用来中止正在执行的脚本
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// 执行预算，为 None 的项不做限制。树遍历解释器按求值的语句和表达式计步，虚拟机按指令计步
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    pub max_steps: Option<u64>,
+    pub deadline: Option<Instant>,
+    pub max_collection_size: Option<usize>,
+    pub cancel: Option<CancelHandle>,
+}
+
+// 解释器持有的计步器，按预算检查已经执行的步数
+#[derive(Debug, Default)]
+pub struct Meter {
+    budget: Budget,
+    steps: u64,
+}
+
+impl Meter {
+    pub fn new(budget: Budget) -> Meter {
+        Meter { budget, steps: 0 }
+    }
+
+    // 每执行一步调用一次
+    pub fn tick(&mut self) -> Result<(), Limit> {
+        self.steps += 1;
+        if let Some(max) = self.budget.max_steps {
+            if self.steps > max {
+                return Err(Limit::Steps(max));
+            }
+        }
+        if self.steps.is_multiple_of(CHECK_INTERVAL) {
+            if self
+                .budget
+                .cancel
+                .as_ref()
+                .is_some_and(|c| c.is_cancelled())
+            {
+                return Err(Limit::Cancelled);
+            }
+            if self.budget.deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(Limit::Deadline);
+            }
+        }
+        Ok(())
+    }
+
+    // 检查新创建的集合的大小，目前只有内置函数返回的字符串和插值拼接出的字符串
+    pub fn check_size(&self, obj: &Rc<dyn Object>) -> Result<(), Limit> {
+        let size = match obj.downcast_ref::<object::Str>() {
+            Some(s) => s.value.chars().count(),
+            None => return Ok(()),
+        };
+        match self.budget.max_collection_size {
+            Some(max) if size > max => Err(Limit::CollectionSize(max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_meter_tick() {
+        let mut meter = Meter::new(Budget {
+            max_steps: Some(3),
+            ..Budget::default()
+        });
+        for _ in 0..3 {
+            assert_eq!(meter.tick(), Ok(()));
+        }
+        assert_eq!(meter.tick(), Err(Limit::Steps(3)));
+
+        let mut meter = Meter::new(Budget {
+            deadline: Some(Instant::now()),
+            ..Budget::default()
+        });
+        let result = (0..CHECK_INTERVAL).try_for_each(|_| meter.tick());
+        assert_eq!(result, Err(Limit::Deadline));
+
+        let cancel = CancelHandle::new();
+        let mut meter = Meter::new(Budget {
+            deadline: Some(Instant::now() + Duration::from_secs(60)),
+            cancel: Some(cancel.clone()),
+            ..Budget::default()
+        });
+        std::thread::spawn(move || cancel.cancel()).join().unwrap();
+        let result = (0..CHECK_INTERVAL).try_for_each(|_| meter.tick());
+        assert_eq!(result, Err(Limit::Cancelled));
+    }
+}
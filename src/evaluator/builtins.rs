@@ -0,0 +1,172 @@
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+use num_traits::FromPrimitive;
+
+use crate::object::bigint;
+use crate::object::float;
+use crate::object::object::{new_error, Builtin, BuiltinFunction, Float, Object, Str};
+
+use super::evaluator::null;
+
+// 编译器按这里的顺序给内置函数编号
+pub const NAMES: &[&str] = &["puts", "int", "float"];
+
+pub fn get(name: &str) -> Option<Rc<dyn Object>> {
+    let func: BuiltinFunction = match name {
+        "puts" => Rc::new(puts),
+        "int" => Rc::new(int),
+        "float" => Rc::new(float),
+        _ => return None,
+    };
+    Some(Rc::new(Builtin {
+        name: name.to_string(),
+        func,
+    }))
+}
+
+fn puts(args: Vec<Rc<dyn Object>>) -> Rc<dyn Object> {
+    for arg in args {
+        println!("{}", arg.inspect());
+    }
+    null()
+}
+
+// 小数向零取整，字符串按十进制解析
+fn int(args: Vec<Rc<dyn Object>>) -> Rc<dyn Object> {
+    if args.len() != 1 {
+        return new_error(format!(
+            "wrong number of arguments: want=1, got={}",
+            args.len()
+        ));
+    }
+    let arg = &args[0];
+    if bigint::to_bigint(arg).is_some() {
+        return arg.clone();
+    }
+    if let Some(f) = arg.downcast_ref::<Float>() {
+        return match BigInt::from_f64(f.value.trunc()) {
+            Some(value) => bigint::normalize(value),
+            None => new_error(format!(
+                "cannot convert {} to INTEGER",
+                shorten(&arg.inspect())
+            )),
+        };
+    }
+    if let Some(s) = arg.downcast_ref::<Str>() {
+        return match s.value.trim().parse::<BigInt>() {
+            Ok(value) => bigint::normalize(value),
+            Err(_) => new_error(format!("cannot convert {:?} to INTEGER", shorten(&s.value))),
+        };
+    }
+    new_error(format!(
+        "argument to `int` must be a number or STRING, got {:?}",
+        arg.type_of()
+    ))
+}
+
+fn float(args: Vec<Rc<dyn Object>>) -> Rc<dyn Object> {
+    if args.len() != 1 {
+        return new_error(format!(
+            "wrong number of arguments: want=1, got={}",
+            args.len()
+        ));
+    }
+    let arg = &args[0];
+    if let Some(s) = arg.downcast_ref::<Str>() {
+        return match s.value.trim().parse::<f64>() {
+            Ok(value) if value.is_finite() => Rc::new(Float { value }),
+            _ => new_error(format!("cannot convert {:?} to FLOAT", shorten(&s.value))),
+        };
+    }
+    match float::to_f64(arg) {
+        Some(value) if value.is_finite() => Rc::new(Float { value }),
+        Some(_) => new_error(format!(
+            "cannot convert {} to FLOAT",
+            shorten(&arg.inspect())
+        )),
+        None => new_error(format!(
+            "argument to `float` must be a number or STRING, got {:?}",
+            arg.type_of()
+        )),
+    }
+}
+
+// 错误信息中过长的值只保留开头，如 2 ** 2000 这样的大整数
+fn shorten(text: &str) -> String {
+    const MAX_CHARS: usize = 32;
+    let n = text.chars().count();
+    if n <= MAX_CHARS {
+        return text.to_string();
+    }
+    let head: String = text.chars().take(MAX_CHARS).collect();
+    format!("{}... ({} chars)", head, n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::object::BigInteger;
+
+    fn call(name: &str, arg: Rc<dyn Object>) -> String {
+        let builtin = get(name).unwrap();
+        let builtin = builtin.downcast_ref::<Builtin>().unwrap();
+        (builtin.func)(vec![arg]).inspect()
+    }
+
+    fn string(value: &str) -> Rc<dyn Object> {
+        Rc::new(Str {
+            value: value.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_conversions() {
+        let tests = vec![
+            ("int", string(" -42 "), "-42"),
+            (
+                "int",
+                string("18446744073709551616"),
+                "18446744073709551616",
+            ),
+            ("int", Rc::new(Float { value: -2.9 }), "-2"),
+            (
+                "int",
+                Rc::new(Float { value: 1e20 }),
+                "100000000000000000000",
+            ),
+            (
+                "int",
+                string("4.5"),
+                "ERROR: cannot convert \"4.5\" to INTEGER",
+            ),
+            (
+                "int",
+                Rc::new(Float { value: f64::NAN }),
+                "ERROR: cannot convert NaN to INTEGER",
+            ),
+            ("float", string("2.5e3"), "2500.0"),
+            (
+                "float",
+                string("inf"),
+                "ERROR: cannot convert \"inf\" to FLOAT",
+            ),
+            ("float", string("x"), "ERROR: cannot convert \"x\" to FLOAT"),
+            (
+                "float",
+                Rc::new(BigInteger {
+                    value: BigInt::from(1) << 2000,
+                }),
+                "ERROR: cannot convert 11481306952742545242328332011776... (603 chars) to FLOAT",
+            ),
+            (
+                "int",
+                string(&"9".repeat(40).replace("99", "9x")),
+                "ERROR: cannot convert \"9x9x9x9x9x9x9x9x9x9x9x9x9x9x9x9x... (40 chars)\" to INTEGER",
+            ),
+        ];
+        for (name, arg, expected) in tests {
+            assert_eq!(call(name, arg), expected, "{}", name);
+        }
+    }
+}
@@ -1,6 +1,1306 @@
-use crate::object::object;
-use crate::ast::ast;
+use std::cell::RefCell;
+use std::rc::Rc;
 
-pub fn eval(node: Box<dyn ast::Node>) -> Box<dyn object::Object> {
+use num_bigint::BigInt;
 
-}
\ No newline at end of file
+use crate::ast::ast::{
+    AssignExpression, BigIntegerLiteral, BlockStatement, Boolean, BreakStatement, CallExpression,
+    ContinueStatement, Expression, ExpressionStatement, FloatLiteral, ForStatement,
+    FunctionLiteral, IFExpression, Identifier, InfixExpression, IntegerLiteral, LetStatement,
+    PrefixExpression, Program, ReturnStatement, Statement, StringLiteral, TemplateLiteral,
+    WhileStatement,
+};
+use crate::object::bigint;
+use crate::object::environment::Environment;
+use crate::object::float;
+use crate::object::object::{self, is_error, Object, ObjectType, TraceFrame};
+use crate::token::token::Token;
+
+use super::budget::{Budget, Meter};
+use super::builtins;
+use super::profile::{Profiler, Stats};
+use super::stack;
+
+pub fn eval(program: &Program, env: &Rc<RefCell<Environment>>) -> Rc<dyn Object> {
+    Evaluator::new().eval(program, env)
+}
+
+// 被调用的函数和求好值的参数
+type Call = (Rc<dyn Object>, Vec<Rc<dyn Object>>);
+
+// 默认允许的最大函数调用深度，尾调用不计入
+pub const MAX_CALL_DEPTH: usize = 1000;
+
+pub struct Evaluator {
+    pub stats: Stats,
+    pub profiler: Option<Profiler>,
+    pub max_depth: usize,
+    meter: Meter,
+    // 正在执行的函数调用链，为空时在顶层
+    calls: Vec<TraceFrame>,
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Evaluator {
+    pub fn new() -> Evaluator {
+        Evaluator {
+            stats: Stats::default(),
+            profiler: None,
+            max_depth: MAX_CALL_DEPTH,
+            meter: Meter::default(),
+            calls: Vec::new(),
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.meter = Meter::new(budget);
+    }
+
+    pub fn eval(&mut self, program: &Program, env: &Rc<RefCell<Environment>>) -> Rc<dyn Object> {
+        let mut result: Rc<dyn Object> = self.null();
+        for stmt in program.statements.iter() {
+            result = self.eval_statement(stmt.as_ref(), env);
+            if let Some(rv) = result.downcast_ref::<object::ReturnValue>() {
+                return rv.value.clone();
+            }
+            if is_error(&result) {
+                return result;
+            }
+        }
+        result
+    }
+
+    fn eval_block_statement(
+        &mut self,
+        block: &BlockStatement,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        let mut result: Rc<dyn Object> = self.null();
+        for stmt in block.statements.iter() {
+            result = self.eval_statement(stmt.as_ref(), env);
+            // 返回值不在这里解包，交给外层的函数调用处理，break 和 continue 交给外层的循环
+            if interrupts(&result) {
+                return result;
+            }
+        }
+        result
+    }
+
+    // 语句和表达式的每层嵌套、每次函数调用都会递归到这里，栈空间不足时切换到新的栈段
+    fn eval_statement(
+        &mut self,
+        stmt: &dyn Statement,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        stack::grow(|| self.eval_statement_inner(stmt, env))
+    }
+
+    fn eval_statement_inner(
+        &mut self,
+        stmt: &dyn Statement,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        self.stats.steps += 1;
+        if let Err(limit) = self.meter.tick() {
+            return self.alloc(object::BudgetExceeded { limit });
+        }
+        if let Some(st) = stmt.downcast_ref::<ExpressionStatement>() {
+            self.eval_expression(st.expression.as_ref(), env)
+        } else if let Some(st) = stmt.downcast_ref::<LetStatement>() {
+            // let 绑定的函数字面量记下名字，用于性能分析等场景
+            let val = match st.value.downcast_ref::<FunctionLiteral>() {
+                Some(func) => self.eval_function_literal(func, env, Some(&st.name.value)),
+                None => self.eval_expression(st.value.as_ref(), env),
+            };
+            if interrupts(&val) {
+                return val;
+            }
+            if st.is_const() {
+                env.borrow_mut().set_const(st.name.value.clone(), val);
+            } else {
+                env.borrow_mut().set(st.name.value.clone(), val);
+            }
+            self.null()
+        } else if let Some(st) = stmt.downcast_ref::<ReturnStatement>() {
+            // 函数体中 return 的调用总是尾调用
+            let val = match st.return_value.downcast_ref::<CallExpression>() {
+                Some(call) if !self.calls.is_empty() => self.eval_tail_call(call, env),
+                _ => self.eval_expression(st.return_value.as_ref(), env),
+            };
+            if is_error(&val) {
+                return val;
+            }
+            self.alloc(object::ReturnValue { value: val })
+        } else if let Some(st) = stmt.downcast_ref::<WhileStatement>() {
+            self.eval_while_statement(st, env)
+        } else if let Some(st) = stmt.downcast_ref::<ForStatement>() {
+            self.eval_for_statement(st, env)
+        } else if stmt.downcast_ref::<BreakStatement>().is_some() {
+            self.alloc(object::Break {})
+        } else if stmt.downcast_ref::<ContinueStatement>().is_some() {
+            self.alloc(object::Continue {})
+        } else {
+            self.error(format!("unknown statement: {}", stmt.to_string()))
+        }
+    }
+
+    fn eval_expression(
+        &mut self,
+        exp: &dyn Expression,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        stack::grow(|| self.eval_expression_inner(exp, env))
+    }
+
+    fn eval_expression_inner(
+        &mut self,
+        exp: &dyn Expression,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        self.stats.steps += 1;
+        if let Err(limit) = self.meter.tick() {
+            return self.alloc(object::BudgetExceeded { limit });
+        }
+        if let Some(e) = exp.downcast_ref::<IntegerLiteral>() {
+            self.alloc(object::Integer { value: e.value })
+        } else if let Some(e) = exp.downcast_ref::<FloatLiteral>() {
+            self.alloc(object::Float { value: e.value })
+        } else if let Some(e) = exp.downcast_ref::<BigIntegerLiteral>() {
+            self.alloc(object::BigInteger {
+                value: e.value.clone(),
+            })
+        } else if let Some(e) = exp.downcast_ref::<StringLiteral>() {
+            self.alloc(object::Str {
+                value: e.value.clone(),
+            })
+        } else if let Some(e) = exp.downcast_ref::<TemplateLiteral>() {
+            self.eval_template_literal(e, env)
+        } else if let Some(e) = exp.downcast_ref::<Boolean>() {
+            self.native_bool(e.value)
+        } else if let Some(e) = exp.downcast_ref::<Identifier>() {
+            self.eval_identifier(e, env)
+        } else if let Some(e) = exp.downcast_ref::<PrefixExpression>() {
+            let right = self.eval_expression(e.right.as_ref(), env);
+            if is_error(&right) {
+                return right;
+            }
+            self.eval_prefix_expression(&e.operator, right)
+        } else if let Some(e) = exp.downcast_ref::<InfixExpression>() {
+            let left = self.eval_expression(e.left.as_ref(), env);
+            if is_error(&left) {
+                return left;
+            }
+            // && 和 || 短路求值，结果总是布尔值
+            match (e.operator.as_str(), is_truthy(&left)) {
+                ("&&", false) => return self.native_bool(false),
+                ("||", true) => return self.native_bool(true),
+                ("&&", true) | ("||", false) => {
+                    let right = self.eval_expression(e.right.as_ref(), env);
+                    if is_error(&right) {
+                        return right;
+                    }
+                    return self.native_bool(is_truthy(&right));
+                }
+                _ => {}
+            }
+            let right = self.eval_expression(e.right.as_ref(), env);
+            if is_error(&right) {
+                return right;
+            }
+            self.eval_infix_expression(&e.operator, left, right)
+        } else if let Some(e) = exp.downcast_ref::<AssignExpression>() {
+            self.eval_assign_expression(e, env)
+        } else if let Some(e) = exp.downcast_ref::<IFExpression>() {
+            self.eval_if_expression(e, env)
+        } else if let Some(e) = exp.downcast_ref::<FunctionLiteral>() {
+            self.eval_function_literal(e, env, None)
+        } else if let Some(e) = exp.downcast_ref::<CallExpression>() {
+            match self.eval_call(e, env) {
+                Ok((func, args)) => self.apply_function(func, args, &e.token),
+                Err(err) => err,
+            }
+        } else {
+            self.error(format!("unknown expression: {}", exp.to_string()))
+        }
+    }
+
+    // 依次求出插值中的表达式，和各段字符串拼接起来
+    fn eval_template_literal(
+        &mut self,
+        e: &TemplateLiteral,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        let mut value = e.strings[0].clone();
+        for (exp, s) in e.expressions.iter().zip(e.strings[1..].iter()) {
+            let part = self.eval_expression(exp.as_ref(), env);
+            if is_error(&part) {
+                return part;
+            }
+            value.push_str(&part.inspect());
+            value.push_str(s);
+        }
+        let result = self.alloc(object::Str { value });
+        match self.meter.check_size(&result) {
+            Ok(()) => result,
+            Err(limit) => self.alloc(object::BudgetExceeded { limit }),
+        }
+    }
+
+    // 求出被调用的函数和参数，出错时返回错误对象
+    fn eval_call(
+        &mut self,
+        call: &CallExpression,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Result<Call, Rc<dyn Object>> {
+        let func = self.eval_expression(call.func.as_ref(), env);
+        if is_error(&func) {
+            return Err(func);
+        }
+        let mut args = Vec::new();
+        for arg in call.args.iter() {
+            let val = self.eval_expression(arg.as_ref(), env);
+            if is_error(&val) {
+                return Err(val);
+            }
+            args.push(val);
+        }
+        Ok((func, args))
+    }
+
+    // 函数体按尾位置求值：最后一条表达式语句（包括其中 if 的分支）里的调用不在这里执行，
+    // 而是返回 TailCall 交给 apply_function 在循环中执行，这样深度递归不会增长调用栈
+    fn eval_tail_block(
+        &mut self,
+        block: &BlockStatement,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        let mut result: Rc<dyn Object> = self.null();
+        for (i, stmt) in block.statements.iter().enumerate() {
+            result = match stmt.downcast_ref::<ExpressionStatement>() {
+                Some(st) if i + 1 == block.statements.len() => {
+                    self.stats.steps += 1;
+                    self.eval_tail_expression(st.expression.as_ref(), env)
+                }
+                _ => self.eval_statement(stmt.as_ref(), env),
+            };
+            if interrupts(&result) {
+                return result;
+            }
+        }
+        result
+    }
+
+    fn eval_tail_expression(
+        &mut self,
+        exp: &dyn Expression,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        if let Some(e) = exp.downcast_ref::<CallExpression>() {
+            self.stats.steps += 1;
+            self.eval_tail_call(e, env)
+        } else if let Some(e) = exp.downcast_ref::<IFExpression>() {
+            self.stats.steps += 1;
+            let condition = self.eval_expression(e.condition.as_ref(), env);
+            if is_error(&condition) {
+                return condition;
+            }
+            if is_truthy(&condition) {
+                self.eval_tail_block(&e.consequence, env)
+            } else {
+                self.eval_tail_block(&e.alternative, env)
+            }
+        } else {
+            self.eval_expression(exp, env)
+        }
+    }
+
+    fn eval_tail_call(
+        &mut self,
+        call: &CallExpression,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        match self.eval_call(call, env) {
+            Ok((func, args)) if func.downcast_ref::<object::Function>().is_some() => {
+                self.alloc(object::TailCall {
+                    func,
+                    args,
+                    line: call.token.line,
+                    column: call.token.column,
+                })
+            }
+            Ok((func, args)) => self.apply_function(func, args, &call.token),
+            Err(err) => err,
+        }
+    }
+
+    fn eval_assign_expression(
+        &mut self,
+        ae: &AssignExpression,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        // 复合赋值先读出变量的当前值，再求右边的值
+        let current = match ae.binary_operator() {
+            Some(_) => {
+                let current = self.eval_identifier(&ae.target, env);
+                if is_error(&current) {
+                    return current;
+                }
+                Some(current)
+            }
+            None => None,
+        };
+        let mut val = self.eval_expression(ae.value.as_ref(), env);
+        if interrupts(&val) {
+            return val;
+        }
+        if let (Some(op), Some(current)) = (ae.binary_operator(), current) {
+            val = self.eval_infix_expression(op, current, val);
+            if is_error(&val) {
+                return val;
+            }
+        }
+        let name = &ae.target.value;
+        let assigned = env.borrow_mut().assign(name, val.clone());
+        match assigned {
+            Ok(()) => val,
+            Err(_) if builtins::get(name).is_some() => {
+                self.error(format!("cannot assign to builtin: {}", name))
+            }
+            Err(msg) => self.error(msg),
+        }
+    }
+
+    fn eval_identifier(
+        &mut self,
+        ident: &Identifier,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        if let Some(val) = env.borrow().get(&ident.value) {
+            return val;
+        }
+        if let Some(builtin) = builtins::get(&ident.value) {
+            return builtin;
+        }
+        self.error(format!("identifier not found: {}", ident.value))
+    }
+
+    fn eval_function_literal(
+        &mut self,
+        func: &FunctionLiteral,
+        env: &Rc<RefCell<Environment>>,
+        name: Option<&str>,
+    ) -> Rc<dyn Object> {
+        self.alloc(object::Function {
+            name: name.map(|n| n.to_string()),
+            params: func.params.clone(),
+            body: func.body.clone(),
+            env: env.clone(),
+        })
+    }
+
+    fn eval_prefix_expression(&mut self, operator: &str, right: Rc<dyn Object>) -> Rc<dyn Object> {
+        match operator {
+            "!" => self.native_bool(!is_truthy(&right)),
+            "-" => match right.downcast_ref::<object::Integer>() {
+                Some(i) => match i.value.checked_neg() {
+                    Some(value) => self.alloc(object::Integer { value }),
+                    None => self.big_integer(-BigInt::from(i.value)),
+                },
+                None => match (
+                    right.downcast_ref::<object::Float>(),
+                    bigint::to_bigint(&right),
+                ) {
+                    (Some(f), _) => self.alloc(object::Float { value: -f.value }),
+                    (_, Some(value)) => self.big_integer(-value),
+                    _ => self.error(format!("unknown operator: -{:?}", right.type_of())),
+                },
+            },
+            "~" => match bigint::to_bigint(&right) {
+                Some(value) => self.big_integer(!value),
+                None => self.error(format!("unknown operator: ~{:?}", right.type_of())),
+            },
+            _ => self.error(format!(
+                "unknown operator: {}{:?}",
+                operator,
+                right.type_of()
+            )),
+        }
+    }
+
+    fn eval_infix_expression(
+        &mut self,
+        operator: &str,
+        left: Rc<dyn Object>,
+        right: Rc<dyn Object>,
+    ) -> Rc<dyn Object> {
+        if let (Some(l), Some(r)) = (
+            left.downcast_ref::<object::Integer>(),
+            right.downcast_ref::<object::Integer>(),
+        ) {
+            return self.eval_integer_infix_expression(operator, l.value, r.value);
+        }
+        // 有一边是小数时按小数计算
+        if left.type_of() == ObjectType::FLOAT || right.type_of() == ObjectType::FLOAT {
+            if let (Some(l), Some(r)) = (float::to_f64(&left), float::to_f64(&right)) {
+                if let Some(result) = self.eval_float_infix_expression(operator, l, r) {
+                    return result;
+                }
+                return self.error(format!(
+                    "unknown operator: {:?} {} {:?}",
+                    left.type_of(),
+                    operator,
+                    right.type_of()
+                ));
+            }
+        }
+        // 至少一边是大整数
+        if let (Some(l), Some(r)) = (bigint::to_bigint(&left), bigint::to_bigint(&right)) {
+            return self.eval_big_integer_infix_expression(operator, &l, &r);
+        }
+        if let (Some(l), Some(r)) = (
+            left.downcast_ref::<object::Boolean>(),
+            right.downcast_ref::<object::Boolean>(),
+        ) {
+            match operator {
+                "==" => return self.native_bool(l.value == r.value),
+                "!=" => return self.native_bool(l.value != r.value),
+                _ => {}
+            }
+        }
+        if left.type_of() != right.type_of() {
+            return self.error(format!(
+                "type mismatch: {:?} {} {:?}",
+                left.type_of(),
+                operator,
+                right.type_of()
+            ));
+        }
+        self.error(format!(
+            "unknown operator: {:?} {} {:?}",
+            left.type_of(),
+            operator,
+            right.type_of()
+        ))
+    }
+
+    fn eval_integer_infix_expression(
+        &mut self,
+        operator: &str,
+        left: i64,
+        right: i64,
+    ) -> Rc<dyn Object> {
+        let value = match operator {
+            "+" => left.checked_add(right),
+            "-" => left.checked_sub(right),
+            "*" => left.checked_mul(right),
+            "/" => {
+                if right == 0 {
+                    return self.error(String::from("division by zero"));
+                }
+                left.checked_div(right)
+            }
+            "%" => {
+                if right == 0 {
+                    return self.error(String::from("modulo by zero"));
+                }
+                // i64::MIN % -1 的结果 0 不会溢出
+                Some(left.wrapping_rem(right))
+            }
+            // 负数或过大的指数都报错
+            "**" => match u32::try_from(right) {
+                Ok(exp) => left.checked_pow(exp),
+                Err(_) => return self.error(format!("exponent out of range: {}", right)),
+            },
+            // 位运算和右移按二进制位处理，右移移出的位直接丢弃；左移丢失有效位时换成大整数
+            "&" => Some(left & right),
+            "|" => Some(left | right),
+            "^" => Some(left ^ right),
+            "<<" | ">>" => {
+                if !(0..64).contains(&right) {
+                    return self.error(format!("shift amount out of range: {}", right));
+                }
+                if operator == "<<" {
+                    bigint::checked_shl(left, right)
+                } else {
+                    Some(left >> right)
+                }
+            }
+            "<" => return self.native_bool(left < right),
+            ">" => return self.native_bool(left > right),
+            "<=" => return self.native_bool(left <= right),
+            ">=" => return self.native_bool(left >= right),
+            "==" => return self.native_bool(left == right),
+            "!=" => return self.native_bool(left != right),
+            ".." | "..=" => {
+                return self.alloc(object::Range {
+                    start: left,
+                    end: right,
+                    inclusive: operator == "..=",
+                })
+            }
+            _ => {
+                return self.error(format!(
+                    "unknown operator: {:?} {} {:?}",
+                    ObjectType::INTEGER,
+                    operator,
+                    ObjectType::INTEGER
+                ))
+            }
+        };
+        match value {
+            Some(value) => self.alloc(object::Integer { value }),
+            // 溢出时换成大整数重新计算
+            None => self.eval_big_integer_infix_expression(operator, &left.into(), &right.into()),
+        }
+    }
+
+    // 小数不支持的运算返回 None
+    fn eval_float_infix_expression(
+        &mut self,
+        operator: &str,
+        left: f64,
+        right: f64,
+    ) -> Option<Rc<dyn Object>> {
+        if let Some(b) = float::compare(operator, left, right) {
+            return Some(self.native_bool(b));
+        }
+        Some(match float::infix(operator, left, right)? {
+            Ok(value) => self.alloc(object::Float { value }),
+            Err(message) => self.error(message),
+        })
+    }
+
+    fn eval_big_integer_infix_expression(
+        &mut self,
+        operator: &str,
+        left: &BigInt,
+        right: &BigInt,
+    ) -> Rc<dyn Object> {
+        if let Some(b) = bigint::compare(operator, left, right) {
+            return self.native_bool(b);
+        }
+        match bigint::infix(operator, left, right) {
+            Ok(value) => self.big_integer(value),
+            Err(message) => self.error(message),
+        }
+    }
+
+    fn eval_if_expression(
+        &mut self,
+        ie: &IFExpression,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        let condition = self.eval_expression(ie.condition.as_ref(), env);
+        if is_error(&condition) {
+            return condition;
+        }
+        if is_truthy(&condition) {
+            self.eval_block_statement(&ie.consequence, env)
+        } else {
+            // 没有 else 分支时 alternative 是一个空语句块，结果为 null
+            self.eval_block_statement(&ie.alternative, env)
+        }
+    }
+
+    // 循环本身的值是 null，循环体中的 return 和错误继续向外传递
+    fn eval_while_statement(
+        &mut self,
+        ws: &WhileStatement,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        loop {
+            let condition = self.eval_expression(ws.condition.as_ref(), env);
+            if is_error(&condition) {
+                return condition;
+            }
+            if !is_truthy(&condition) {
+                break;
+            }
+            let result = self.eval_block_statement(&ws.body, env);
+            match result.type_of() {
+                ObjectType::BREAK => break,
+                ObjectType::CONTINUE => continue,
+                _ if interrupts(&result) => return result,
+                _ => {}
+            }
+        }
+        self.null()
+    }
+
+    // 通过迭代协议遍历，循环变量绑定在当前环境中，循环结束后仍然可见
+    fn eval_for_statement(
+        &mut self,
+        fs: &ForStatement,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Rc<dyn Object> {
+        let iterable = self.eval_expression(fs.iterable.as_ref(), env);
+        if is_error(&iterable) {
+            return iterable;
+        }
+        let entries = match iterable.iter() {
+            Some(entries) => entries,
+            None => return self.error(format!("not iterable: {:?}", iterable.type_of())),
+        };
+        for (key, value) in entries {
+            // 空的循环体不会求值任何节点，每一轮单独计一步
+            self.stats.steps += 1;
+            if let Err(limit) = self.meter.tick() {
+                return self.alloc(object::BudgetExceeded { limit });
+            }
+            if let Some(k) = &fs.key {
+                env.borrow_mut().set(k.value.clone(), key);
+            }
+            env.borrow_mut().set(fs.value.value.clone(), value);
+            let result = self.eval_block_statement(&fs.body, env);
+            match result.type_of() {
+                ObjectType::BREAK => break,
+                ObjectType::CONTINUE => continue,
+                _ if interrupts(&result) => return result,
+                _ => {}
+            }
+        }
+        self.null()
+    }
+
+    // site 是调用处的 token，用于出错时的调用栈
+    fn apply_function(
+        &mut self,
+        mut func: Rc<dyn Object>,
+        mut args: Vec<Rc<dyn Object>>,
+        site: &Token,
+    ) -> Rc<dyn Object> {
+        let (mut line, mut column) = (site.line, site.column);
+        loop {
+            let name = function_name(&func);
+            if self.calls.len() >= self.max_depth {
+                let names: Vec<String> = self.calls.iter().map(|f| f.function.clone()).collect();
+                return self.error(recursion_error(&names, &name));
+            }
+            if let Some(p) = self.profiler.as_mut() {
+                p.enter(&name);
+            }
+            self.calls.push(TraceFrame {
+                function: name,
+                line,
+                column,
+            });
+            let result = self.call_function(&func, args);
+            let frame = self.calls.pop().unwrap();
+            if let Some(p) = self.profiler.as_mut() {
+                p.exit(&frame.function);
+            }
+            // 尾调用复用当前帧，调用栈中只留下最后一次调用的位置
+            match result.downcast_ref::<object::TailCall>() {
+                Some(tc) => {
+                    func = tc.func.clone();
+                    args = tc.args.clone();
+                    (line, column) = (tc.line, tc.column);
+                }
+                None => return result,
+            }
+        }
+    }
+
+    fn call_function(&mut self, func: &Rc<dyn Object>, args: Vec<Rc<dyn Object>>) -> Rc<dyn Object> {
+        if let Some(f) = func.downcast_ref::<object::Function>() {
+            if f.params.len() != args.len() {
+                return self.error(format!(
+                    "wrong number of arguments: want={}, got={}",
+                    f.params.len(),
+                    args.len()
+                ));
+            }
+            let mut env = Environment::new_enclosed(f.env.clone());
+            self.stats.allocations += 1;
+            for (param, arg) in f.params.iter().zip(args) {
+                env.set(param.value.clone(), arg);
+            }
+            let result = self.eval_tail_block(&f.body, &Rc::new(RefCell::new(env)));
+            match result.downcast_ref::<object::ReturnValue>() {
+                Some(rv) => rv.value.clone(),
+                None => result,
+            }
+        } else if let Some(b) = func.downcast_ref::<object::Builtin>() {
+            let result = (b.func)(args);
+            // 内置函数返回的错误没有调用栈，在这里补上
+            if let Some(e) = result.downcast_ref::<object::Error>() {
+                return self.error(e.message.clone());
+            }
+            match self.meter.check_size(&result) {
+                Ok(()) => result,
+                Err(limit) => self.alloc(object::BudgetExceeded { limit }),
+            }
+        } else {
+            self.error(format!("not a function: {:?}", func.type_of()))
+        }
+    }
+
+    fn alloc<T: Object>(&mut self, obj: T) -> Rc<dyn Object> {
+        self.stats.allocations += 1;
+        Rc::new(obj)
+    }
+
+    // 大整数运算的结果，能用 64 位表示时是 Integer
+    fn big_integer(&mut self, value: BigInt) -> Rc<dyn Object> {
+        self.stats.allocations += 1;
+        bigint::normalize(value)
+    }
+
+    fn native_bool(&mut self, value: bool) -> Rc<dyn Object> {
+        self.alloc(object::Boolean { value })
+    }
+
+    fn null(&mut self) -> Rc<dyn Object> {
+        self.alloc(object::Null {})
+    }
+
+    fn error(&mut self, message: String) -> Rc<dyn Object> {
+        let trace = self.calls.iter().rev().cloned().collect();
+        self.alloc(object::Error { message, trace })
+    }
+}
+
+// 调用链从最外层的调用开始，连续重复的调用合并成 `f (x999)`，太长时省略中间部分
+pub fn recursion_error(calls: &[String], callee: &str) -> String {
+    let mut runs: Vec<(&str, usize)> = Vec::new();
+    for name in calls.iter().map(|s| s.as_str()).chain([callee]) {
+        match runs.last_mut() {
+            Some((last, n)) if *last == name => *n += 1,
+            _ => runs.push((name, 1)),
+        }
+    }
+    let mut chain: Vec<String> = runs
+        .iter()
+        .map(|(name, n)| match n {
+            1 => name.to_string(),
+            _ => format!("{} (x{})", name, n),
+        })
+        .collect();
+    if chain.len() > 8 {
+        chain.splice(4..chain.len() - 4, [String::from("...")]);
+    }
+    format!(
+        "maximum recursion depth exceeded, call chain: {}",
+        chain.join(" -> ")
+    )
+}
+
+fn function_name(func: &Rc<dyn Object>) -> String {
+    if let Some(f) = func.downcast_ref::<object::Function>() {
+        if let Some(name) = &f.name {
+            return name.clone();
+        }
+    } else if let Some(b) = func.downcast_ref::<object::Builtin>() {
+        return b.name.clone();
+    }
+    String::from("<anonymous>")
+}
+
+// 是否需要中止当前语句块的执行：return、break、continue 或错误
+fn interrupts(obj: &Rc<dyn Object>) -> bool {
+    matches!(
+        obj.type_of(),
+        ObjectType::RETURN_VALUE | ObjectType::BREAK | ObjectType::CONTINUE
+    ) || is_error(obj)
+}
+
+pub fn is_truthy(obj: &Rc<dyn Object>) -> bool {
+    match obj.downcast_ref::<object::Boolean>() {
+        Some(b) => b.value,
+        None => obj.type_of() != ObjectType::NULL,
+    }
+}
+
+pub fn null() -> Rc<dyn Object> {
+    Rc::new(object::Null {})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::budget::Limit;
+    use crate::lexer::lexer::Lexer;
+    use crate::parser::parser::Parser;
+    use std::time::{Duration, Instant};
+
+    fn test_eval(input: &str) -> Rc<dyn Object> {
+        let l = Lexer::new(input.to_string());
+        let mut p = Parser::new(l);
+        let prgm = p.parse_program().unwrap();
+        let env = Rc::new(RefCell::new(Environment::new()));
+        eval(&prgm, &env)
+    }
+
+    #[test]
+    fn test_eval_integer_expression() {
+        let tests = vec![
+            ("5", 5),
+            ("-10", -10),
+            ("5 + 5 + 5 + 5 - 10", 10),
+            ("2 * (5 + 10)", 30),
+            ("50 / 2 * 2 + 10", 60),
+            ("(5 + 10 * 2 + 15 / 3) * 2 + -10", 50),
+            ("17 % 5 + -7 % 3", 1),
+            ("2 ** 3 ** 2", 512),
+            ("-2 ** 2", -4),
+            ("(-2) ** 3 * 2", -16),
+            ("6 & 3 | 8 ^ 1", 11),
+            ("~5", -6),
+            ("1 << 62 >> 60", 4),
+            ("-1 << 63", i64::MIN),
+            ("(-9223372036854775807 - 1) % -1", 0),
+            ("-9223372036854775807 - 1 + 9223372036854775807", -1),
+            ("-16 >> 2", -4),
+        ];
+        for (input, expect) in tests {
+            let obj = test_eval(input);
+            match obj.downcast_ref::<object::Integer>() {
+                Some(i) => assert_eq!(i.value, expect, "{}", input),
+                None => panic!("{} not Integer, got {}", input, obj.inspect()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_big_integers() {
+        let tests = vec![
+            ("9223372036854775807 + 1", "9223372036854775808"),
+            ("-9223372036854775807 - 2", "-9223372036854775809"),
+            (
+                "let m = -9223372036854775807 - 1; -m",
+                "9223372036854775808",
+            ),
+            (
+                "let m = -9223372036854775807 - 1; m / -1",
+                "9223372036854775808",
+            ),
+            ("3037000500 * 3037000500", "9223372037000250000"),
+            ("1 << 63", "9223372036854775808"),
+            ("(1 << 62) << 2", "18446744073709551616"),
+            ("-3 << 62", "-13835058055282163712"),
+            ("2 ** 100", "1267650600228229401496703205376"),
+            ("123456789012345678901234567890n % 1000", "890"),
+            ("18446744073709551616n / 2 - 1", "9223372036854775807"),
+            ("~18446744073709551616n", "-18446744073709551617"),
+            ("18446744073709551616n >> 60 | 1", "17"),
+            ("5n", "5"),
+            (
+                "2 ** 64 > 2 ** 63 && 2 ** 64 != 1 && 2 ** 64 == 18446744073709551616n",
+                "true",
+            ),
+            (
+                "let fact = fn(n) { let r = 1; for (i in 2..=n) { r *= i; } r }; fact(25)",
+                "15511210043330985984000000",
+            ),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+        // 能用 64 位表示的结果换回 Integer
+        let obj = test_eval("2 ** 64 - 2 ** 64 + 1");
+        assert_eq!(obj.downcast_ref::<object::Integer>().unwrap().value, 1);
+    }
+
+    #[test]
+    fn test_eval_strings() {
+        let tests = vec![
+            (r#""hello""#, "hello"),
+            (
+                r#"let name = "monkey"; let n = 3; "hello ${name}, you have ${n * 2} items""#,
+                "hello monkey, you have 6 items",
+            ),
+            (r#""${"a${"b${1 + 1}"}c"}""#, "ab2c"),
+            (r#""${if (true) { "{" } else { "}" }}""#, "{"),
+            (
+                r#""${fn(x) { x }(1.5)} ${true} ${if (false) { 1 }}""#,
+                "1.5 true null",
+            ),
+            (r#""\${x} costs $5""#, "${x} costs $5"),
+            (
+                r#"let s = ""; for (c in "abc") { s = "${c}${s}"; } s"#,
+                "cba",
+            ),
+            (r#""a${1 / 0}b""#, "ERROR: division by zero"),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_eval_floats() {
+        let tests = vec![
+            ("3.14", "3.14"),
+            ("0.1 + 0.2", "0.30000000000000004"),
+            ("1 + .5", "1.5"),
+            ("7 / 2", "3"),
+            ("7 / 2.0", "3.5"),
+            ("-2.5 * 2", "-5.0"),
+            ("7.5 % 2", "1.5"),
+            ("2 ** 0.5 * 2 ** 0.5 > 1.99", "true"),
+            ("1 == 1.0", "true"),
+            ("1.5 < 2 && 2 ** 64 > 1e18", "true"),
+            ("1e-9", "1e-9"),
+            ("int(-2.7) + int(2.7)", "0"),
+            ("float(1) / 4", "0.25"),
+            ("let x = 1; x += 0.5; x", "1.5"),
+            ("1.0 / 0", "ERROR: division by zero"),
+            ("1.5 & 1", "ERROR: unknown operator: FLOAT & INTEGER"),
+            ("~1.5", "ERROR: unknown operator: ~FLOAT"),
+            ("1.5 + true", "ERROR: type mismatch: FLOAT + BOOLEAN"),
+            (
+                "int(true)",
+                "ERROR: argument to `int` must be a number or STRING, got BOOLEAN",
+            ),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_eval_boolean_expression() {
+        let tests = vec![
+            ("true", true),
+            ("!true", false),
+            ("!!5", true),
+            ("1 < 2", true),
+            ("1 == 2", false),
+            ("(1 < 2) == true", true),
+            ("true != false", true),
+            ("2 <= 2", true),
+            ("1 >= 2", false),
+            ("true && 0", true),
+            ("1 < 2 && 2 < 1", false),
+            ("false || 1 >= 1", true),
+            ("false || false", false),
+        ];
+        for (input, expect) in tests {
+            let obj = test_eval(input);
+            match obj.downcast_ref::<object::Boolean>() {
+                Some(b) => assert_eq!(b.value, expect, "{}", input),
+                None => panic!("{} not Boolean, got {}", input, obj.inspect()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_if_and_return() {
+        let tests = vec![
+            ("if (true) { 10 }", "10"),
+            ("if (false) { 10 }", "null"),
+            ("if (1 > 2) { 10 } else { 20 }", "20"),
+            ("if (10 > 1) { if (10 > 1) { return 10; } return 1; }", "10"),
+            ("9; return 2 * 5; 9;", "10"),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_eval_while() {
+        let tests = vec![
+            ("while (false) { 10 }", "null"),
+            ("while (true) { break; 10 } 5", "5"),
+            ("let f = fn(n) { while (n > 0) { return n * 2; } 0 }; f(3) + f(0)", "6"),
+            (
+                "let f = fn(n) { while (true) { if (n > 2) { break; } return n; } 10 }; f(1) + f(5)",
+                "11",
+            ),
+            ("while (true) { let x = if (true) { break; }; x }", "null"),
+            ("while (1 + true) { }", "ERROR: type mismatch: INTEGER + BOOLEAN"),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+
+        // continue 跳过循环体剩下的部分，这里只能靠步数限制结束
+        let prgm = Parser::new(Lexer::new(String::from(
+            "while (true) { continue; return 1; }",
+        )))
+        .parse_program()
+        .unwrap();
+        let mut evaluator = Evaluator::new();
+        evaluator.set_budget(Budget {
+            max_steps: Some(1000),
+            ..Budget::default()
+        });
+        let obj = evaluator.eval(&prgm, &Rc::new(RefCell::new(Environment::new())));
+        assert_eq!(
+            obj.inspect(),
+            "ERROR: budget exceeded: more than 1000 steps executed"
+        );
+    }
+
+    #[test]
+    fn test_eval_for() {
+        let tests = vec![
+            ("2..5", "2..5"),
+            ("for (x in 1..=3) { } x", "3"),
+            ("for (i, x in 10..20) { if (x == 15) { break; } } i", "5"),
+            (
+                "let find = fn(n) { for (x in 0..100) { if (x * x > n) { return x; } } -1 }; find(50)",
+                "8",
+            ),
+            ("for (x in 1..3) { continue; 10 } x", "2"),
+            ("let x = 7; for (x in 5..1) { } x", "7"),
+            ("for (x in 5) { }", "ERROR: not iterable: INTEGER"),
+            ("1..true", "ERROR: type mismatch: INTEGER .. BOOLEAN"),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+
+        // 空的循环体也按轮数计入步数
+        let prgm = Parser::new(Lexer::new(String::from("for (x in 0..1000000000) { }")))
+            .parse_program()
+            .unwrap();
+        let mut evaluator = Evaluator::new();
+        evaluator.set_budget(Budget {
+            max_steps: Some(1000),
+            ..Budget::default()
+        });
+        let obj = evaluator.eval(&prgm, &Rc::new(RefCell::new(Environment::new())));
+        assert_eq!(
+            obj.inspect(),
+            "ERROR: budget exceeded: more than 1000 steps executed"
+        );
+    }
+
+    #[test]
+    fn test_eval_assign() {
+        let tests = vec![
+            ("let x = 1; x = x + 1; x", "2"),
+            ("let x = 10; x += 5; x -= 3; x *= 2; x /= 4; x", "6"),
+            ("let x = 1; let y = x = 5; x + y", "10"),
+            ("let sum = 0; for (i in 1..=10) { sum += i; } sum", "55"),
+            (
+                "let i = 0; let odd = 0; while (i < 10) { i += 1; if (i / 2 * 2 == i) { continue; } odd += 1; } odd",
+                "5",
+            ),
+            ("let n = 1; let f = fn() { n *= 3 }; f(); f(); n", "9"),
+            ("const x = 1; let x = 2; x = 3; x", "3"),
+            (
+                "let counter = fn() { let n = 0; fn() { n += 1 } }; let c = counter(); c(); c(); c()",
+                "3",
+            ),
+            (
+                "let g = fn() { let a = 1; let f = fn() { a }; a = 2; f() }; g()",
+                "2",
+            ),
+            ("let x = 1; x += true", "ERROR: type mismatch: INTEGER + BOOLEAN"),
+            // 短路时右边不会求值
+            ("let n = 0; false && (n = 1); true || (n = 2); n", "0"),
+            ("let n = 0; true && (n = 1); false || (n += 2); n", "3"),
+            ("false && y", "false"),
+            ("true && y", "ERROR: identifier not found: y"),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+
+        let tests = vec![
+            ("y = 1", "identifier not found: y"),
+            ("puts = 1", "cannot assign to builtin: puts"),
+            ("const x = 1; x += 1", "cannot assign to constant: x"),
+            (
+                "let f = fn() { const c = 0; fn() { c = 1 } }; f()()",
+                "cannot assign to constant: c",
+            ),
+        ];
+        for (input, expect) in tests {
+            match test_eval(input).downcast_ref::<object::Error>() {
+                Some(e) => assert_eq!(e.message, expect, "{}", input),
+                None => panic!("{}: expected error", input),
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_functions() {
+        let tests = vec![
+            ("let identity = fn(x) { x; }; identity(5);", "5"),
+            ("let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));", "20"),
+            ("fn(x) { x; }(5)", "5"),
+            (
+                "let newAdder = fn(x) { fn(y) { x + y } }; let addTwo = newAdder(2); addTwo(2);",
+                "4",
+            ),
+            (
+                "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(10)",
+                "55",
+            ),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_eval_tail_calls() {
+        let tests = vec![
+            (
+                "let countdown = fn(n) { if (n == 0) { 0 } else { countdown(n - 1) } }; countdown(1000000)",
+                "0",
+            ),
+            (
+                "let sum = fn(n, acc) { if (n == 0) { return acc; } return sum(n - 1, acc + n); }; sum(100000, 0)",
+                "5000050000",
+            ),
+            (
+                "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } }; let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } }; even(100001)",
+                "false",
+            ),
+            ("let f = fn(x) { x }; let g = fn(x) { f(x) + 1 }; g(1)", "2"),
+            ("let f = fn(x) { x }; f(1, 2); 3", "ERROR: wrong number of arguments: want=1, got=2"),
+        ];
+        for (input, expect) in tests {
+            assert_eq!(test_eval(input).inspect(), expect, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_eval_recursion_limit() {
+        let tests = vec![
+            (
+                "let f = fn(n) { 1 + f(n + 1) }; f(0)",
+                "maximum recursion depth exceeded, call chain: f (x51)",
+            ),
+            (
+                "let even = fn(n) { if (n == 0) { true } else { !odd(n - 1) } }; let odd = fn(n) { if (n == 0) { false } else { !even(n - 1) } }; fn() { even(100) }()",
+                "maximum recursion depth exceeded, call chain: even -> odd -> even -> odd -> ... -> odd -> even -> odd -> even",
+            ),
+            // 尾调用不计入调用深度
+            (
+                "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } }; f(100)",
+                "0",
+            ),
+        ];
+        for (input, expect) in tests {
+            let prgm = Parser::new(Lexer::new(input.to_string()))
+                .parse_program()
+                .unwrap();
+            let mut evaluator = Evaluator::new();
+            evaluator.max_depth = 50;
+            let obj = evaluator.eval(&prgm, &Rc::new(RefCell::new(Environment::new())));
+            match obj.downcast_ref::<object::Error>() {
+                Some(e) => assert_eq!(e.message, expect, "{}", input),
+                None => assert_eq!(obj.inspect(), expect, "{}", input),
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_budget() {
+        let prgm = Parser::new(Lexer::new(String::from("let f = fn(n) { f(n + 1) }; f(0)")))
+            .parse_program()
+            .unwrap();
+        let budgets = vec![
+            Budget {
+                max_steps: Some(1000),
+                ..Budget::default()
+            },
+            Budget {
+                deadline: Some(Instant::now() + Duration::from_millis(20)),
+                ..Budget::default()
+            },
+        ];
+        let limits = vec![Limit::Steps(1000), Limit::Deadline];
+        for (budget, limit) in budgets.into_iter().zip(limits) {
+            let mut evaluator = Evaluator::new();
+            evaluator.set_budget(budget);
+            let obj = evaluator.eval(&prgm, &Rc::new(RefCell::new(Environment::new())));
+            match obj.downcast_ref::<object::BudgetExceeded>() {
+                Some(e) => assert_eq!(e.limit, limit),
+                None => panic!("not BudgetExceeded, got {}", obj.inspect()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_stack_trace() {
+        let input = "let g = fn(x) { x / 0 };\nlet f = fn(x) {\n  let y = g(x);\n  y\n};\nf(1)";
+        let obj = test_eval(input);
+        let e = obj.downcast_ref::<object::Error>().unwrap();
+        let frame = |function: &str, line, column| TraceFrame {
+            function: function.to_string(),
+            line,
+            column,
+        };
+        assert_eq!(e.trace, vec![frame("g", 3, 12), frame("f", 6, 2)]);
+        let lines: Vec<&str> = input.lines().collect();
+        assert_eq!(
+            e.format_trace(&lines),
+            "  at g (line 3, column 12)\n    let y = g(x);\n  at f (line 6, column 2)\n    f(1)\n"
+        );
+
+        // 调用栈太深时只显示两端
+        let prgm = Parser::new(Lexer::new(String::from(
+            "let f = fn(n) { 1 + f(n + 1) }; f(0)",
+        )))
+        .parse_program()
+        .unwrap();
+        let mut evaluator = Evaluator::new();
+        evaluator.max_depth = 50;
+        let obj = evaluator.eval(&prgm, &Rc::new(RefCell::new(Environment::new())));
+        let e = obj.downcast_ref::<object::Error>().unwrap();
+        assert_eq!(e.trace.len(), 50);
+        let trace = e.format_trace(&[]);
+        assert_eq!(trace.lines().count(), 21);
+        assert!(trace.contains("\n  ... 30 more frames\n"), "{}", trace);
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let tests = vec![
+            ("5 + true;", "type mismatch: INTEGER + BOOLEAN"),
+            ("-true", "unknown operator: -BOOLEAN"),
+            ("true + false; 5", "unknown operator: BOOLEAN + BOOLEAN"),
+            ("if (10 > 1) { true + false; }", "unknown operator: BOOLEAN + BOOLEAN"),
+            ("foobar", "identifier not found: foobar"),
+            ("1 / 0", "division by zero"),
+            ("1 % 0", "modulo by zero"),
+            ("2n ** 100000000", "integer too large"),
+            ("1 .. 9223372036854775808n", "range bounds must fit in 64 bits"),
+            ("9223372036854775808n + true", "type mismatch: INTEGER + BOOLEAN"),
+            ("2 ** -1", "exponent out of range: -1"),
+            ("1 << 64", "shift amount out of range: 64"),
+            ("1 >> -1", "shift amount out of range: -1"),
+            ("~true", "unknown operator: ~BOOLEAN"),
+            ("true & false", "unknown operator: BOOLEAN & BOOLEAN"),
+            ("let f = fn(x) { x }; f(1, 2)", "wrong number of arguments: want=1, got=2"),
+        ];
+        for (input, expect) in tests {
+            let obj = test_eval(input);
+            match obj.downcast_ref::<object::Error>() {
+                Some(e) => assert_eq!(e.message, expect, "{}", input),
+                None => panic!("{} not Error, got {}", input, obj.inspect()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_stats_and_profile() {
+        let l = Lexer::new(String::from(
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(10)",
+        ));
+        let mut p = Parser::new(l);
+        let prgm = p.parse_program().unwrap();
+        let env = Rc::new(RefCell::new(Environment::new()));
+        let mut evaluator = Evaluator::new();
+        evaluator.profiler = Some(Profiler::new());
+        assert_eq!(evaluator.eval(&prgm, &env).inspect(), "55");
+        assert!(evaluator.stats.steps > 177);
+        assert!(evaluator.stats.allocations > 177);
+        let report = evaluator.profiler.unwrap().report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].name, "fib");
+        assert_eq!(report[0].calls, 177);
+    }
+}
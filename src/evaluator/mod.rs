@@ -1 +1,5 @@
-pub mod evaluator;
\ No newline at end of file
+pub mod budget;
+pub mod builtins;
+pub mod evaluator;
+pub mod profile;
+pub mod stack;
\ No newline at end of file
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// 求值过程中的计数，供 REPL 的 :time 命令使用
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    // 求值的语句和表达式节点数
+    pub steps: u64,
+    // 求值过程中创建的对象和环境数
+    pub allocations: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    pub cumulative: Duration,
+}
+
+// 统计每个函数的调用次数和累计耗时，递归调用只在最外层那次计时，避免重复累加
+#[derive(Default)]
+pub struct Profiler {
+    entries: HashMap<String, Entry>,
+}
+
+#[derive(Default)]
+struct Entry {
+    calls: u64,
+    cumulative: Duration,
+    active: usize,
+    started: Option<Instant>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn enter(&mut self, name: &str) {
+        let entry = self.entries.entry(name.to_string()).or_default();
+        entry.calls += 1;
+        if entry.active == 0 {
+            entry.started = Some(Instant::now());
+        }
+        entry.active += 1;
+    }
+
+    pub fn exit(&mut self, name: &str) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            entry.active -= 1;
+            if entry.active == 0 {
+                if let Some(started) = entry.started.take() {
+                    entry.cumulative += started.elapsed();
+                }
+            }
+        }
+    }
+
+    // 按累计耗时从高到低排列
+    pub fn report(&self) -> Vec<FunctionProfile> {
+        let mut ans: Vec<FunctionProfile> = self
+            .entries
+            .iter()
+            .map(|(name, e)| FunctionProfile {
+                name: name.clone(),
+                calls: e.calls,
+                cumulative: e.cumulative,
+            })
+            .collect();
+        ans.sort_by(|a, b| b.cumulative.cmp(&a.cumulative).then(a.name.cmp(&b.name)));
+        ans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiler_recursion() {
+        let mut p = Profiler::new();
+        p.enter("fib");
+        p.enter("fib");
+        p.exit("fib");
+        p.enter("puts");
+        p.exit("puts");
+        p.exit("fib");
+        let report = p.report();
+        assert_eq!(report.len(), 2);
+        let fib = report.iter().find(|f| f.name == "fib").unwrap();
+        let puts = report.iter().find(|f| f.name == "puts").unwrap();
+        assert_eq!(fib.calls, 2);
+        assert_eq!(puts.calls, 1);
+        assert!(fib.cumulative >= puts.cumulative);
+    }
+}
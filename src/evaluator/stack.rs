@@ -0,0 +1,19 @@
+// 递归的解析、编译和求值不依赖调用方线程的栈大小：
+// 解析时剩余的栈空间不足就报错，之后的各个遍历在栈空间不足时切换到新分配的栈段继续递归
+
+// 剩余的栈空间少于这个值时切换到新的栈段，要足够输出或释放嵌套最深的语法树
+const RED_ZONE: usize = 1 << 20;
+// 每次新分配的栈段大小
+const SEGMENT_SIZE: usize = 16 << 20;
+// 解析时至少保留的栈空间
+const PARSER_RESERVE: usize = 256 << 10;
+
+// 在栈空间不足时先分配新的栈段再执行 f
+pub fn grow<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(RED_ZONE, SEGMENT_SIZE, f)
+}
+
+// 解析器继续递归前检查，无法得知剩余的栈空间时认为足够
+pub fn exhausted() -> bool {
+    stacker::remaining_stack().is_some_and(|n| n < PARSER_RESERVE)
+}
@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use crate::token::token;
 
 pub struct Lexer {
@@ -6,6 +8,11 @@ pub struct Lexer {
     read_position: usize,
     //始终指向下一个字符
     ch: u8,
+    // 当前字符所在的行号，以及该行起始的字节位置，用于计算 token 的行列号
+    line: usize,
+    line_start: usize,
+    // 每个未结束的字符串插值 ${ 里还没有闭合的 { 的个数
+    templates: Vec<usize>,
 }
 
 impl Lexer {
@@ -15,13 +22,27 @@ impl Lexer {
             position: 0,
             read_position: 0,
             ch: 0,
+            line: 1,
+            line_start: 0,
+            templates: Vec::new(),
         };
         l.read_char();
         l
     }
 
+    // 从第 line 行开始计数，用于 REPL 中把多次输入当作一份连续的源码
+    pub fn new_at_line(input: String, line: usize) -> Lexer {
+        let mut l = Lexer::new(input);
+        l.line = line;
+        l
+    }
+
     //读取下一个字符
     fn read_char(&mut self) {
+        if self.ch == b'\n' {
+            self.line += 1;
+            self.line_start = self.read_position;
+        }
         if self.read_position >= self.input.len() {
             self.ch = 0;
         } else {
@@ -41,6 +62,15 @@ impl Lexer {
 
     pub fn next_token(&mut self) -> token::Token {
         self.skip_whitespace();
+        let line = self.line;
+        let column = self.position - self.line_start + 1;
+        let mut tk = self.read_token();
+        tk.line = line;
+        tk.column = column;
+        tk
+    }
+
+    fn read_token(&mut self) -> token::Token {
         let tk = match self.ch {
             b'=' => {
                 if self.peek_char() == b'=' {
@@ -58,16 +88,66 @@ impl Lexer {
                     token::Token::new(token::TokenType::BANG, u8to_String(self.ch))
                 }
             }
+            // .5 这样省略整数部分的小数
+            b'.' if is_digit(self.peek_char()) => return self.read_number(),
+            // .. 和 ..= 是区间，单独的 . 目前没有意义
+            b'.' if self.peek_char() == b'.' => {
+                self.read_char();
+                if self.peek_char() == b'=' {
+                    self.read_char();
+                    token::Token::new(token::TokenType::DOT_DOT_EQ, String::from("..="))
+                } else {
+                    token::Token::new(token::TokenType::DOT_DOT, String::from(".."))
+                }
+            }
             b';' => token::Token::new(token::TokenType::SEMICOLON, u8to_String(self.ch)),
             b'(' => token::Token::new(token::TokenType::LPAREN, u8to_String(self.ch)),
             b')' => token::Token::new(token::TokenType::RPAREN, u8to_String(self.ch)),
             b',' => token::Token::new(token::TokenType::COMMA, u8to_String(self.ch)),
+            b'+' if self.peek_char() == b'=' => self.read_compound(token::TokenType::PLUS_ASSIGN),
+            b'-' if self.peek_char() == b'=' => self.read_compound(token::TokenType::MINUS_ASSIGN),
+            b'*' if self.peek_char() == b'=' => {
+                self.read_compound(token::TokenType::ASTERISK_ASSIGN)
+            }
+            b'/' if self.peek_char() == b'=' => self.read_compound(token::TokenType::SLASH_ASSIGN),
+            b'*' if self.peek_char() == b'*' => self.read_pair(token::TokenType::POWER),
             b'+' => token::Token::new(token::TokenType::PLUS, u8to_String(self.ch)),
             b'-' => token::Token::new(token::TokenType::MINUS, u8to_String(self.ch)),
             b'*' => token::Token::new(token::TokenType::ASTERISK, u8to_String(self.ch)),
             b'/' => token::Token::new(token::TokenType::SLASH, u8to_String(self.ch)),
-            b'{' => token::Token::new(token::TokenType::LBRACE, u8to_String(self.ch)),
-            b'}' => token::Token::new(token::TokenType::RBRACE, u8to_String(self.ch)),
+            b'%' => token::Token::new(token::TokenType::PERCENT, u8to_String(self.ch)),
+            b'"' => {
+                self.read_char();
+                return self.read_string(true);
+            }
+            b'{' => {
+                if let Some(depth) = self.templates.last_mut() {
+                    *depth += 1;
+                }
+                token::Token::new(token::TokenType::LBRACE, u8to_String(self.ch))
+            }
+            // 闭合插值的 } 后面接着字符串的下一段
+            b'}' if self.templates.last() == Some(&0) => {
+                self.templates.pop();
+                self.read_char();
+                return self.read_string(false);
+            }
+            b'}' => {
+                if let Some(depth) = self.templates.last_mut() {
+                    *depth -= 1;
+                }
+                token::Token::new(token::TokenType::RBRACE, u8to_String(self.ch))
+            }
+            b'<' if self.peek_char() == b'=' => self.read_compound(token::TokenType::LT_EQ),
+            b'>' if self.peek_char() == b'=' => self.read_compound(token::TokenType::GT_EQ),
+            b'&' if self.peek_char() == b'&' => self.read_pair(token::TokenType::AND),
+            b'|' if self.peek_char() == b'|' => self.read_pair(token::TokenType::OR),
+            b'<' if self.peek_char() == b'<' => self.read_pair(token::TokenType::SHL),
+            b'>' if self.peek_char() == b'>' => self.read_pair(token::TokenType::SHR),
+            b'&' => token::Token::new(token::TokenType::AMPERSAND, u8to_String(self.ch)),
+            b'|' => token::Token::new(token::TokenType::PIPE, u8to_String(self.ch)),
+            b'^' => token::Token::new(token::TokenType::CARET, u8to_String(self.ch)),
+            b'~' => token::Token::new(token::TokenType::TILDE, u8to_String(self.ch)),
             b'<' => token::Token::new(token::TokenType::LT, u8to_String(self.ch)),
             b'>' => token::Token::new(token::TokenType::GT, u8to_String(self.ch)),
             b'}' => token::Token::new(token::TokenType::RBRACE, u8to_String(self.ch)),
@@ -78,7 +158,7 @@ impl Lexer {
                     let ty = token::TokenType::lookup_ident(lit.as_str());
                     return token::Token::new(ty, lit);
                 } else if is_digit(self.ch) {
-                    return token::Token::new(token::TokenType::INT, self.read_number());
+                    return self.read_number();
                 } else {
                     token::Token::new(token::TokenType::ILLEGAL, u8to_String(self.ch))
                 }
@@ -88,6 +168,81 @@ impl Lexer {
         tk
     }
 
+    // 返回下一个 token 及其在输入中的字节区间，供高亮等需要回溯源码位置的场景使用
+    pub fn next_token_with_span(&mut self) -> (token::Token, Range<usize>) {
+        self.skip_whitespace();
+        let start = self.position.min(self.input.len());
+        let tk = self.next_token();
+        let end = self.position.min(self.input.len());
+        (tk, start..end)
+    }
+
+    // 当前字符加上后面的 = 组成的运算符，如 += 和 <=
+    fn read_compound(&mut self, typ: token::TokenType) -> token::Token {
+        let op = self.ch;
+        self.read_char();
+        token::Token::new(typ, format!("{}=", op as char))
+    }
+
+    // 同一个字符重复两次的运算符，如 && 和 <<
+    fn read_pair(&mut self, typ: token::TokenType) -> token::Token {
+        let op = self.ch;
+        self.read_char();
+        token::Token::new(typ, format!("{0}{0}", op as char))
+    }
+
+    // 读取字符串的一段，直到结尾的 " 或插值开头的 ${，literal 是转义后的内容。
+    // 没有结尾的 " 时返回 ILLEGAL，literal 是从这一段开头的 " 或 } 到输入结尾的源码
+    fn read_string(&mut self, head: bool) -> token::Token {
+        let start = self.position;
+        let mut value = Vec::new();
+        loop {
+            match self.ch {
+                0 => {
+                    let sl = &self.input[start - 1..];
+                    return token::Token::new(token::TokenType::ILLEGAL, String::from(sl));
+                }
+                b'"' => {
+                    self.read_char();
+                    let typ = if head {
+                        token::TokenType::STRING
+                    } else {
+                        token::TokenType::TEMPLATE_TAIL
+                    };
+                    return token::Token::new(typ, String::from_utf8(value).unwrap());
+                }
+                b'$' if self.peek_char() == b'{' => {
+                    self.read_char();
+                    self.read_char();
+                    self.templates.push(0);
+                    let typ = if head {
+                        token::TokenType::TEMPLATE_HEAD
+                    } else {
+                        token::TokenType::TEMPLATE_MIDDLE
+                    };
+                    return token::Token::new(typ, String::from_utf8(value).unwrap());
+                }
+                b'\\' => {
+                    self.read_char();
+                    match self.ch {
+                        b'n' => value.push(b'\n'),
+                        b't' => value.push(b'\t'),
+                        b'r' => value.push(b'\r'),
+                        b'"' | b'\\' | b'$' => value.push(self.ch),
+                        // 不认识的转义原样保留
+                        0 => continue,
+                        c => value.extend([b'\\', c]),
+                    }
+                    self.read_char();
+                }
+                c => {
+                    value.push(c);
+                    self.read_char();
+                }
+            }
+        }
+    }
+
     fn read_identifier(&mut self) -> String {
         let pos = self.position;
         while is_letter(self.ch) {
@@ -97,13 +252,72 @@ impl Lexer {
         String::from(sl)
     }
 
-    fn read_number(&mut self) -> String {
+    // 整数或小数：12、12n、1_000、0x1F、0o17、0b1010、3.14、.5、1e-9、2.5E+3
+    fn read_number(&mut self) -> token::Token {
         let pos = self.position;
-        while is_digit(self.ch) {
+        let mut typ = token::TokenType::INT;
+        // 带进制前缀时读入后面所有的字母、数字、下划线和小数点（后面不是 . 时），
+        // 由解析器检查每一位是否合法，以便在出错的位置报告
+        if self.ch == b'0' && matches!(self.peek_char(), b'x' | b'X' | b'o' | b'O' | b'b' | b'B') {
+            self.read_char();
+            self.read_char();
+            loop {
+                let next = self.peek_char();
+                let fraction = self.ch == b'.' && (is_letter(next) || is_digit(next));
+                if !(is_letter(self.ch) || is_digit(self.ch) || fraction) {
+                    break;
+                }
+                self.read_char();
+            }
+            let sl = &self.input[pos..self.position];
+            return token::Token::new(typ, String::from(sl));
+        }
+        self.read_digits();
+        // 小数点后面必须有数字，1..5 仍然是区间
+        if self.ch == b'.' && is_digit(self.peek_char()) {
+            typ = token::TokenType::FLOAT;
+            self.read_char();
+            self.read_digits();
+        }
+        if self.ch == b'e' || self.ch == b'E' {
+            let next = self.peek_char();
+            let sign = next == b'+' || next == b'-';
+            let digit_at = if sign {
+                self.read_position + 1
+            } else {
+                self.read_position
+            };
+            if self
+                .input
+                .as_bytes()
+                .get(digit_at)
+                .is_some_and(|c| is_digit(*c))
+            {
+                typ = token::TokenType::FLOAT;
+                self.read_char();
+                if sign {
+                    self.read_char();
+                }
+                self.read_digits();
+            }
+        }
+        // 后缀 n 表示大整数字面量，如 12n
+        if typ == token::TokenType::INT
+            && self.ch == b'n'
+            && !is_letter(self.peek_char())
+            && !is_digit(self.peek_char())
+        {
             self.read_char();
         }
         let sl = &self.input[pos..self.position];
-        String::from(sl)
+        token::Token::new(typ, String::from(sl))
+    }
+
+    // 数字中间可以用下划线分隔，如 1_000_000
+    fn read_digits(&mut self) {
+        while is_digit(self.ch) || self.ch == b'_' {
+            self.read_char();
+        }
     }
 
     fn skip_whitespace(&mut self) {
@@ -218,10 +432,228 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_range_tokens() {
+        let tests = vec![
+            (token::TokenType::FOR, "for"),
+            (token::TokenType::IN, "in"),
+            (token::TokenType::INT, "1"),
+            (token::TokenType::DOT_DOT, ".."),
+            (token::TokenType::IDENT, "n"),
+            (token::TokenType::DOT_DOT_EQ, "..="),
+            (token::TokenType::ILLEGAL, "."),
+            (token::TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(String::from("for in 1..n..= ."));
+        for (typ, literal) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(tok.literal, literal);
+        }
+    }
+
+    #[test]
+    fn test_assign_tokens() {
+        let tests = vec![
+            (token::TokenType::CONST, "const"),
+            (token::TokenType::ASSIGN, "="),
+            (token::TokenType::PLUS_ASSIGN, "+="),
+            (token::TokenType::MINUS_ASSIGN, "-="),
+            (token::TokenType::ASTERISK_ASSIGN, "*="),
+            (token::TokenType::SLASH_ASSIGN, "/="),
+            (token::TokenType::EQ, "=="),
+            (token::TokenType::MINUS, "-"),
+            (token::TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(String::from("const = += -= *= /= == -"));
+        for (typ, literal) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(tok.literal, literal);
+        }
+    }
+
+    #[test]
+    fn test_logical_tokens() {
+        let tests = vec![
+            (token::TokenType::AND, "&&"),
+            (token::TokenType::OR, "||"),
+            (token::TokenType::LT_EQ, "<="),
+            (token::TokenType::GT_EQ, ">="),
+            (token::TokenType::LT, "<"),
+            (token::TokenType::ILLEGAL, "@"),
+            (token::TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(String::from("&& || <= >= < @"));
+        for (typ, literal) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(tok.literal, literal);
+        }
+    }
+
+    #[test]
+    fn test_bitwise_tokens() {
+        let tests = vec![
+            (token::TokenType::PERCENT, "%"),
+            (token::TokenType::POWER, "**"),
+            (token::TokenType::ASTERISK, "*"),
+            (token::TokenType::AMPERSAND, "&"),
+            (token::TokenType::AND, "&&"),
+            (token::TokenType::PIPE, "|"),
+            (token::TokenType::CARET, "^"),
+            (token::TokenType::TILDE, "~"),
+            (token::TokenType::SHL, "<<"),
+            (token::TokenType::SHR, ">>"),
+            (token::TokenType::GT_EQ, ">="),
+            (token::TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(String::from("% ** * & && | ^ ~ << >> >="));
+        for (typ, literal) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(tok.literal, literal);
+        }
+    }
+
+    #[test]
+    fn test_big_integer_tokens() {
+        let tests = vec![
+            (token::TokenType::INT, "12n"),
+            (token::TokenType::INT, "5"),
+            (token::TokenType::IDENT, "name"),
+            (token::TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(String::from("12n 5name"));
+        for (typ, literal) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(tok.literal, literal);
+        }
+    }
+
+    #[test]
+    fn test_float_tokens() {
+        let tests = vec![
+            (token::TokenType::FLOAT, "3.14"),
+            (token::TokenType::FLOAT, ".5"),
+            (token::TokenType::FLOAT, "1e-9"),
+            (token::TokenType::FLOAT, "2.5E+3"),
+            (token::TokenType::INT, "1"),
+            (token::TokenType::DOT_DOT, ".."),
+            (token::TokenType::INT, "5"),
+            (token::TokenType::INT, "2"),
+            (token::TokenType::IDENT, "em"),
+            (token::TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(String::from("3.14 .5 1e-9 2.5E+3 1..5 2em"));
+        for (typ, literal) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(tok.literal, literal);
+        }
+    }
+
+    #[test]
+    fn test_prefixed_number_tokens() {
+        let tests = vec![
+            (token::TokenType::INT, "0x1F"),
+            (token::TokenType::INT, "0o17"),
+            (token::TokenType::INT, "0b1010"),
+            (token::TokenType::INT, "0b102"),
+            (token::TokenType::INT, "0xffn"),
+            (token::TokenType::INT, "1_000_000"),
+            (token::TokenType::FLOAT, "1_0.5_5"),
+            (token::TokenType::INT, "0x"),
+            (token::TokenType::DOT_DOT, ".."),
+            (token::TokenType::INT, "0"),
+            (token::TokenType::INT, "0X1F"),
+            (token::TokenType::INT, "0B11"),
+            (token::TokenType::INT, "0x1.5"),
+            (token::TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(String::from(
+            "0x1F 0o17 0b1010 0b102 0xffn 1_000_000 1_0.5_5 0x..0 0X1F 0B11 0x1.5",
+        ));
+        for (typ, literal) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(tok.literal, literal);
+        }
+    }
+
+    #[test]
+    fn test_string_tokens() {
+        let tests = vec![
+            (token::TokenType::STRING, "a \"b\"\n"),
+            (token::TokenType::TEMPLATE_HEAD, "x="),
+            (token::TokenType::IDENT, "f"),
+            (token::TokenType::LPAREN, "("),
+            (token::TokenType::TEMPLATE_HEAD, ""),
+            (token::TokenType::LBRACE, "{"),
+            (token::TokenType::RBRACE, "}"),
+            (token::TokenType::TEMPLATE_TAIL, "}"),
+            (token::TokenType::RPAREN, ")"),
+            (token::TokenType::TEMPLATE_MIDDLE, ", "),
+            (token::TokenType::INT, "1"),
+            (token::TokenType::TEMPLATE_TAIL, "${é}"),
+            (token::TokenType::ILLEGAL, "\"a"),
+            (token::TokenType::EOF, ""),
+        ];
+        let input = r#""a \"b\"\n" "x=${f("${{}}}")}, ${1}\${é}" "a"#;
+        let mut l = Lexer::new(String::from(input));
+        for (typ, literal) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(tok.literal, literal);
+        }
+    }
+
     #[test]
     fn test_u8to_String() {
         let r = u8to_String(b'=');
         let l = String::from("=");
         assert_eq!(l, r);
     }
+
+    #[test]
+    fn test_next_token_with_span() {
+        let input = String::from("let  ab = 12;");
+        let tests = vec![
+            (token::TokenType::LET, 0..3),
+            (token::TokenType::IDENT, 5..7),
+            (token::TokenType::ASSIGN, 8..9),
+            (token::TokenType::INT, 10..12),
+            (token::TokenType::SEMICOLON, 12..13),
+            (token::TokenType::EOF, 13..13),
+        ];
+        let mut l = Lexer::new(input);
+        for (typ, span) in tests {
+            let (tok, sp) = l.next_token_with_span();
+            assert_eq!(tok.typ, typ);
+            assert_eq!(sp, span);
+        }
+    }
+
+    #[test]
+    fn test_token_position() {
+        let input = String::from("let a = 1;\n  a + b\n");
+        let tests = vec![
+            ("let", 1, 1),
+            ("a", 1, 5),
+            ("=", 1, 7),
+            ("1", 1, 9),
+            (";", 1, 10),
+            ("a", 2, 3),
+            ("+", 2, 5),
+            ("b", 2, 7),
+            ("", 3, 1),
+        ];
+        let mut l = Lexer::new(input);
+        for (literal, line, column) in tests {
+            let tok = l.next_token();
+            assert_eq!(tok.literal, literal);
+            assert_eq!((tok.line, tok.column), (line, column), "{}", literal);
+        }
+    }
 }
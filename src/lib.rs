@@ -7,4 +7,9 @@ pub mod ast;
 pub mod parser;
 pub mod object;
 pub mod evaluator;
+pub mod script;
+pub mod code;
+pub mod compiler;
+pub mod vm;
+pub mod optimizer;
 
@@ -1,5 +1,226 @@
+use std::io::{IsTerminal, Read};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use rust_repl::code::{disassembler, mkc};
+use rust_repl::object::object::Object;
 use rust_repl::repl::repl;
+use rust_repl::script::script::{self, Options};
+
+const USAGE: &str = "usage: rust-repl [--engine=tree|vm] [-O] [limits] [script.monkey | -e <code> | -] [args...]\n       rust-repl --session <file>\n       rust-repl [-O] --disassemble <script.monkey>\n       rust-repl [-O] compile <script.monkey> -o <out.mkc>\n       rust-repl [limits] <script.mkc> [args...]\nlimits: --max-nesting=N --max-depth=N --max-steps=N --timeout=MS --max-collection-size=N";
+
+// 树遍历解释器每层函数调用都会递归，在足够大的栈上运行，很少需要切换到新分配的栈段
+const STACK_SIZE: usize = 256 << 20;
 
 fn main() {
-    repl::start();
+    let code = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(start)
+        .expect("cannot spawn interpreter thread")
+        .join()
+        .unwrap_or(101);
+    process::exit(code);
+}
+
+fn start() -> i32 {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut options = Options::default();
+    while let Some(flag) = args.first() {
+        if let Some(name) = flag.strip_prefix("--engine=") {
+            options.engine = match name.parse() {
+                Ok(engine) => engine,
+                Err(e) => {
+                    eprintln!("rust-repl: {}\n{}", e, USAGE);
+                    process::exit(2);
+                }
+            };
+        } else if flag == "-O" {
+            options.optimize = true;
+        } else if let Some(n) = flag.strip_prefix("--max-nesting=") {
+            options.max_nesting = parse_limit("--max-nesting", n);
+        } else if let Some(n) = flag.strip_prefix("--max-depth=") {
+            options.max_depth = parse_limit("--max-depth", n);
+        } else if let Some(n) = flag.strip_prefix("--max-steps=") {
+            options.budget.max_steps = Some(parse_limit("--max-steps", n) as u64);
+        } else if let Some(n) = flag.strip_prefix("--timeout=") {
+            let timeout = Duration::from_millis(parse_limit("--timeout", n) as u64);
+            options.budget.deadline = Some(Instant::now() + timeout);
+        } else if let Some(n) = flag.strip_prefix("--max-collection-size=") {
+            options.budget.max_collection_size = Some(parse_limit("--max-collection-size", n));
+        } else {
+            break;
+        }
+        args.remove(0);
+    }
+    match args.first().map(|s| s.as_str()) {
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            0
+        }
+        Some("-e") => match args.get(1) {
+            Some(code) => run(code, &args[2..], options),
+            None => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        Some("--session") => match args.get(1) {
+            Some(path) => run_repl(repl::Config {
+                session: Some(PathBuf::from(path)),
+                ..repl::Config::default()
+            }),
+            None => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        Some("--disassemble") => match args.get(1) {
+            Some(path) => disassemble(path, options),
+            None => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        Some("compile") => match (args.get(1), args.get(2).map(|s| s.as_str()), args.get(3)) {
+            (Some(input), Some("-o"), Some(output)) if args.len() == 4 => {
+                compile(input, output, options)
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        Some("-") => run_stdin(&args[1..], options),
+        Some(path) => run_file(path, &args[1..], options),
+        None if !std::io::stdin().is_terminal() => run_stdin(&[], options),
+        None => run_repl(repl::Config::default()),
+    }
+}
+
+fn parse_limit(flag: &str, value: &str) -> usize {
+    match value.parse() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            eprintln!(
+                "rust-repl: invalid value for {}: {}\n{}",
+                flag, value, USAGE
+            );
+            process::exit(2);
+        }
+    }
+}
+
+fn run_repl(config: repl::Config) -> i32 {
+    let result = if std::io::stdin().is_terminal() {
+        repl::start_terminal(config)
+    } else {
+        repl::start(std::io::stdin().lock(), std::io::stdout(), config)
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("rust-repl: {}", e);
+            1
+        }
+    }
+}
+
+// 以 .mkc 结尾或以 .mkc 魔数开头的文件当作编译好的字节码直接执行
+fn run_file(path: &str, args: &[String], options: Options) -> i32 {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("rust-repl: cannot read {}: {}", path, e);
+            return 1;
+        }
+    };
+    if path.ends_with(".mkc") || mkc::is_mkc(&data) {
+        let bytecode = match mkc::deserialize(&data) {
+            Ok(bytecode) => bytecode,
+            Err(e) => {
+                eprintln!("rust-repl: cannot load {}: {}", path, e);
+                return 1;
+            }
+        };
+        return report(script::run_bytecode(bytecode, args, options));
+    }
+    match String::from_utf8(data) {
+        Ok(source) => run(&source, args, options),
+        Err(_) => {
+            eprintln!("rust-repl: cannot read {}: not valid UTF-8", path);
+            1
+        }
+    }
+}
+
+fn run_stdin(args: &[String], options: Options) -> i32 {
+    let mut source = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut source) {
+        eprintln!("rust-repl: cannot read stdin: {}", e);
+        return 1;
+    }
+    run(&source, args, options)
+}
+
+fn run(source: &str, args: &[String], options: Options) -> i32 {
+    report(script::run(source, args, options))
+}
+
+fn report(result: Result<Rc<dyn Object>, script::ScriptError>) -> i32 {
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn disassemble(path: &str, options: Options) -> i32 {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("rust-repl: cannot read {}: {}", path, e);
+            return 1;
+        }
+    };
+    match script::compile(&source, options) {
+        Ok(bytecode) => {
+            print!("{}", disassembler::disassemble(&bytecode));
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn compile(input: &str, output: &str, options: Options) -> i32 {
+    let source = match std::fs::read_to_string(input) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("rust-repl: cannot read {}: {}", input, e);
+            return 1;
+        }
+    };
+    let bytecode = match script::compile(&source, options) {
+        Ok(bytecode) => bytecode,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let result = mkc::serialize(&bytecode).and_then(|data| {
+        std::fs::write(output, data).map_err(|e| format!("cannot write {}: {}", output, e))
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("rust-repl: {}", e);
+            1
+        }
+    }
 }
@@ -0,0 +1,171 @@
+// 大整数运算：64 位整数运算溢出或者有操作数是大整数时，两种引擎都在这里计算
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+
+use super::object::{BigInteger, Integer, Object};
+
+// 结果的位数上限，防止一次运算就耗尽内存
+pub const MAX_BITS: u64 = 1 << 24;
+
+// 64 位整数左移，shift 已经检查过在 0 到 63 之间；移出有效位时返回 None，由调用方换成大整数计算
+pub fn checked_shl(l: i64, shift: i64) -> Option<i64> {
+    let value = l << shift;
+    if value >> shift == l {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+// 整数和大整数都转换成 BigInt，其他对象返回 None
+pub fn to_bigint(obj: &Rc<dyn Object>) -> Option<BigInt> {
+    if let Some(i) = obj.downcast_ref::<Integer>() {
+        Some(BigInt::from(i.value))
+    } else {
+        obj.downcast_ref::<BigInteger>().map(|b| b.value.clone())
+    }
+}
+
+// 能用 64 位表示的结果换回 Integer，保证同一个值只有一种表示
+pub fn normalize(value: BigInt) -> Rc<dyn Object> {
+    match i64::try_from(&value) {
+        Ok(value) => Rc::new(Integer { value }),
+        Err(_) => Rc::new(BigInteger { value }),
+    }
+}
+
+// 比较运算，operator 不是比较运算符时返回 None
+pub fn compare(operator: &str, l: &BigInt, r: &BigInt) -> Option<bool> {
+    let ord = l.cmp(r);
+    match operator {
+        "<" => Some(ord == Ordering::Less),
+        ">" => Some(ord == Ordering::Greater),
+        "<=" => Some(ord != Ordering::Greater),
+        ">=" => Some(ord != Ordering::Less),
+        "==" => Some(ord == Ordering::Equal),
+        "!=" => Some(ord != Ordering::Equal),
+        _ => None,
+    }
+}
+
+// 算术、按位和移位运算，规则与 64 位整数相同
+pub fn infix(operator: &str, l: &BigInt, r: &BigInt) -> Result<BigInt, String> {
+    let zero = BigInt::from(0);
+    let value = match operator {
+        "+" => l + r,
+        "-" => l - r,
+        "*" => l * r,
+        "/" if *r == zero => return Err(String::from("division by zero")),
+        "/" => l / r,
+        "%" if *r == zero => return Err(String::from("modulo by zero")),
+        "%" => l % r,
+        "**" => {
+            let exp = match u32::try_from(r) {
+                Ok(exp) => exp,
+                Err(_) => return Err(format!("exponent out of range: {}", r)),
+            };
+            // 0、1 和 -1 的任意次方都不会变大
+            if l.bits() > 1 && l.bits() * exp as u64 > MAX_BITS {
+                return Err(String::from("integer too large"));
+            }
+            l.pow(exp)
+        }
+        "&" => l & r,
+        "|" => l | r,
+        "^" => l ^ r,
+        "<<" | ">>" => {
+            let shift = match u8::try_from(r) {
+                Ok(shift) if shift < 64 => shift,
+                _ => return Err(format!("shift amount out of range: {}", r)),
+            };
+            if operator == "<<" {
+                l << shift
+            } else {
+                l >> shift
+            }
+        }
+        ".." | "..=" => return Err(String::from("range bounds must fit in 64 bits")),
+        _ => return Err(format!("unknown operator: INTEGER {} INTEGER", operator)),
+    };
+    if value.bits() > MAX_BITS {
+        return Err(String::from("integer too large"));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(s: &str) -> BigInt {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_infix() {
+        let max = BigInt::from(i64::MAX);
+        assert_eq!(
+            infix("+", &max, &BigInt::from(1)).unwrap(),
+            big("9223372036854775808")
+        );
+        assert_eq!(
+            infix("**", &BigInt::from(2), &BigInt::from(100)).unwrap(),
+            big("1267650600228229401496703205376")
+        );
+        assert_eq!(
+            infix("/", &big("-100000000000000000000"), &BigInt::from(7)).unwrap(),
+            big("-14285714285714285714")
+        );
+        assert_eq!(
+            infix("%", &big("-100000000000000000000"), &BigInt::from(7)).unwrap(),
+            BigInt::from(-2)
+        );
+        assert_eq!(
+            infix(">>", &big("-100000000000000000000"), &BigInt::from(60)).unwrap(),
+            BigInt::from(-87)
+        );
+        assert_eq!(
+            infix("&", &big("-100000000000000000000"), &BigInt::from(255)).unwrap(),
+            BigInt::from(0)
+        );
+        assert_eq!(
+            infix("/", &max, &BigInt::from(0)).err().unwrap(),
+            "division by zero"
+        );
+        assert_eq!(
+            infix("<<", &max, &BigInt::from(64)).err().unwrap(),
+            "shift amount out of range: 64"
+        );
+        assert_eq!(
+            infix("**", &BigInt::from(3), &BigInt::from(u32::MAX))
+                .err()
+                .unwrap(),
+            "integer too large"
+        );
+        assert_eq!(
+            infix("**", &BigInt::from(-1), &BigInt::from(u32::MAX)).unwrap(),
+            BigInt::from(-1)
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(BigInt::from(i64::MIN))
+                .downcast_ref::<Integer>()
+                .unwrap()
+                .value,
+            i64::MIN
+        );
+        let obj = normalize(big("9223372036854775808"));
+        assert_eq!(obj.inspect(), "9223372036854775808");
+        assert!(obj.downcast_ref::<BigInteger>().is_some());
+        assert_eq!(
+            compare("<", &BigInt::from(5), &big("9223372036854775808")),
+            Some(true)
+        );
+        assert_eq!(compare("+", &BigInt::from(5), &BigInt::from(5)), None);
+    }
+}
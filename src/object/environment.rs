@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use super::object::Object;
+
+pub struct Environment {
+    store: HashMap<String, Rc<dyn Object>>,
+    // 用 const 定义、不能再赋值的名字
+    constants: HashSet<String>,
+    outer: Option<Rc<RefCell<Environment>>>,
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        Environment {
+            store: HashMap::new(),
+            constants: HashSet::new(),
+            outer: None,
+        }
+    }
+
+    // 函数调用时创建的作用域，找不到的名字交给外层环境
+    pub fn new_enclosed(outer: Rc<RefCell<Environment>>) -> Environment {
+        Environment {
+            store: HashMap::new(),
+            constants: HashSet::new(),
+            outer: Some(outer),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<dyn Object>> {
+        match self.store.get(name) {
+            Some(v) => Some(v.clone()),
+            None => match &self.outer {
+                Some(outer) => outer.borrow().get(name),
+                None => None,
+            },
+        }
+    }
+
+    // 当前作用域中定义的名字，按字典序排列
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.store.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // let 定义的名字，会覆盖同一作用域中之前的定义（包括 const）
+    pub fn set(&mut self, name: String, val: Rc<dyn Object>) -> Rc<dyn Object> {
+        self.constants.remove(&name);
+        self.store.insert(name, val.clone());
+        val
+    }
+
+    pub fn set_const(&mut self, name: String, val: Rc<dyn Object>) -> Rc<dyn Object> {
+        self.constants.insert(name.clone());
+        self.store.insert(name, val.clone());
+        val
+    }
+
+    // 给已有的名字赋值：修改定义这个名字的作用域，闭包也可以给捕获的外层变量赋值
+    pub fn assign(&mut self, name: &str, val: Rc<dyn Object>) -> Result<(), String> {
+        if self.store.contains_key(name) {
+            return self.replace(name, val);
+        }
+        match &self.outer {
+            Some(outer) => outer.borrow_mut().assign(name, val),
+            None => Err(format!("identifier not found: {}", name)),
+        }
+    }
+
+    fn replace(&mut self, name: &str, val: Rc<dyn Object>) -> Result<(), String> {
+        if self.constants.contains(name) {
+            return Err(format!("cannot assign to constant: {}", name));
+        }
+        self.store.insert(name.to_string(), val);
+        Ok(())
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,70 @@
+// 小数运算：有一边是小数时另一边的整数先转换成小数，两种引擎都在这里计算
+use std::rc::Rc;
+
+use num_traits::ToPrimitive;
+
+use super::object::{BigInteger, Float, Integer, Object};
+
+// 整数、大整数和小数都转换成 f64，其他对象返回 None
+pub fn to_f64(obj: &Rc<dyn Object>) -> Option<f64> {
+    if let Some(f) = obj.downcast_ref::<Float>() {
+        Some(f.value)
+    } else if let Some(i) = obj.downcast_ref::<Integer>() {
+        Some(i.value as f64)
+    } else {
+        obj.downcast_ref::<BigInteger>()
+            .and_then(|b| b.value.to_f64())
+    }
+}
+
+// 比较运算，operator 不是比较运算符时返回 None；和 NaN 比较时只有 != 成立
+pub fn compare(operator: &str, l: f64, r: f64) -> Option<bool> {
+    match operator {
+        "<" => Some(l < r),
+        ">" => Some(l > r),
+        "<=" => Some(l <= r),
+        ">=" => Some(l >= r),
+        "==" => Some(l == r),
+        "!=" => Some(l != r),
+        _ => None,
+    }
+}
+
+// 算术运算，除以零和整数一样报错；按位运算、移位和区间不支持小数，返回 None
+pub fn infix(operator: &str, l: f64, r: f64) -> Option<Result<f64, String>> {
+    let value = match operator {
+        "+" => l + r,
+        "-" => l - r,
+        "*" => l * r,
+        "/" if r == 0.0 => return Some(Err(String::from("division by zero"))),
+        "/" => l / r,
+        "%" if r == 0.0 => return Some(Err(String::from("modulo by zero"))),
+        "%" => l % r,
+        "**" => l.powf(r),
+        _ => return None,
+    };
+    Some(Ok(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_infix() {
+        assert_eq!(infix("+", 0.5, 0.25), Some(Ok(0.75)));
+        assert_eq!(infix("%", -7.5, 2.0), Some(Ok(-1.5)));
+        assert_eq!(infix("**", 2.0, -1.0), Some(Ok(0.5)));
+        assert_eq!(
+            infix("/", 1.0, 0.0),
+            Some(Err(String::from("division by zero")))
+        );
+        assert_eq!(infix("&", 1.0, 2.0), None);
+        assert_eq!(compare("!=", f64::NAN, f64::NAN), Some(true));
+        assert_eq!(compare("==", f64::NAN, f64::NAN), Some(false));
+        let big: Rc<dyn Object> = Rc::new(BigInteger {
+            value: "18446744073709551616".parse().unwrap(),
+        });
+        assert_eq!(to_f64(&big), Some(18446744073709551616.0));
+    }
+}
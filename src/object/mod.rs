@@ -1,2 +1,4 @@
-
+pub mod bigint;
+pub mod environment;
+pub mod float;
 pub mod object;
\ No newline at end of file
@@ -1,24 +1,57 @@
-// type  ObjectType = String;
+use std::cell::RefCell;
+use std::rc::Rc;
 
-use std::str::FromStr;
+use downcast_rs::{impl_downcast, Downcast};
+use num_bigint::BigInt;
 
-pub trait Object {
+use crate::ast::ast::{BlockStatement, Identifier, Node};
+use crate::code::code::{Instructions, Position};
+use crate::evaluator::budget::Limit;
+
+use super::environment::Environment;
+
+pub trait Object: Downcast {
     fn type_of(&self) -> ObjectType;
-    fn inspect(self) -> String;
+    fn inspect(&self) -> String;
+    // 迭代协议：可以用 for 遍历的对象按顺序返回 (键, 值)，序列的键是从 0 开始的下标
+    fn iter(&self) -> Option<Entries> {
+        None
+    }
 }
 
-enum ObjectType {
+pub type Entries = Box<dyn Iterator<Item = (Rc<dyn Object>, Rc<dyn Object>)>>;
+
+impl_downcast!(Object);
+
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum ObjectType {
     INTEGER,
-    BOOLEN,
+    FLOAT,
+    BOOLEAN,
     NULL,
+    STRING,
+    RANGE,
+    ITERATOR,
+    RETURN_VALUE,
+    TAIL_CALL,
+    BREAK,
+    CONTINUE,
+    ERROR,
+    BUDGET_EXCEEDED,
+    FUNCTION,
+    BUILTIN,
+    COMPILED_FUNCTION,
+    CLOSURE,
+    CELL,
 }
 
-struct Integer {
-    value: i64,
+pub struct Integer {
+    pub value: i64,
 }
 
 impl Object for Integer {
-    fn inspect(self) -> String {
+    fn inspect(&self) -> String {
         self.value.to_string()
     }
     fn type_of(&self) -> ObjectType {
@@ -26,28 +59,349 @@ impl Object for Integer {
     }
 }
 
-struct Boolen {
-    value: bool,
+// 超出 64 位的整数，对脚本来说和 Integer 是同一种类型；能用 64 位表示的值总是用 Integer
+pub struct BigInteger {
+    pub value: BigInt,
 }
 
-impl Object for Boolen {
-    fn inspect(self) -> String {
+impl Object for BigInteger {
+    fn inspect(&self) -> String {
         self.value.to_string()
     }
     fn type_of(&self) -> ObjectType {
-        ObjectType::BOOLEN
+        ObjectType::INTEGER
     }
 }
 
-struct Null {
-    
+pub struct Float {
+    pub value: f64,
+}
+
+impl Object for Float {
+    // 用 Debug 格式输出，保证和整数区分开：1.0、0.5、1e-9
+    fn inspect(&self) -> String {
+        format!("{:?}", self.value)
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::FLOAT
+    }
+}
+
+pub struct Boolean {
+    pub value: bool,
+}
+
+impl Object for Boolean {
+    fn inspect(&self) -> String {
+        self.value.to_string()
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::BOOLEAN
+    }
 }
 
+pub struct Null {}
+
 impl Object for Null {
-    fn inspect(self) -> String {
+    fn inspect(&self) -> String {
         String::from("null")
     }
     fn type_of(&self) -> ObjectType {
         ObjectType::NULL
     }
 }
+
+// 字符串字面量、字符串插值和宿主程序（如脚本参数）产生的字符串
+pub struct Str {
+    pub value: String,
+}
+
+impl Object for Str {
+    fn inspect(&self) -> String {
+        self.value.clone()
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::STRING
+    }
+    // 按字符遍历，每个字符是一个单字符的字符串
+    fn iter(&self) -> Option<Entries> {
+        let chars: Vec<char> = self.value.chars().collect();
+        Some(Box::new(chars.into_iter().enumerate().map(|(i, c)| {
+            let value: Rc<dyn Object> = Rc::new(Str {
+                value: c.to_string(),
+            });
+            (index(i), value)
+        })))
+    }
+}
+
+// 整数区间 start..end，inclusive 时包含 end；遍历时才逐个产生元素
+pub struct Range {
+    pub start: i64,
+    pub end: i64,
+    pub inclusive: bool,
+}
+
+impl Object for Range {
+    fn inspect(&self) -> String {
+        let op = if self.inclusive { "..=" } else { ".." };
+        format!("{}{}{}", self.start, op, self.end)
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::RANGE
+    }
+    fn iter(&self) -> Option<Entries> {
+        let integer = |(i, value)| -> (Rc<dyn Object>, Rc<dyn Object>) {
+            (index(i), Rc::new(Integer { value }))
+        };
+        if self.inclusive {
+            Some(Box::new((self.start..=self.end).enumerate().map(integer)))
+        } else {
+            Some(Box::new((self.start..self.end).enumerate().map(integer)))
+        }
+    }
+}
+
+fn index(i: usize) -> Rc<dyn Object> {
+    Rc::new(Integer { value: i as i64 })
+}
+
+// 虚拟机中 for 循环正在遍历的位置，保存在栈上直到循环结束
+pub struct Iter {
+    pub entries: RefCell<Entries>,
+}
+
+impl Object for Iter {
+    fn inspect(&self) -> String {
+        String::from("<iterator>")
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::ITERATOR
+    }
+}
+
+// return 语句的返回值，沿着语句块向外传递直到函数调用或程序顶层
+pub struct ReturnValue {
+    pub value: Rc<dyn Object>,
+}
+
+impl Object for ReturnValue {
+    fn inspect(&self) -> String {
+        self.value.inspect()
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::RETURN_VALUE
+    }
+}
+
+// 尾位置上的函数调用：由调用方在循环中执行，而不是在求值时递归调用
+pub struct TailCall {
+    pub func: Rc<dyn Object>,
+    pub args: Vec<Rc<dyn Object>>,
+    // 调用处的位置
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Object for TailCall {
+    fn inspect(&self) -> String {
+        String::from("<tail call>")
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::TAIL_CALL
+    }
+}
+
+// break 和 continue 语句的结果，和返回值一样沿着语句块向外传递直到所在的循环
+pub struct Break {}
+
+impl Object for Break {
+    fn inspect(&self) -> String {
+        String::from("<break>")
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::BREAK
+    }
+}
+
+pub struct Continue {}
+
+impl Object for Continue {
+    fn inspect(&self) -> String {
+        String::from("<continue>")
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::CONTINUE
+    }
+}
+
+// 调用栈中的一帧：正在执行的函数，以及调用它的位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub function: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+// 调用栈最多显示的帧数，更深时省略中间的部分
+const MAX_TRACE_FRAMES: usize = 20;
+
+pub struct Error {
+    pub message: String,
+    // 出错时的调用栈，最内层的调用在前，在顶层出错时为空
+    pub trace: Vec<TraceFrame>,
+}
+
+impl Error {
+    pub fn format_trace(&self, lines: &[&str]) -> String {
+        format_trace(&self.trace, lines)
+    }
+}
+
+// 每帧一行函数名和调用位置，下一行是调用处的源码，lines 是按行拆开的源码
+pub fn format_trace(trace: &[TraceFrame], lines: &[&str]) -> String {
+    let mut out = String::new();
+    let n = trace.len();
+    let half = MAX_TRACE_FRAMES / 2;
+    for (i, frame) in trace.iter().enumerate() {
+        if n > MAX_TRACE_FRAMES && i >= half && i < n - half {
+            if i == half {
+                out.push_str(&format!("  ... {} more frames\n", n - MAX_TRACE_FRAMES));
+            }
+            continue;
+        }
+        out.push_str(&format!(
+            "  at {} (line {}, column {})\n",
+            frame.function, frame.line, frame.column
+        ));
+        if let Some(line) = frame.line.checked_sub(1).and_then(|i| lines.get(i)) {
+            out.push_str(&format!("    {}\n", line.trim()));
+        }
+    }
+    out
+}
+
+impl Object for Error {
+    fn inspect(&self) -> String {
+        format!("ERROR: {}", self.message)
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::ERROR
+    }
+}
+
+// 执行预算耗尽或被取消，和错误一样中止求值
+pub struct BudgetExceeded {
+    pub limit: Limit,
+}
+
+impl Object for BudgetExceeded {
+    fn inspect(&self) -> String {
+        format!("ERROR: budget exceeded: {}", self.limit)
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::BUDGET_EXCEEDED
+    }
+}
+
+pub struct Function {
+    // let 绑定时的名字，匿名函数为 None
+    pub name: Option<String>,
+    pub params: Vec<Identifier>,
+    pub body: Rc<BlockStatement>,
+    pub env: Rc<RefCell<Environment>>,
+}
+
+impl Object for Function {
+    fn inspect(&self) -> String {
+        function_text(&self.params, &self.body)
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::FUNCTION
+    }
+}
+
+// 函数值的输出形式，树遍历解释器和虚拟机输出的一样
+pub fn function_text(params: &[Identifier], body: &BlockStatement) -> String {
+    let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+    format!("fn({}) {{\n{}\n}}", params.join(", "), body.to_string())
+}
+
+// 编译后的函数体，存放在常量池中
+pub struct CompiledFunction {
+    pub instructions: Instructions,
+    pub positions: Vec<Position>,
+    pub num_locals: usize,
+    pub num_params: usize,
+    pub name: Option<String>,
+    // 编译时由 function_text 生成，inspect 时输出
+    pub text: String,
+}
+
+impl Object for CompiledFunction {
+    fn inspect(&self) -> String {
+        self.text.clone()
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::COMPILED_FUNCTION
+    }
+}
+
+// 虚拟机运行时的函数值：编译后的函数加上它捕获的自由变量
+pub struct Closure {
+    pub func: Rc<CompiledFunction>,
+    pub free: Vec<Rc<dyn Object>>,
+}
+
+impl Object for Closure {
+    fn inspect(&self) -> String {
+        self.func.inspect()
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::CLOSURE
+    }
+}
+
+// 虚拟机中被闭包捕获的局部变量，外层函数和所有捕获它的闭包共享同一个 Cell
+pub struct Cell {
+    pub value: RefCell<Rc<dyn Object>>,
+}
+
+impl Object for Cell {
+    fn inspect(&self) -> String {
+        String::from("<cell>")
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::CELL
+    }
+}
+
+pub type BuiltinFunction = Rc<dyn Fn(Vec<Rc<dyn Object>>) -> Rc<dyn Object>>;
+
+pub struct Builtin {
+    pub name: String,
+    pub func: BuiltinFunction,
+}
+
+impl Object for Builtin {
+    fn inspect(&self) -> String {
+        format!("builtin function {}", self.name)
+    }
+    fn type_of(&self) -> ObjectType {
+        ObjectType::BUILTIN
+    }
+}
+
+pub fn new_error(message: String) -> Rc<dyn Object> {
+    Rc::new(Error {
+        message,
+        trace: Vec::new(),
+    })
+}
+
+pub fn is_error(obj: &Rc<dyn Object>) -> bool {
+    matches!(
+        obj.type_of(),
+        ObjectType::ERROR | ObjectType::BUDGET_EXCEEDED
+    )
+}
@@ -0,0 +1,430 @@
+use std::rc::Rc;
+
+use crate::ast::ast::{
+    AssignExpression, BlockStatement, Boolean, CallExpression, Expression, ExpressionStatement,
+    FloatLiteral, ForStatement, FunctionLiteral, IFExpression, InfixExpression, IntegerLiteral,
+    LetStatement, PrefixExpression, Program, ReturnStatement, Statement, StringLiteral,
+    TemplateLiteral, WhileStatement,
+};
+use crate::evaluator::stack;
+use crate::object::bigint;
+use crate::token::token::{Token, TokenType};
+
+// 编译期就能确定值的表达式
+#[derive(Clone, Copy)]
+enum Constant {
+    Int(i64),
+    Bool(bool),
+}
+
+impl Constant {
+    fn is_truthy(self) -> bool {
+        match self {
+            Constant::Int(_) => true,
+            Constant::Bool(b) => b,
+        }
+    }
+}
+
+// AST 级别的优化：折叠常量表达式、去掉条件恒定的 if 的死分支、删除没有副作用的表达式语句。
+// 运行时才会出错的表达式（例如除以零）保持原样，让错误照常在执行时报出。
+pub fn optimize(program: Program) -> Program {
+    Program {
+        statements: optimize_statements(program.statements),
+    }
+}
+
+fn optimize_statements(statements: Vec<Box<dyn Statement>>) -> Vec<Box<dyn Statement>> {
+    let count = statements.len();
+    let mut spliced: Vec<Box<dyn Statement>> = Vec::with_capacity(count);
+    for (i, stmt) in statements.into_iter().enumerate() {
+        let stmt = optimize_statement(stmt);
+        // if (true) { ... } 的语句块没有自己的作用域，可以直接展开到外层；
+        // 但展开空语句块会改变最后一条语句的值，这时保留原样
+        match always_taken(stmt.as_ref()) {
+            Some(block) if i + 1 < count || !block.statements.is_empty() => {
+                let es = stmt.downcast::<ExpressionStatement>().ok().unwrap();
+                let ie = es.expression.downcast::<IFExpression>().ok().unwrap();
+                spliced.extend(ie.consequence.statements);
+            }
+            _ => spliced.push(stmt),
+        }
+    }
+
+    // 最后一条语句的值是整个语句块的值，不能删除
+    let count = spliced.len();
+    spliced
+        .into_iter()
+        .enumerate()
+        .filter(|(i, stmt)| {
+            i + 1 == count
+                || !stmt
+                    .downcast_ref::<ExpressionStatement>()
+                    .is_some_and(|es| is_pure(es.expression.as_ref()))
+        })
+        .map(|(_, stmt)| stmt)
+        .collect()
+}
+
+fn always_taken(stmt: &dyn Statement) -> Option<&BlockStatement> {
+    let ie = stmt
+        .downcast_ref::<ExpressionStatement>()?
+        .expression
+        .downcast_ref::<IFExpression>()?;
+    match constant(ie.condition.as_ref()) {
+        Some(Constant::Bool(true)) if ie.alternative.statements.is_empty() => Some(&ie.consequence),
+        _ => None,
+    }
+}
+
+// 求值时既不会出错也没有副作用的表达式
+fn is_pure(exp: &dyn Expression) -> bool {
+    constant(exp).is_some()
+        || exp.downcast_ref::<FloatLiteral>().is_some()
+        || exp.downcast_ref::<StringLiteral>().is_some()
+        || exp.downcast_ref::<FunctionLiteral>().is_some()
+}
+
+// 栈空间不足时切换到新的栈段，见 stack::grow
+fn optimize_statement(stmt: Box<dyn Statement>) -> Box<dyn Statement> {
+    stack::grow(|| optimize_statement_inner(stmt))
+}
+
+fn optimize_statement_inner(stmt: Box<dyn Statement>) -> Box<dyn Statement> {
+    let stmt = match stmt.downcast::<ExpressionStatement>() {
+        Ok(mut st) => {
+            st.expression = optimize_expression(st.expression);
+            return st;
+        }
+        Err(stmt) => stmt,
+    };
+    let stmt = match stmt.downcast::<LetStatement>() {
+        Ok(mut st) => {
+            st.value = optimize_expression(st.value);
+            return st;
+        }
+        Err(stmt) => stmt,
+    };
+    let stmt = match stmt.downcast::<ReturnStatement>() {
+        Ok(mut st) => {
+            st.return_value = optimize_expression(st.return_value);
+            return st;
+        }
+        Err(stmt) => stmt,
+    };
+    let stmt = match stmt.downcast::<WhileStatement>() {
+        Ok(st) => {
+            let st = *st;
+            return Box::new(WhileStatement::new(
+                st.token,
+                optimize_expression(st.condition),
+                optimize_block(st.body),
+            ));
+        }
+        Err(stmt) => stmt,
+    };
+    match stmt.downcast::<ForStatement>() {
+        Ok(st) => {
+            let st = *st;
+            Box::new(ForStatement {
+                iterable: optimize_expression(st.iterable),
+                body: optimize_block(st.body),
+                ..st
+            })
+        }
+        Err(stmt) => stmt,
+    }
+}
+
+fn optimize_expression(exp: Box<dyn Expression>) -> Box<dyn Expression> {
+    stack::grow(|| optimize_expression_inner(exp))
+}
+
+fn optimize_expression_inner(exp: Box<dyn Expression>) -> Box<dyn Expression> {
+    let exp = match exp.downcast::<PrefixExpression>() {
+        Ok(e) => return fold_prefix(*e),
+        Err(exp) => exp,
+    };
+    let exp = match exp.downcast::<InfixExpression>() {
+        Ok(e) => return fold_infix(*e),
+        Err(exp) => exp,
+    };
+    let exp = match exp.downcast::<AssignExpression>() {
+        Ok(mut e) => {
+            e.value = optimize_expression(e.value);
+            return e;
+        }
+        Err(exp) => exp,
+    };
+    let exp = match exp.downcast::<IFExpression>() {
+        Ok(e) => return optimize_if(*e),
+        Err(exp) => exp,
+    };
+    let exp = match exp.downcast::<FunctionLiteral>() {
+        Ok(mut e) => {
+            // 刚解析出来的函数体没有被共享，共享时就不做优化
+            e.body = match Rc::try_unwrap(e.body) {
+                Ok(body) => Rc::new(optimize_block(body)),
+                Err(body) => body,
+            };
+            return e;
+        }
+        Err(exp) => exp,
+    };
+    let exp = match exp.downcast::<TemplateLiteral>() {
+        Ok(mut e) => {
+            e.expressions = e.expressions.into_iter().map(optimize_expression).collect();
+            return e;
+        }
+        Err(exp) => exp,
+    };
+    match exp.downcast::<CallExpression>() {
+        Ok(mut e) => {
+            e.func = optimize_expression(e.func);
+            e.args = e.args.into_iter().map(optimize_expression).collect();
+            e
+        }
+        Err(exp) => exp,
+    }
+}
+
+fn optimize_block(block: BlockStatement) -> BlockStatement {
+    BlockStatement {
+        token: block.token,
+        statements: optimize_statements(block.statements),
+    }
+}
+
+fn fold_prefix(mut e: PrefixExpression) -> Box<dyn Expression> {
+    e.right = optimize_expression(e.right);
+    let folded = match (e.operator.as_str(), constant(e.right.as_ref())) {
+        ("!", Some(c)) => Constant::Bool(!c.is_truthy()),
+        ("-", Some(Constant::Int(v))) if v != i64::MIN => Constant::Int(-v),
+        ("~", Some(Constant::Int(v))) => Constant::Int(!v),
+        _ => return Box::new(e),
+    };
+    literal(&e.token, folded)
+}
+
+fn fold_infix(mut e: InfixExpression) -> Box<dyn Expression> {
+    e.left = optimize_expression(e.left);
+    e.right = optimize_expression(e.right);
+    let folded = match (constant(e.left.as_ref()), constant(e.right.as_ref())) {
+        // 左边已经决定结果时右边不会求值，可以整个折叠掉
+        (Some(l), _) if e.operator == "&&" && !l.is_truthy() => Constant::Bool(false),
+        (Some(l), _) if e.operator == "||" && l.is_truthy() => Constant::Bool(true),
+        (Some(_), Some(r)) if e.operator == "&&" || e.operator == "||" => {
+            Constant::Bool(r.is_truthy())
+        }
+        (Some(Constant::Int(l)), Some(Constant::Int(r))) => match fold_integer(&e.operator, l, r) {
+            Some(c) => c,
+            None => return Box::new(e),
+        },
+        (Some(Constant::Bool(l)), Some(Constant::Bool(r))) => match e.operator.as_str() {
+            "==" => Constant::Bool(l == r),
+            "!=" => Constant::Bool(l != r),
+            _ => return Box::new(e),
+        },
+        _ => return Box::new(e),
+    };
+    literal(&e.token, folded)
+}
+
+// 溢出、除以零、超出范围的指数和移位都不折叠，留到运行时报错
+fn fold_integer(operator: &str, l: i64, r: i64) -> Option<Constant> {
+    let value = match operator {
+        "+" => l.checked_add(r)?,
+        "-" => l.checked_sub(r)?,
+        "*" => l.checked_mul(r)?,
+        "/" => l.checked_div(r)?,
+        "%" if r != 0 => l.wrapping_rem(r),
+        "**" => l.checked_pow(u32::try_from(r).ok()?)?,
+        "&" => l & r,
+        "|" => l | r,
+        "^" => l ^ r,
+        "<<" if (0..64).contains(&r) => bigint::checked_shl(l, r)?,
+        ">>" if (0..64).contains(&r) => l >> r,
+        "<" => return Some(Constant::Bool(l < r)),
+        ">" => return Some(Constant::Bool(l > r)),
+        "<=" => return Some(Constant::Bool(l <= r)),
+        ">=" => return Some(Constant::Bool(l >= r)),
+        "==" => return Some(Constant::Bool(l == r)),
+        "!=" => return Some(Constant::Bool(l != r)),
+        _ => return None,
+    };
+    Some(Constant::Int(value))
+}
+
+// 条件恒定时只保留会执行的分支：只有一个表达式时直接用这个表达式代替，
+// 否则规整成 if (true) { ... } 的形式，由外层决定能否展开
+fn optimize_if(e: IFExpression) -> Box<dyn Expression> {
+    let condition = optimize_expression(e.condition);
+    let consequence = optimize_block(e.consequence);
+    let alternative = optimize_block(e.alternative);
+    let taken = match constant(condition.as_ref()) {
+        Some(c) if c.is_truthy() => consequence,
+        Some(_) => alternative,
+        None => {
+            return Box::new(IFExpression::new(
+                e.token,
+                condition,
+                consequence,
+                alternative,
+            ))
+        }
+    };
+    if taken.statements.len() == 1
+        && taken.statements[0]
+            .downcast_ref::<ExpressionStatement>()
+            .is_some()
+    {
+        let es = taken.statements.into_iter().next().unwrap();
+        return es
+            .downcast::<ExpressionStatement>()
+            .ok()
+            .unwrap()
+            .expression;
+    }
+    let empty = BlockStatement::new(taken.token.clone(), Vec::new());
+    Box::new(IFExpression::new(
+        e.token.clone(),
+        literal(&e.token, Constant::Bool(true)),
+        taken,
+        empty,
+    ))
+}
+
+fn constant(exp: &dyn Expression) -> Option<Constant> {
+    if let Some(i) = exp.downcast_ref::<IntegerLiteral>() {
+        Some(Constant::Int(i.value))
+    } else {
+        exp.downcast_ref::<Boolean>()
+            .map(|b| Constant::Bool(b.value))
+    }
+}
+
+// 折叠出的字面量沿用原表达式的位置
+fn literal(origin: &Token, value: Constant) -> Box<dyn Expression> {
+    let mut token = match value {
+        Constant::Int(v) => Token::new(TokenType::INT, v.to_string()),
+        Constant::Bool(true) => Token::new(TokenType::TRUE, String::from("true")),
+        Constant::Bool(false) => Token::new(TokenType::FALSE, String::from("false")),
+    };
+    token.line = origin.line;
+    token.column = origin.column;
+    match value {
+        Constant::Int(v) => Box::new(IntegerLiteral::new(token, v)),
+        Constant::Bool(b) => Box::new(Boolean::new(token, b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ast::Node;
+    use crate::evaluator::evaluator;
+    use crate::lexer::lexer::Lexer;
+    use crate::object::environment::Environment;
+    use crate::parser::parser::Parser;
+    use std::cell::RefCell;
+
+    fn parse(input: &str) -> Program {
+        Parser::new(Lexer::new(input.to_string()))
+            .parse_program()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let tests = vec![
+            ("1 + 2 * 3", "7"),
+            ("!true", "false"),
+            ("!!5", "true"),
+            ("-(2 - 5)", "3"),
+            ("1 < 2 == true", "true"),
+            ("9223372036854775807 + 1", "(9223372036854775807 + 1)"),
+            ("-9223372036854775807 - 1", "-9223372036854775808"),
+            ("-(-9223372036854775807 - 1)", "(--9223372036854775808)"),
+            ("3 ** 40", "(3 ** 40)"),
+            ("a + 2 * 3", "(a + 6)"),
+            ("1 / 0", "(1 / 0)"),
+            ("(4 / 2) / (1 - 1)", "(2 / 0)"),
+            ("-true", "(-true)"),
+            ("2 <= 2 && 3 >= 4", "false"),
+            ("false && f()", "false"),
+            ("1 || f()", "true"),
+            ("true && 0", "true"),
+            ("true && x", "(true && x)"),
+            ("2 ** 3 ** 2 % 100", "12"),
+            ("~(1 << 4 | 3) & 255 ^ 1", "237"),
+            ("-8 >> 1", "-4"),
+            ("5 % 0", "(5 % 0)"),
+            ("2 ** -1", "(2 ** -1)"),
+            ("1 << 64", "(1 << 64)"),
+            ("1 << 63", "(1 << 63)"),
+            ("1 + true", "(1 + true)"),
+            ("f(1 + 1)", "f(2)"),
+            ("let x = 2 * 2;", "let x = 4;"),
+            (
+                "fn(a) { return 1 + a * (2 + 3); }",
+                "fn(a)return (1 + (a * 5));",
+            ),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(optimize(parse(input)).to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_eliminate_dead_branches() {
+        let tests = vec![
+            ("if (1 > 2) { a } else { b }", "b"),
+            ("let x = if (true) { 10 } else { y };", "let x = 10;"),
+            ("if (false) { a }", "iftrue "),
+            ("if (!false) { let a = 1; a } 2", "let a = 1;a2"),
+            ("if (true) { return a; } b", "return a;b"),
+            ("if (x) { 1 + 1 } else { 2 * 2 }", "ifx 2 else 4"),
+            ("fn() { if (true) { } }", "fn()iftrue "),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(optimize(parse(input)).to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_drop_pure_statements() {
+        let tests = vec![
+            ("1; true; fn(x) { x }; 2", "2"),
+            ("a; 1", "a1"),
+            ("1 + 2; let x = 3;", "let x = 3;"),
+            ("1 / 0; 2", "(1 / 0)2"),
+            ("fn() { 1; 2 }", "fn()2"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(optimize(parse(input)).to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_preserves_results() {
+        let tests = vec![
+            "let f = fn(n) { if (1 < 2) { n * (3 - 1) } else { n / 0 } }; f(21)",
+            "let a = 1; if (true) { let a = 2; } a",
+            "if (true) { 5 }",
+            "let x = 5; if (false) { 1 }",
+            "1 / 0",
+            "if (true) { 1 / (2 - 2) }",
+            "-true; 1",
+            "let x = 9223372036854775807; x * 1 + 1",
+        ];
+        for input in tests {
+            let expected =
+                evaluator::eval(&parse(input), &Rc::new(RefCell::new(Environment::new())));
+            let got = evaluator::eval(
+                &optimize(parse(input)),
+                &Rc::new(RefCell::new(Environment::new())),
+            );
+            assert_eq!(got.inspect(), expected.inspect(), "{}", input);
+        }
+    }
+}
@@ -1,23 +1,50 @@
 use crate::token::token::TokenType;
 
 pub const LOWEST: i8 = 1;
-pub const EQUALS: i8 = 2; // ==
-pub const LESSGREATER: i8 = 3; // > or <
-pub const SUM: i8 = 4; //+
-pub const PRODUCT: i8 = 5; // *
-pub const PREFIX: i8 = 6; // -X or !X
-pub const CALL: i8 = 7; //fn(x)
+pub const ASSIGN: i8 = 2; // = or +=
+pub const RANGE: i8 = 3; // .. or ..=
+pub const OR: i8 = 4; // ||
+pub const AND: i8 = 5; // &&
+pub const EQUALS: i8 = 6; // ==
+pub const LESSGREATER: i8 = 7; // > or <
+pub const BIT_OR: i8 = 8; // |
+pub const BIT_XOR: i8 = 9; // ^
+pub const BIT_AND: i8 = 10; // &
+pub const SHIFT: i8 = 11; // << or >>
+pub const SUM: i8 = 12; //+
+pub const PRODUCT: i8 = 13; // *
+pub const PREFIX: i8 = 14; // -X or !X
+pub const POWER: i8 = 15; // **，比前缀运算高：-2 ** 2 是 -(2 ** 2)
+pub const CALL: i8 = 16; //fn(x)
 
 pub fn get_precedence(typ: TokenType) -> i8 {
     match typ {
+        TokenType::ASSIGN => ASSIGN,
+        TokenType::PLUS_ASSIGN => ASSIGN,
+        TokenType::MINUS_ASSIGN => ASSIGN,
+        TokenType::ASTERISK_ASSIGN => ASSIGN,
+        TokenType::SLASH_ASSIGN => ASSIGN,
+        TokenType::DOT_DOT => RANGE,
+        TokenType::DOT_DOT_EQ => RANGE,
+        TokenType::OR => OR,
+        TokenType::AND => AND,
         TokenType::EQ => EQUALS,
         TokenType::NOT_EQ => EQUALS,
         TokenType::LT => LESSGREATER,
         TokenType::GT => LESSGREATER,
+        TokenType::LT_EQ => LESSGREATER,
+        TokenType::GT_EQ => LESSGREATER,
         TokenType::PLUS => SUM,
         TokenType::MINUS => SUM,
         TokenType::SLASH => PRODUCT,
         TokenType::ASTERISK => PRODUCT,
+        TokenType::PERCENT => PRODUCT,
+        TokenType::POWER => POWER,
+        TokenType::PIPE => BIT_OR,
+        TokenType::CARET => BIT_XOR,
+        TokenType::AMPERSAND => BIT_AND,
+        TokenType::SHL => SHIFT,
+        TokenType::SHR => SHIFT,
         TokenType::LPAREN => CALL,
         _ => LOWEST,
     }
@@ -1,17 +1,27 @@
 use std::collections::HashMap;
 use std::future;
 
+use num_bigint::BigInt;
+
 use crate::ast::ast::{
-    BlockStatement, Boolean, CallExpression, Expression, ExpressionStatement, FunctionLiteral,
-    IFExpression, Identifier, InfixExpression, IntegerLiteral, LetStatement, Node,
-    PrefixExpression, Program, ReturnStatement, Statement,
+    AssignExpression, BigIntegerLiteral, BlockStatement, Boolean, BreakStatement, CallExpression,
+    ContinueStatement, Expression, ExpressionStatement, FloatLiteral, ForStatement,
+    FunctionLiteral, IFExpression, Identifier, InfixExpression, IntegerLiteral, LetStatement, Node,
+    PrefixExpression, Program, ReturnStatement, Statement, StringLiteral, TemplateLiteral,
+    WhileStatement,
 };
+use crate::evaluator::stack;
 use crate::lexer::lexer::Lexer;
 use crate::parser::consts;
 use crate::{token::token::TokenType, token::Token};
 
 use super::consts::PREFIX;
 
+// 表达式默认允许的最大嵌套层数
+pub const MAX_NESTING: usize = 256;
+// 可以设置的最大嵌套层数，更深的语法树在输出和释放时也可能耗尽栈空间
+pub const MAX_NESTING_LIMIT: usize = 4096;
+
 // pub type PrefixParseFn = fn() -> Box<dyn Expression>;
 // pub type InfixParseFn = fn(exp: dyn Expression) -> Box<dyn Expression>;
 
@@ -23,6 +33,12 @@ pub struct Parser {
     prefix_parses: HashMap<TokenType, fn(&mut Parser) -> Box<dyn Expression>>,
     infix_parses:
         HashMap<TokenType, fn(&mut Parser, exp: Box<dyn Expression>) -> Box<dyn Expression>>,
+    max_depth: usize,
+    depth: usize,
+    // 嵌套过深时放弃解析剩下的输入，不再报告后续的错误
+    aborted: bool,
+    // 当前所在的循环层数，函数体中重新从 0 开始
+    loops: usize,
 }
 
 impl Parser {
@@ -36,11 +52,19 @@ impl Parser {
             erros: Vec::new(),
             prefix_parses: HashMap::new(),
             infix_parses: HashMap::new(),
+            max_depth: MAX_NESTING,
+            depth: 0,
+            aborted: false,
+            loops: 0,
         };
         ans.register_prefix(TokenType::IDENT, Parser::parse_indentifier);
         ans.register_prefix(TokenType::INT, Parser::parse_integer_literal);
+        ans.register_prefix(TokenType::FLOAT, Parser::parse_float_literal);
+        ans.register_prefix(TokenType::STRING, Parser::parse_string_literal);
+        ans.register_prefix(TokenType::TEMPLATE_HEAD, Parser::parse_template_literal);
         ans.register_prefix(TokenType::BANG, Parser::parse_prefix_expression);
         ans.register_prefix(TokenType::MINUS, Parser::parse_prefix_expression);
+        ans.register_prefix(TokenType::TILDE, Parser::parse_prefix_expression);
         ans.register_prefix(TokenType::TRUE, Parser::parse_boolean);
         ans.register_prefix(TokenType::FALSE, Parser::parse_boolean);
         ans.register_prefix(TokenType::LPAREN, Parser::parse_grouped_expression);
@@ -51,15 +75,37 @@ impl Parser {
         ans.register_infix(TokenType::MINUS, Parser::parse_infix_expression);
         ans.register_infix(TokenType::SLASH, Parser::parse_infix_expression);
         ans.register_infix(TokenType::ASTERISK, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::PERCENT, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::POWER, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::AMPERSAND, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::PIPE, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::CARET, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::SHL, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::SHR, Parser::parse_infix_expression);
         ans.register_infix(TokenType::EQ, Parser::parse_infix_expression);
         ans.register_infix(TokenType::NOT_EQ, Parser::parse_infix_expression);
         ans.register_infix(TokenType::LT, Parser::parse_infix_expression);
         ans.register_infix(TokenType::GT, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::LT_EQ, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::GT_EQ, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::AND, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::OR, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::DOT_DOT, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::DOT_DOT_EQ, Parser::parse_infix_expression);
+        ans.register_infix(TokenType::ASSIGN, Parser::parse_assign_expression);
+        ans.register_infix(TokenType::PLUS_ASSIGN, Parser::parse_assign_expression);
+        ans.register_infix(TokenType::MINUS_ASSIGN, Parser::parse_assign_expression);
+        ans.register_infix(TokenType::ASTERISK_ASSIGN, Parser::parse_assign_expression);
+        ans.register_infix(TokenType::SLASH_ASSIGN, Parser::parse_assign_expression);
         ans.register_infix(TokenType::LPAREN, Parser::parse_call_expression);
         ans
     }
 
 
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth.min(MAX_NESTING_LIMIT);
+    }
+
     pub fn parse_program(&mut self) -> Result<Program, String> {
         let mut program = Program::new();
         while self.cur_token.typ != TokenType::EOF {
@@ -69,7 +115,10 @@ impl Parser {
             }
             self.next_token();
         }
-        Ok((program))
+        if !self.erros.is_empty() {
+            return Err(self.erros.join("\n"));
+        }
+        Ok(program)
     }
 
     fn next_token(&mut self) {
@@ -80,8 +129,11 @@ impl Parser {
 
     fn parse_statement(&mut self) -> Result<Box<dyn Statement>, String> {
         match self.cur_token.typ {
-            TokenType::LET => self.parse_let_statement(),
+            TokenType::LET | TokenType::CONST => self.parse_let_statement(),
             TokenType::RETURN => self.parse_return_statement(),
+            TokenType::WHILE => self.parse_while_statement(),
+            TokenType::FOR => self.parse_for_statement(),
+            TokenType::BREAK | TokenType::CONTINUE => self.parse_loop_control(),
             _ => self.parse_expression_statement(),
         }
     }
@@ -117,6 +169,92 @@ impl Parser {
         Ok(Box::new(ReturnStatement::new(token, val)))
     }
 
+    fn parse_while_statement(&mut self) -> Result<Box<dyn Statement>, String> {
+        let token = self.cur_token.clone();
+        if !self.expect_peek(TokenType::LPAREN) {
+            return Err("expect_peek failed".to_string());
+        }
+        self.next_token();
+        let condition = self.parse_expression(consts::LOWEST);
+        if !self.expect_peek(TokenType::RPAREN) || !self.expect_peek(TokenType::LBRACE) {
+            return Err("expect_peek failed".to_string());
+        }
+        let body = self.parse_loop_body();
+        if self.peek_token_is(TokenType::SEMICOLON) {
+            self.next_token();
+        }
+        Ok(Box::new(WhileStatement::new(token, condition, body)))
+    }
+
+    fn parse_for_statement(&mut self) -> Result<Box<dyn Statement>, String> {
+        let token = self.cur_token.clone();
+        if !self.expect_peek(TokenType::LPAREN) || !self.expect_peek(TokenType::IDENT) {
+            return Err("expect_peek failed".to_string());
+        }
+        let mut key = None;
+        let mut value = Identifier::new(self.cur_token.clone(), self.cur_token.literal.clone());
+        if self.peek_token_is(TokenType::COMMA) {
+            self.next_token();
+            if !self.expect_peek(TokenType::IDENT) {
+                return Err("expect_peek failed".to_string());
+            }
+            let second = Identifier::new(self.cur_token.clone(), self.cur_token.literal.clone());
+            key = Some(std::mem::replace(&mut value, second));
+        }
+        if !self.expect_peek(TokenType::IN) {
+            return Err("expect_peek failed".to_string());
+        }
+        self.next_token();
+        let iterable = self.parse_expression(consts::LOWEST);
+        if !self.expect_peek(TokenType::RPAREN) || !self.expect_peek(TokenType::LBRACE) {
+            return Err("expect_peek failed".to_string());
+        }
+        let body = self.parse_loop_body();
+        if self.peek_token_is(TokenType::SEMICOLON) {
+            self.next_token();
+        }
+        Ok(Box::new(ForStatement {
+            token,
+            key,
+            value,
+            iterable,
+            body,
+        }))
+    }
+
+    // 循环体和表达式一样计入嵌套深度
+    fn parse_loop_body(&mut self) -> BlockStatement {
+        if !self.enter_nesting() {
+            return BlockStatement::new(self.cur_token.clone(), Vec::new());
+        }
+        self.loops += 1;
+        let body = self.parse_block_statement();
+        self.loops -= 1;
+        self.depth -= 1;
+        body
+    }
+
+    // break 或 continue
+    fn parse_loop_control(&mut self) -> Result<Box<dyn Statement>, String> {
+        let token = self.cur_token.clone();
+        if self.peek_token_is(TokenType::SEMICOLON) {
+            self.next_token();
+        }
+        if self.loops == 0 {
+            let msg = format!(
+                "{} outside of a loop (line {}, column {})",
+                token.literal, token.line, token.column
+            );
+            self.erros.push(msg.clone());
+            return Err(msg);
+        }
+        if token.typ == TokenType::BREAK {
+            Ok(Box::new(BreakStatement { token }))
+        } else {
+            Ok(Box::new(ContinueStatement { token }))
+        }
+    }
+
     fn parse_expression_statement(&mut self) -> Result<Box<dyn Statement>, String> {
         let token = self.cur_token.clone();
         // println!("parse_expression_statement {:?}", token);
@@ -146,6 +284,7 @@ impl Parser {
             self.next_token();
             true
         } else {
+            self.peek_error(t);
             false
         }
     }
@@ -163,11 +302,72 @@ impl Parser {
     }
 
     fn no_prefix_parse_fn_error(&mut self, t: TokenType) {
+        if self.aborted {
+            return;
+        }
+        if t == TokenType::ILLEGAL && self.cur_token.literal.starts_with('"') {
+            return self.unterminated_string(&self.cur_token.clone());
+        }
+        // 例如还不支持的 [ 和 ]，后面的输入不再解析，以免报告一连串无关的错误
+        if t == TokenType::ILLEGAL {
+            self.erros.push(format!(
+                "illegal character: {} (line {}, column {})",
+                self.cur_token.literal, self.cur_token.line, self.cur_token.column
+            ));
+            return self.abort();
+        }
         let err = format!("no prefix parse fnction for {:?}", t);
         self.erros.push(err)
     }
 
+    fn unterminated_string(&mut self, token: &Token) {
+        self.erros.push(format!(
+            "unterminated string (line {}, column {})",
+            token.line, token.column
+        ));
+    }
+
+    // 每一层括号、前缀运算符、if 和函数体都会递归到这里，限制嵌套深度，
+    // 以免恶意构造的输入耗尽 Rust 的调用栈；在栈较小的线程上可能在达到限制之前就耗尽
     fn parse_expression(&mut self, precedence: i8) -> Box<dyn Expression> {
+        if !self.enter_nesting() {
+            return self.tmp_value();
+        }
+        let exp = self.parse_operators(precedence);
+        self.depth -= 1;
+        exp
+    }
+
+    // 进入下一层嵌套，超过限制时报告错误并放弃解析
+    fn enter_nesting(&mut self) -> bool {
+        if self.depth >= self.max_depth {
+            self.nesting_error(format!("more than {} levels deep", self.max_depth));
+            return false;
+        }
+        if stack::exhausted() {
+            self.nesting_error(String::from("too deeply for the available stack"));
+            return false;
+        }
+        self.depth += 1;
+        true
+    }
+
+    fn nesting_error(&mut self, reason: String) {
+        self.erros.push(format!(
+            "maximum recursion depth exceeded: expressions nested {} (line {}, column {})",
+            reason, self.cur_token.line, self.cur_token.column
+        ));
+        self.abort();
+    }
+
+    fn abort(&mut self) {
+        self.aborted = true;
+        while !self.cur_token_is(TokenType::EOF) {
+            self.next_token();
+        }
+    }
+
+    fn parse_operators(&mut self, precedence: i8) -> Box<dyn Expression> {
         if let Some(prefix) = self.prefix_parses.get(&self.cur_token.typ) {
             // println!("parse_expression prefix cur_token {:?}, peek_token {:?}", self.cur_token, self.peek_token);
             let mut left = prefix(self);
@@ -202,12 +402,41 @@ impl Parser {
     fn parse_infix_expression(&mut self, left: Box<dyn Expression>) -> Box<dyn Expression> {
         let token = self.cur_token.clone();
         let operator = self.cur_token.literal.clone();
-        let prececedence = self.cur_precedence();
+        let mut prececedence = self.cur_precedence();
+        // ** 是右结合的：2 ** 3 ** 2 是 2 ** (3 ** 2)
+        if token.typ == TokenType::POWER {
+            prececedence -= 1;
+        }
         self.next_token();
         let right = self.parse_expression(prececedence);
         Box::new(InfixExpression::new(token, left, operator, right))
     }
 
+    // 赋值是右结合的：a = b = 1 先给 b 赋值
+    fn parse_assign_expression(&mut self, left: Box<dyn Expression>) -> Box<dyn Expression> {
+        let token = self.cur_token.clone();
+        let operator = self.cur_token.literal.clone();
+        self.next_token();
+        let value = self.parse_expression(consts::LOWEST);
+        match left.downcast::<Identifier>() {
+            Ok(target) => Box::new(AssignExpression {
+                token,
+                target: *target,
+                operator,
+                value,
+            }),
+            Err(left) => {
+                self.erros.push(format!(
+                    "invalid assignment target: {} (line {}, column {})",
+                    left.to_string(),
+                    token.line,
+                    token.column
+                ));
+                self.tmp_value()
+            }
+        }
+    }
+
     fn parse_boolean(&mut self) -> Box<dyn Expression> {
         let token = self.cur_token.clone();
         let value = self.cur_token_is(TokenType::TRUE);
@@ -261,10 +490,12 @@ impl Parser {
         }
         let parmas = self.parse_function_params();
         if !self.expect_peek(TokenType::LBRACE) {
-            self.peek_error(TokenType::LBRACE);
             return self.tmp_value();
         }
+        // 函数体中的 break 和 continue 不能跳出外层的循环
+        let loops = std::mem::take(&mut self.loops);
         let body = self.parse_block_statement();
+        self.loops = loops;
         // println!("parse_function_literal {:?}, {:?}", parmas, body.to_string());
         Box::new(FunctionLiteral::new(token, parmas, body))
     }
@@ -288,7 +519,6 @@ impl Parser {
         }
         // println!("parse_function_params {:?}", ans);
         if !self.expect_peek(TokenType::RPAREN) {
-            self.peek_error(TokenType::RPAREN);
             return Vec::new();
         }
         ans
@@ -309,7 +539,6 @@ impl Parser {
     }
 
     fn parse_call_expression(&mut self, func: Box<dyn Expression>) -> Box<dyn Expression> {
-        println!("parse_call_expression");
         let token = self.cur_token.clone();
         let args = self.parse_call_args();
 
@@ -330,8 +559,6 @@ impl Parser {
             self.next_token();
             self.next_token();
             let exp = self.parse_expression(consts::LOWEST);
-            println!("parse_call_args {:?}", exp.to_string());
-
             ans.push(exp);
         }
         if !self.expect_peek(TokenType::RPAREN) {
@@ -349,12 +576,112 @@ impl Parser {
 
     fn parse_integer_literal(&mut self) -> Box<dyn Expression> {
         let token = self.cur_token.clone();
-        match token.literal.parse::<i64>() {
+        // 带 n 后缀的字面量可以超出 64 位，没有超出时和普通整数一样
+        let (body, big) = match token.literal.strip_suffix('n') {
+            Some(body) => (body, true),
+            None => (token.literal.as_str(), false),
+        };
+        let (radix, digits) = match number_digits(body) {
+            Ok(v) => v,
+            Err(e) => return self.number_error(e),
+        };
+        let value = BigInt::parse_bytes(digits.as_bytes(), radix).unwrap();
+        match i64::try_from(&value) {
             Ok(val) => Box::new(IntegerLiteral::new(token, val)),
-            Err(e) => self.tmp_value(),
+            Err(_) if big => Box::new(BigIntegerLiteral { token, value }),
+            Err(_) => {
+                self.erros.push(format!(
+                    "integer literal out of range: {} (line {}, column {})",
+                    token.literal, token.line, token.column
+                ));
+                self.tmp_value()
+            }
+        }
+    }
+
+    fn parse_float_literal(&mut self) -> Box<dyn Expression> {
+        let token = self.cur_token.clone();
+        let digits = match number_digits(&token.literal) {
+            Ok((_, digits)) => digits,
+            Err(e) => return self.number_error(e),
+        };
+        match digits.parse::<f64>() {
+            Ok(value) if value.is_finite() => Box::new(FloatLiteral { token, value }),
+            _ => {
+                self.erros.push(format!(
+                    "float literal out of range: {} (line {}, column {})",
+                    token.literal, token.line, token.column
+                ));
+                self.tmp_value()
+            }
+        }
+    }
+
+    fn parse_string_literal(&mut self) -> Box<dyn Expression> {
+        let token = self.cur_token.clone();
+        let value = token.literal.clone();
+        Box::new(StringLiteral { token, value })
+    }
+
+    // "a${x}b${y}c" 依次是 TEMPLATE_HEAD、x、TEMPLATE_MIDDLE、y、TEMPLATE_TAIL
+    fn parse_template_literal(&mut self) -> Box<dyn Expression> {
+        let token = self.cur_token.clone();
+        let mut strings = vec![token.literal.clone()];
+        let mut expressions = Vec::new();
+        loop {
+            // ${} 中没有表达式时报错后继续解析后面的部分
+            if matches!(
+                self.peek_token.typ,
+                TokenType::TEMPLATE_MIDDLE | TokenType::TEMPLATE_TAIL
+            ) {
+                self.erros.push(format!(
+                    "empty interpolation in string (line {}, column {})",
+                    self.peek_token.line, self.peek_token.column
+                ));
+                expressions.push(self.tmp_value());
+            } else {
+                self.next_token();
+                expressions.push(self.parse_expression(consts::LOWEST));
+            }
+            match self.peek_token.typ {
+                TokenType::TEMPLATE_MIDDLE | TokenType::TEMPLATE_TAIL => self.next_token(),
+                TokenType::ILLEGAL if self.peek_token.literal.starts_with('}') => {
+                    self.next_token();
+                    self.unterminated_string(&token);
+                    return self.tmp_value();
+                }
+                _ => {
+                    self.erros.push(format!(
+                        "unterminated interpolation in string (line {}, column {})",
+                        token.line, token.column
+                    ));
+                    return self.tmp_value();
+                }
+            }
+            strings.push(self.cur_token.literal.clone());
+            if self.cur_token.typ == TokenType::TEMPLATE_TAIL {
+                return Box::new(TemplateLiteral {
+                    token,
+                    strings,
+                    expressions,
+                });
+            }
         }
     }
 
+    // 数字字面量中的错误报告在出错的那个字符上
+    fn number_error(&mut self, (offset, msg): (usize, String)) -> Box<dyn Expression> {
+        let token = &self.cur_token;
+        self.erros.push(format!(
+            "{}: {} (line {}, column {})",
+            msg,
+            token.literal,
+            token.line,
+            token.column + offset
+        ));
+        self.tmp_value()
+    }
+
     fn peek_precedence(&self) -> i8 {
         consts::get_precedence(self.peek_token.typ)
     }
@@ -363,6 +690,9 @@ impl Parser {
     }
 
     fn peek_error(&mut self, t: TokenType) {
+        if self.aborted {
+            return;
+        }
         let msg = format!(
             "expected next token to be {:?}, got {:?} instead",
             t, self.peek_token.typ
@@ -381,6 +711,45 @@ impl Parser {
     }
 }
 
+// 去掉数字字面量的进制前缀和下划线，返回进制和剩下的数字；出错时返回出错字符的下标
+fn number_digits(literal: &str) -> Result<(u32, String), (usize, String)> {
+    let prefix = literal.get(..2).map(|p| p.to_ascii_lowercase());
+    let (radix, name) = match prefix.as_deref() {
+        Some("0x") => (16, "hexadecimal"),
+        Some("0o") => (8, "octal"),
+        Some("0b") => (2, "binary"),
+        _ => (10, "decimal"),
+    };
+    let start = if radix == 10 { 0 } else { 2 };
+    // 下划线只能出现在两个数字之间
+    let is_digit = |c: Option<&u8>| match c {
+        Some(c) if radix == 10 => c.is_ascii_digit(),
+        Some(c) => c.is_ascii_alphanumeric(),
+        None => false,
+    };
+    let bytes = literal.as_bytes();
+    let mut digits = String::new();
+    for (i, &c) in bytes.iter().enumerate().skip(start) {
+        if c == b'_' {
+            if i == start || !is_digit(bytes.get(i - 1)) || !is_digit(bytes.get(i + 1)) {
+                return Err((i, String::from("invalid digit separator")));
+            }
+            continue;
+        }
+        if radix != 10 && !(c as char).is_digit(radix) {
+            return Err((
+                i,
+                format!("invalid digit '{}' in {} literal", c as char, name),
+            ));
+        }
+        digits.push(c as char);
+    }
+    if digits.is_empty() {
+        return Err((literal.len(), format!("missing digits in {} literal", name)));
+    }
+    Ok((radix, digits))
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -423,7 +792,7 @@ mod tests {
         let input = r#"
         return 5;
         return 10;
-        return 993 322;
+        return 993322;
         "#;
         let l = Lexer::new(input.to_string());
         let mut p = Parser::new(l);
@@ -626,6 +995,22 @@ mod tests {
             input: "1 + (2 + 3) + 4".to_string(),
             expect: "((1 + (2 + 3)) + 4)".to_string(),
         });
+        tests.push(OperatorPrecedence {
+            input: "a == b && c <= d || !e".to_string(),
+            expect: "(((a == b) && (c <= d)) || (!e))".to_string(),
+        });
+        tests.push(OperatorPrecedence {
+            input: "a | b ^ c & d << 1 + 2 * 3 ** 4 ** 5 == e".to_string(),
+            expect: "((a | (b ^ (c & (d << (1 + (2 * (3 ** (4 ** 5)))))))) == e)".to_string(),
+        });
+        tests.push(OperatorPrecedence {
+            input: "-2 ** ~x % y".to_string(),
+            expect: "((-(2 ** (~x))) % y)".to_string(),
+        });
+        tests.push(OperatorPrecedence {
+            input: "a || b && c >= 1 + 2".to_string(),
+            expect: "(a || (b && (c >= (1 + 2))))".to_string(),
+        });
 
         for tt in tests {
             let l = Lexer::new(tt.input);
@@ -714,4 +1099,293 @@ mod tests {
             assert_eq!(stmt.to_string(), tt.expect);
         }
     }
+
+    #[test]
+    fn test_parse_while() {
+        let tests = vec![
+            ("while (x < y) { x }", "while(x < y) x"),
+            (
+                "while (true) { if (x) { break; } continue }",
+                "whiletrue ifx break;continue;",
+            ),
+        ];
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(input.to_string()));
+            let prgm = parser.parse_program().unwrap();
+            assert_eq!(prgm.get_statements_len(), 1);
+            assert_eq!(prgm.get_statement(0).to_string(), expect);
+        }
+
+        // 函数体中的 break 不属于外层的循环
+        let tests = vec![
+            ("break;", "break outside of a loop (line 1, column 1)"),
+            ("if (x) { continue }", "continue outside of a loop (line 1, column 10)"),
+            (
+                "while (x) { fn() { break } }",
+                "break outside of a loop (line 1, column 20)",
+            ),
+        ];
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(input.to_string()));
+            assert_eq!(parser.parse_program().err().unwrap(), expect, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_for() {
+        let tests = vec![
+            ("for (x in 1..n + 1) { x }", "for(x in (1 .. (n + 1))) x"),
+            ("for (i, c in s) { break }", "for(i, c in s) break;"),
+            ("a..=b == c", "(a ..= (b == c))"),
+        ];
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(input.to_string()));
+            let prgm = parser.parse_program().unwrap();
+            assert_eq!(prgm.get_statements_len(), 1);
+            assert_eq!(prgm.get_statement(0).to_string(), expect);
+        }
+
+        let mut parser = Parser::new(Lexer::new(String::from("for (1 in x) { }")));
+        assert!(parser.parse_program().is_err());
+    }
+
+    #[test]
+    fn test_parse_assign() {
+        let tests = vec![
+            ("x = y = 1", "(x = (y = 1))"),
+            ("x += 1 * 2", "(x += (1 * 2))"),
+            ("const x = a == b", "const x = (a == b);"),
+            ("f(x -= 1)", "f((x -= 1))"),
+        ];
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(input.to_string()));
+            let prgm = parser.parse_program().unwrap();
+            assert_eq!(prgm.get_statements_len(), 1);
+            assert_eq!(prgm.get_statement(0).to_string(), expect);
+        }
+
+        let mut parser = Parser::new(Lexer::new(String::from("1 + x = 2")));
+        assert_eq!(
+            parser.parse_program().err().unwrap(),
+            "invalid assignment target: (1 + x) (line 1, column 7)"
+        );
+
+        // 还没有下标表达式，下标赋值在 [ 处报一个错误
+        for input in ["a[0] = 1", "let h = 1;\nh[\"k\"] += 1"] {
+            let mut parser = Parser::new(Lexer::new(String::from(input)));
+            let line = input.lines().count();
+            assert_eq!(
+                parser.parse_program().err().unwrap(),
+                format!("illegal character: [ (line {}, column 2)", line)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_template_literal() {
+        let tests = vec![
+            (r#""hi""#, r#""hi""#),
+            (r#""a${x + 1}b""#, r#""a${(x + 1)}b""#),
+            (
+                r#""${f("${y}")} \${z} \"q\"""#,
+                r#""${f("${y}")} \${z} \"q\"""#,
+            ),
+            (
+                r#""${if (x) { "}" } else { 2 }}""#,
+                r#""${ifx "}" else 2}""#,
+            ),
+        ];
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(String::from(input)));
+            let prgm = parser.parse_program().unwrap();
+            assert_eq!(prgm.to_string(), expect);
+        }
+
+        let errors = vec![
+            ("let s = \"abc;", "unterminated string (line 1, column 9)"),
+            ("1 +\n\"a${1}b", "unterminated string (line 2, column 1)"),
+            (
+                "\"a${1 + 2",
+                "unterminated interpolation in string (line 1, column 1)",
+            ),
+            (
+                "\"${}${1}\"",
+                "empty interpolation in string (line 1, column 4)",
+            ),
+        ];
+        for (input, expect) in errors {
+            let mut parser = Parser::new(Lexer::new(String::from(input)));
+            assert_eq!(parser.parse_program().err().unwrap(), expect);
+        }
+    }
+
+    #[test]
+    fn test_parse_float_literal() {
+        let mut parser = Parser::new(Lexer::new(String::from("-.5 * 1e3 + 2")));
+        let prgm = parser.parse_program().unwrap();
+        assert_eq!(prgm.to_string(), "(((-.5) * 1e3) + 2)");
+
+        let mut parser = Parser::new(Lexer::new(String::from("1.5e400")));
+        assert_eq!(
+            parser.parse_program().err().unwrap(),
+            "float literal out of range: 1.5e400 (line 1, column 1)"
+        );
+    }
+
+    #[test]
+    fn test_parse_integer_literal_overflow() {
+        let mut parser = Parser::new(Lexer::new(String::from("9223372036854775807")));
+        assert_eq!(
+            parser.parse_program().unwrap().to_string(),
+            "9223372036854775807"
+        );
+
+        let mut parser = Parser::new(Lexer::new(String::from(
+            "let x = 1;\nx + 9223372036854775808",
+        )));
+        assert_eq!(
+            parser.parse_program().err().unwrap(),
+            "integer literal out of range: 9223372036854775808 (line 2, column 5)"
+        );
+
+        // 带 n 后缀时可以超出 64 位
+        let mut parser = Parser::new(Lexer::new(String::from("9223372036854775808n; 5n")));
+        let prgm = parser.parse_program().unwrap();
+        let literal = |i: usize| {
+            let stmt = prgm.get_statement(i);
+            &stmt
+                .downcast_ref::<ExpressionStatement>()
+                .unwrap()
+                .expression
+        };
+        assert_eq!(literal(0).to_string(), "9223372036854775808n");
+        assert_eq!(
+            literal(1).downcast_ref::<IntegerLiteral>().unwrap().value,
+            5
+        );
+        let lit = literal(0);
+        assert_eq!(
+            lit.downcast_ref::<BigIntegerLiteral>().unwrap().value,
+            "9223372036854775808".parse::<BigInt>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_number_prefixes_and_separators() {
+        let tests = vec![
+            ("0x1F", 31),
+            ("0o17", 15),
+            ("0X1F", 31),
+            ("0O17", 15),
+            ("0B11", 3),
+            ("0b1010", 10),
+            ("1_000_000", 1_000_000),
+            ("0xdead_beef", 0xdead_beef),
+            ("0x7fff_ffff_ffff_ffffn", i64::MAX),
+        ];
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(String::from(input)));
+            let prgm = parser.parse_program().unwrap();
+            let stmt = prgm.get_statement(0);
+            let expr = &stmt
+                .downcast_ref::<ExpressionStatement>()
+                .unwrap()
+                .expression;
+            assert_eq!(
+                expr.downcast_ref::<IntegerLiteral>().map(|i| i.value),
+                Some(expect),
+                "{}",
+                input
+            );
+        }
+
+        let errors = vec![
+            (
+                "let x = 0b102;",
+                "invalid digit '2' in binary literal: 0b102 (line 1, column 13)",
+            ),
+            (
+                "1 +\n  0o781",
+                "invalid digit '8' in octal literal: 0o781 (line 2, column 6)",
+            ),
+            (
+                "0xfg",
+                "invalid digit 'g' in hexadecimal literal: 0xfg (line 1, column 4)",
+            ),
+            (
+                "1 + 0x1.5",
+                "invalid digit '.' in hexadecimal literal: 0x1.5 (line 1, column 8)",
+            ),
+            (
+                "0b",
+                "missing digits in binary literal: 0b (line 1, column 3)",
+            ),
+            (
+                "1__000",
+                "invalid digit separator: 1__000 (line 1, column 2)",
+            ),
+            ("1_", "invalid digit separator: 1_ (line 1, column 2)"),
+            ("0x_ff", "invalid digit separator: 0x_ff (line 1, column 3)"),
+            ("1_.5", "invalid digit separator: 1_.5 (line 1, column 2)"),
+            (
+                "0xffff_ffff_ffff_ffff",
+                "integer literal out of range: 0xffff_ffff_ffff_ffff (line 1, column 1)",
+            ),
+        ];
+        for (input, expect) in errors {
+            let mut parser = Parser::new(Lexer::new(String::from(input)));
+            assert_eq!(parser.parse_program().err().unwrap(), expect);
+        }
+
+        let mut parser = Parser::new(Lexer::new(String::from("1_000.2_5e1_0")));
+        let prgm = parser.parse_program().unwrap();
+        let stmt = prgm.get_statement(0);
+        let expr = &stmt
+            .downcast_ref::<ExpressionStatement>()
+            .unwrap()
+            .expression;
+        assert_eq!(
+            expr.downcast_ref::<FloatLiteral>().unwrap().value,
+            1000.25e10
+        );
+    }
+
+    #[test]
+    fn test_parse_nesting_limit() {
+        let nested = |n: usize| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        let mut parser = Parser::new(Lexer::new(nested(MAX_NESTING - 1)));
+        assert!(parser.parse_program().is_ok());
+
+        // 超过限制时只报告一个错误
+        let mut parser = Parser::new(Lexer::new(nested(100000)));
+        assert_eq!(
+            parser.parse_program().err().unwrap(),
+            "maximum recursion depth exceeded: expressions nested more than 256 levels deep (line 1, column 257)"
+        );
+
+        let mut parser = Parser::new(Lexer::new(String::from(
+            "if (x) { if (y) { -(-z) } }",
+        )));
+        parser.set_max_depth(4);
+        assert_eq!(
+            parser.parse_program().err().unwrap(),
+            "maximum recursion depth exceeded: expressions nested more than 4 levels deep (line 1, column 21)"
+        );
+
+        // 循环体也计入嵌套深度，设置的层数不能超过 MAX_NESTING_LIMIT
+        let mut parser = Parser::new(Lexer::new(String::from(
+            "while (a) { for (x in b) { while (c) { 1 } } }",
+        )));
+        parser.set_max_depth(2);
+        assert_eq!(
+            parser.parse_program().err().unwrap(),
+            "maximum recursion depth exceeded: expressions nested more than 2 levels deep (line 1, column 35)"
+        );
+        let mut parser = Parser::new(Lexer::new(nested(100000)));
+        parser.set_max_depth(usize::MAX);
+        assert_eq!(
+            parser.parse_program().err().unwrap(),
+            "maximum recursion depth exceeded: expressions nested more than 4096 levels deep (line 1, column 4097)"
+        );
+    }
 }
@@ -0,0 +1,264 @@
+use std::borrow::Cow;
+use std::io::IsTerminal;
+use std::ops::Range;
+
+use rustyline::completion::Completer;
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::Helper;
+
+use crate::lexer::lexer::Lexer;
+use crate::token::token::TokenType;
+
+const RESET: &str = "\x1b[0m";
+const KEYWORD: &str = "\x1b[1;35m";
+const IDENT: &str = "\x1b[36m";
+const INT: &str = "\x1b[33m";
+const STRING: &str = "\x1b[32m";
+const OPERATOR: &str = "\x1b[34m";
+const ILLEGAL: &str = "\x1b[1;37;41m";
+const MATCHED_BRACKET: &str = "\x1b[1;4;32m";
+
+// 只有 stdout 是终端并且没有设置 NO_COLOR 时才输出颜色
+pub fn color_enabled() -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    std::io::stdout().is_terminal() && !no_color
+}
+
+// 用 Lexer 对一行输入着色，pos 为光标所在的字节位置，用于高亮配对的括号
+pub fn highlight(line: &str, pos: usize) -> String {
+    let tokens = tokenize(line);
+    let pair = matching_brackets(&tokens, pos);
+    let mut out = String::with_capacity(line.len() * 2);
+    let mut last = 0;
+    for (i, (typ, span)) in tokens.iter().enumerate() {
+        out.push_str(&line[last..span.start]);
+        let color = match pair {
+            Some((open, close)) if i == open || i == close => Some(MATCHED_BRACKET),
+            _ => color_of(*typ),
+        };
+        match color {
+            Some(c) => {
+                out.push_str(c);
+                out.push_str(&line[span.clone()]);
+                out.push_str(RESET);
+            }
+            None => out.push_str(&line[span.clone()]),
+        }
+        last = span.end;
+    }
+    out.push_str(&line[last..]);
+    out
+}
+
+fn color_of(typ: TokenType) -> Option<&'static str> {
+    match typ {
+        TokenType::FUNCTION
+        | TokenType::LET
+        | TokenType::TRUE
+        | TokenType::FALSE
+        | TokenType::IF
+        | TokenType::ELSE
+        | TokenType::RETURN
+        | TokenType::WHILE
+        | TokenType::BREAK
+        | TokenType::CONTINUE
+        | TokenType::FOR
+        | TokenType::IN
+        | TokenType::CONST => Some(KEYWORD),
+        TokenType::IDENT => Some(IDENT),
+        TokenType::INT | TokenType::FLOAT => Some(INT),
+        TokenType::STRING
+        | TokenType::TEMPLATE_HEAD
+        | TokenType::TEMPLATE_MIDDLE
+        | TokenType::TEMPLATE_TAIL => Some(STRING),
+        TokenType::ILLEGAL => Some(ILLEGAL),
+        TokenType::ASSIGN
+        | TokenType::PLUS
+        | TokenType::MINUS
+        | TokenType::ASTERISK
+        | TokenType::SLASH
+        | TokenType::BANG
+        | TokenType::LT
+        | TokenType::GT
+        | TokenType::EQ
+        | TokenType::NOT_EQ
+        | TokenType::DOT_DOT
+        | TokenType::DOT_DOT_EQ
+        | TokenType::PLUS_ASSIGN
+        | TokenType::MINUS_ASSIGN
+        | TokenType::ASTERISK_ASSIGN
+        | TokenType::SLASH_ASSIGN
+        | TokenType::AND
+        | TokenType::OR
+        | TokenType::LT_EQ
+        | TokenType::GT_EQ
+        | TokenType::PERCENT
+        | TokenType::POWER
+        | TokenType::AMPERSAND
+        | TokenType::PIPE
+        | TokenType::CARET
+        | TokenType::TILDE
+        | TokenType::SHL
+        | TokenType::SHR => Some(OPERATOR),
+        _ => None,
+    }
+}
+
+fn tokenize(line: &str) -> Vec<(TokenType, Range<usize>)> {
+    let mut l = Lexer::new(line.to_string());
+    let mut ans: Vec<(TokenType, Range<usize>)> = Vec::new();
+    loop {
+        let (tk, mut span) = l.next_token_with_span();
+        if tk.typ == TokenType::EOF {
+            break;
+        }
+        // Lexer 按字节读取，非 ASCII 字符会拆成多个 ILLEGAL，这里合并成一个完整字符
+        if ans.last().is_some_and(|(_, last)| span.start < last.end) {
+            continue;
+        }
+        while !line.is_char_boundary(span.end) {
+            span.end += 1;
+        }
+        ans.push((tk.typ, span));
+    }
+    ans
+}
+
+fn bracket_pair(typ: TokenType) -> Option<(TokenType, TokenType)> {
+    match typ {
+        TokenType::LPAREN | TokenType::RPAREN => Some((TokenType::LPAREN, TokenType::RPAREN)),
+        TokenType::LBRACE | TokenType::RBRACE => Some((TokenType::LBRACE, TokenType::RBRACE)),
+        _ => None,
+    }
+}
+
+// 先看光标下的字符，再看光标前一个字符，找到括号后返回它和与之配对的括号的下标
+fn matching_brackets(tokens: &[(TokenType, Range<usize>)], pos: usize) -> Option<(usize, usize)> {
+    let cur = tokens
+        .iter()
+        .position(|(typ, span)| span.start == pos && bracket_pair(*typ).is_some())
+        .or_else(|| {
+            tokens
+                .iter()
+                .position(|(typ, span)| span.end == pos && bracket_pair(*typ).is_some())
+        })?;
+    let (open, close) = bracket_pair(tokens[cur].0)?;
+    let mut depth = 0;
+    if tokens[cur].0 == open {
+        for (i, (typ, _)) in tokens.iter().enumerate().skip(cur) {
+            if *typ == open {
+                depth += 1;
+            } else if *typ == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((cur, i));
+                }
+            }
+        }
+    } else {
+        for i in (0..=cur).rev() {
+            if tokens[i].0 == close {
+                depth += 1;
+            } else if tokens[i].0 == open {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((i, cur));
+                }
+            }
+        }
+    }
+    None
+}
+
+pub struct ReplHelper {
+    color: bool,
+}
+
+impl ReplHelper {
+    pub fn new(color: bool) -> ReplHelper {
+        ReplHelper { color }
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        if self.color {
+            Cow::Owned(highlight(line, pos))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    // 括号配对依赖光标位置，所以光标移动时也要重绘
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        self.color && kind != CmdKind::ForcedRefresh
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_tokens() {
+        let out = highlight("let x = 5 @ y;", 0);
+        assert_eq!(
+            out,
+            format!(
+                "{k}let{r} {i}x{r} {o}={r} {n}5{r} {e}@{r} {i}y{r};",
+                k = KEYWORD,
+                i = IDENT,
+                o = OPERATOR,
+                n = INT,
+                e = ILLEGAL,
+                r = RESET
+            )
+        );
+    }
+
+    #[test]
+    fn test_highlight_matching_brackets() {
+        let tokens = tokenize("f(a, (b))");
+        // 光标在第一个 ( 上
+        assert_eq!(matching_brackets(&tokens, 1), Some((1, 7)));
+        // 光标在最后一个 ) 之后
+        assert_eq!(matching_brackets(&tokens, 9), Some((1, 7)));
+        assert_eq!(matching_brackets(&tokens, 5), Some((4, 6)));
+        assert_eq!(matching_brackets(&tokens, 3), None);
+        assert_eq!(matching_brackets(&tokenize("(a"), 0), None);
+    }
+
+    #[test]
+    fn test_highlight_strings() {
+        let out = highlight("\"a${x}b\" \"é\"", 0);
+        assert_eq!(
+            out,
+            format!(
+                "{s}\"a${{{r}{i}x{r}{s}}}b\"{r} {s}\"é\"{r}",
+                s = STRING,
+                i = IDENT,
+                r = RESET
+            )
+        );
+    }
+
+    #[test]
+    fn test_highlight_non_ascii() {
+        let out = highlight("a é", 0);
+        assert_eq!(out, format!("{i}a{r} {e}é{r}", i = IDENT, e = ILLEGAL, r = RESET));
+    }
+}
@@ -1 +1,2 @@
+pub mod highlight;
 pub mod repl;
\ No newline at end of file
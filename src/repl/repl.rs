@@ -1,26 +1,394 @@
-use std::io::Write;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Instant;
 
-use crate::{ast::ast::Node, lexer, parser::parser, token::token};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
 
+use crate::code::disassembler;
+use crate::compiler::compiler::Compiler;
+use crate::evaluator::builtins;
+use crate::evaluator::evaluator::{self, Evaluator};
+use crate::evaluator::profile::{Profiler, Stats};
+use crate::object::environment::Environment;
+use crate::object::object::{is_error, Builtin, Error};
+use crate::{lexer, parser::parser};
+
+use super::highlight::{self, ReplHelper};
 
 const PROMPT: &str = ">> ";
 
-pub fn start() {
+pub struct Config {
+    pub prompt: String,
+    // 会话文件：启动时若存在则回放其中的输入，退出时把本次会话写回
+    pub session: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prompt: String::from(PROMPT),
+            session: None,
+        }
+    }
+}
+
+// 从任意 BufRead 读取输入、向任意 Write 输出，便于嵌入到其它程序或在测试中驱动
+pub fn start<R: BufRead, W: Write>(mut input: R, mut output: W, config: Config) -> io::Result<()> {
+    let mut session = Session::new();
+    session.open(&config, &mut output)?;
+    loop {
+        output.write_all(config.prompt.as_bytes())?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        if line.trim() == "exit" {
+            break;
+        }
+        session.eval_line(&line, &mut output)?;
+    }
+    session.close(&config, &mut output)
+}
+
+// 终端下的交互式 REPL，支持行编辑、历史记录和语法高亮
+pub fn start_terminal(config: Config) -> io::Result<()> {
+    let mut rl: Editor<ReplHelper, DefaultHistory> = Editor::new().map_err(io::Error::other)?;
+    rl.set_helper(Some(ReplHelper::new(highlight::color_enabled())));
+    let mut session = Session::new();
+    let mut output = io::stdout();
+    session.open(&config, &mut output)?;
     loop {
-        print!("{}", PROMPT);
-        std::io::stdout().flush().unwrap();
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        if input.trim() == "exit" {
+        let line = match rl.readline(&config.prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        if line.trim() == "exit" {
             break;
         }
-        let mut l = lexer::lexer::Lexer::new(input);
+        let _ = rl.add_history_entry(line.as_str());
+        session.eval_line(&line, &mut output)?;
+    }
+    session.close(&config, &mut output)
+}
+
+struct Session {
+    env: Rc<RefCell<Environment>>,
+    evaluator: Evaluator,
+    // puts 的输出先缓存在这里，每行执行完再写到 REPL 的输出中
+    printed: Rc<RefCell<Vec<u8>>>,
+    // 成功执行过的输入，:save 时按顺序写入文件
+    inputs: Vec<String>,
+    // 执行过的所有输入按行拆开，行号在整个会话中连续编号，用于在调用栈中显示源码
+    lines: Vec<String>,
+}
+
+impl Session {
+    fn new() -> Session {
+        let env = Rc::new(RefCell::new(Environment::new()));
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let buf = printed.clone();
+        env.borrow_mut().set(
+            String::from("puts"),
+            Rc::new(Builtin {
+                name: String::from("puts"),
+                func: Rc::new(move |args| {
+                    for arg in args {
+                        let _ = writeln!(buf.borrow_mut(), "{}", arg.inspect());
+                    }
+                    evaluator::null()
+                }),
+            }),
+        );
+        Session {
+            env,
+            evaluator: Evaluator::new(),
+            printed,
+            inputs: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    fn eval_line<W: Write>(&mut self, line: &str, output: &mut W) -> io::Result<()> {
+        let line = line.trim_start();
+        if let Some(cmd) = line.strip_prefix(':') {
+            let (name, arg) = match cmd.split_once(char::is_whitespace) {
+                Some((name, arg)) => (name, arg),
+                None => (cmd.trim_end(), ""),
+            };
+            match name {
+                "time" => self.time(arg, output)?,
+                "profile" => self.profile(arg, output)?,
+                "dis" => self.dis(arg, output)?,
+                "save" => match self.save(Path::new(arg.trim())) {
+                    Ok(()) => writeln!(output, "saved {} inputs", self.inputs.len())?,
+                    Err(e) => writeln!(output, "cannot save session: {}", e)?,
+                },
+                "restore" => match self.restore(Path::new(arg.trim())) {
+                    Ok(n) => writeln!(output, "restored {} inputs", n)?,
+                    Err(e) => writeln!(output, "cannot restore session: {}", e)?,
+                },
+                _ => writeln!(output, "unknown command: :{}", name)?,
+            }
+        } else {
+            self.run(line, output)?;
+        }
+        output.flush()
+    }
+
+    fn run<W: Write>(&mut self, source: &str, output: &mut W) -> io::Result<()> {
+        let l = lexer::lexer::Lexer::new_at_line(source.to_string(), self.lines.len() + 1);
+        self.lines.extend(source.lines().map(String::from));
         let mut p = parser::Parser::new(l);
-        let pgm = p.parse_program().unwrap();
-        println!("parsed {:?} {}", pgm, pgm.get_statements_len());
-        std::io::stdout().write(pgm.to_string().as_bytes());
-        std::io::stdout().write(b"\n");
-        std::io::stdout().flush();
+        match p.parse_program() {
+            Ok(pgm) => {
+                let evaluated = self.evaluator.eval(&pgm, &self.env);
+                output.write_all(&self.printed.borrow_mut().split_off(0))?;
+                writeln!(output, "{}", evaluated.inspect())?;
+                if let Some(e) = evaluated.downcast_ref::<Error>() {
+                    let lines: Vec<&str> = self.lines.iter().map(|s| s.as_str()).collect();
+                    write!(output, "{}", e.format_trace(&lines))?;
+                }
+                if !is_error(&evaluated) && !source.trim().is_empty() {
+                    self.inputs.push(source.trim().to_string());
+                }
+            }
+            Err(errors) => write_parser_errors(&errors, output)?,
+        }
+        Ok(())
+    }
+
+    // :dis <expr> 输出编译后的字节码，会话中已定义的名字当作全局变量处理
+    fn dis<W: Write>(&mut self, source: &str, output: &mut W) -> io::Result<()> {
+        let l = lexer::lexer::Lexer::new(source.to_string());
+        let pgm = match parser::Parser::new(l).parse_program() {
+            Ok(pgm) => pgm,
+            Err(errors) => return write_parser_errors(&errors, output),
+        };
+        let (mut symbol_table, constants) = Compiler::new().into_state();
+        for name in self.env.borrow().names() {
+            if !builtins::NAMES.contains(&name.as_str()) {
+                symbol_table.define(&name);
+            }
+        }
+        let mut compiler = Compiler::new_with_state(symbol_table, constants);
+        match compiler.compile(&pgm) {
+            Ok(()) => write!(output, "{}", disassembler::disassemble(&compiler.bytecode())),
+            Err(e) => writeln!(output, "compile error: {}", e),
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut content = String::new();
+        for input in self.inputs.iter() {
+            content.push_str(input);
+            content.push('\n');
+        }
+        fs::write(path, content)
+    }
+
+    // 在一个全新的会话里依次回放文件中的输入，返回成功执行的条数
+    fn restore(&mut self, path: &Path) -> io::Result<usize> {
+        let content = fs::read_to_string(path)?;
+        *self = Session::new();
+        for line in content.lines() {
+            self.run(line, &mut io::sink())?;
+        }
+        Ok(self.inputs.len())
     }
 
-}
\ No newline at end of file
+    fn open<W: Write>(&mut self, config: &Config, output: &mut W) -> io::Result<()> {
+        if let Some(path) = &config.session {
+            if path.exists() {
+                match self.restore(path) {
+                    Ok(n) => writeln!(output, "restored {} inputs from {}", n, path.display())?,
+                    Err(e) => writeln!(output, "cannot restore session: {}", e)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn close<W: Write>(&mut self, config: &Config, output: &mut W) -> io::Result<()> {
+        if let Some(path) = &config.session {
+            if let Err(e) = self.save(path) {
+                writeln!(output, "cannot save session: {}", e)?;
+            }
+        }
+        output.flush()
+    }
+
+    // :time <expr> 输出耗时、求值步数和分配的对象数
+    fn time<W: Write>(&mut self, source: &str, output: &mut W) -> io::Result<()> {
+        self.evaluator.stats = Stats::default();
+        let started = Instant::now();
+        self.run(source, output)?;
+        let elapsed = started.elapsed();
+        let stats = self.evaluator.stats;
+        writeln!(
+            output,
+            "time: {:?}, steps: {}, allocations: {}",
+            elapsed, stats.steps, stats.allocations
+        )
+    }
+
+    // :profile <expr> 输出每个函数的调用次数和累计耗时
+    fn profile<W: Write>(&mut self, source: &str, output: &mut W) -> io::Result<()> {
+        self.evaluator.profiler = Some(Profiler::new());
+        self.run(source, output)?;
+        let report = match self.evaluator.profiler.take() {
+            Some(p) => p.report(),
+            None => Vec::new(),
+        };
+        writeln!(output, "{:<20} {:>10} {:>14}", "function", "calls", "cumulative")?;
+        for f in report {
+            writeln!(
+                output,
+                "{:<20} {:>10} {:>14}",
+                f.name,
+                f.calls,
+                format!("{:?}", f.cumulative)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn write_parser_errors<W: Write>(errors: &str, output: &mut W) -> io::Result<()> {
+    for e in errors.lines() {
+        writeln!(output, "parser error: {}", e)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_session(input: &str) -> String {
+        let mut output = Vec::new();
+        let config = Config {
+            prompt: String::new(),
+            ..Config::default()
+        };
+        start(input.as_bytes(), &mut output, config).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let input = "let add = fn(a, b) { a + b };\nadd(1, 2)\nputs(add(3, 4))\n";
+        assert_eq!(run_session(input), "null\n3\n7\nnull\n");
+    }
+
+    #[test]
+    fn test_session_errors_and_exit() {
+        let input = "let x 5;\nx\nexit\n1\n";
+        assert_eq!(
+            run_session(input),
+            "parser error: expected next token to be ASSIGN, got INT instead\nERROR: identifier not found: x\n"
+        );
+    }
+
+    #[test]
+    fn test_session_stack_trace() {
+        // 函数在之前的输入中定义，调用栈中的行号按整个会话连续编号
+        let input = "let g = fn(x) { x + true };\nlet f = fn(x) { let y = g(x); y };\nfn() { f(1) + 1 }()\n";
+        assert_eq!(
+            run_session(input),
+            "null\nnull\nERROR: type mismatch: INTEGER + BOOLEAN
+  at g (line 2, column 26)
+    let f = fn(x) { let y = g(x); y };
+  at f (line 3, column 9)
+    fn() { f(1) + 1 }()
+  at <anonymous> (line 3, column 18)
+    fn() { f(1) + 1 }()
+"
+        );
+    }
+
+    #[test]
+    fn test_session_prompt() {
+        let mut output = Vec::new();
+        let config = Config {
+            prompt: String::from("monkey> "),
+            ..Config::default()
+        };
+        start("1\n".as_bytes(), &mut output, config).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "monkey> 1\nmonkey> ");
+    }
+
+    #[test]
+    fn test_session_time_and_profile() {
+        let input = "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };\n:time fib(5)\n:profile fib(5)\n:foo\n";
+        let out = run_session(input);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "null");
+        assert_eq!(lines[1], "5");
+        assert!(lines[2].starts_with("time: "), "{}", lines[2]);
+        assert!(lines[2].contains("steps: "), "{}", lines[2]);
+        assert!(lines[2].contains("allocations: "), "{}", lines[2]);
+        assert_eq!(lines[3], "5");
+        assert!(lines[4].starts_with("function"));
+        assert!(lines[5].starts_with("fib"));
+        assert!(lines[5].contains(" 15 "), "{}", lines[5]);
+        assert_eq!(lines[6], "unknown command: :foo");
+    }
+
+    #[test]
+    fn test_session_save_and_restore() {
+        let path = std::env::temp_dir().join(format!("rust-repl-session-{}", std::process::id()));
+        let input = format!(
+            "let add = fn(a, b) {{ a + b }};\nadd(1, true)\nlet three = add(1, 2);\n:save {}\n",
+            path.display()
+        );
+        let out = run_session(&input);
+        assert!(out.ends_with("saved 2 inputs\n"), "{}", out);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "let add = fn(a, b) { a + b };\nlet three = add(1, 2);\n"
+        );
+
+        let input = format!("let three = 0;\n:restore {}\nadd(three, 4)\n", path.display());
+        assert_eq!(run_session(&input), "null\nrestored 2 inputs\n7\n");
+
+        let mut output = Vec::new();
+        let config = Config {
+            prompt: String::new(),
+            session: Some(path.clone()),
+        };
+        start("let four = add(2, 2);\n".as_bytes(), &mut output, config).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("restored 2 inputs from {}\nnull\n", path.display())
+        );
+        assert!(fs::read_to_string(&path).unwrap().ends_with("let four = add(2, 2);\n"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_session_dis() {
+        let input = "let a = 1;\n:dis puts(a + 2)\n:dis b\n:dis let\n";
+        assert_eq!(
+            run_session(input),
+            "null
+== main ==
+0000 OpGetBuiltin 0           ; puts
+0002 OpGetGlobal 0
+0005 OpConstant 0             ; 2
+0008 OpAdd
+0009 OpCall 1
+0011 OpPop
+compile error: identifier not found: b (line 1, column 1)
+parser error: expected next token to be IDENT, got EOF instead
+"
+        );
+    }
+}
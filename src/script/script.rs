@@ -0,0 +1,664 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::ast::ast::Program;
+use crate::compiler::compiler::{Bytecode, Compiler};
+use crate::compiler::resolver::Resolver;
+use crate::evaluator::budget::{Budget, Limit};
+use crate::evaluator::evaluator::{Evaluator, MAX_CALL_DEPTH};
+use crate::lexer::lexer::Lexer;
+use crate::object::environment::Environment;
+use crate::object::object::{self, new_error, Builtin, Object, TraceFrame};
+use crate::optimizer::optimizer;
+use crate::parser::parser::{Parser, MAX_NESTING};
+use crate::vm::vm::{VmError, VM};
+
+fn write_lines(f: &mut fmt::Formatter<'_>, prefix: &str, msg: &str) -> fmt::Result {
+    for (i, line) in msg.lines().enumerate() {
+        if i > 0 {
+            writeln!(f)?;
+        }
+        write!(f, "{}: {}", prefix, line)?;
+    }
+    Ok(())
+}
+
+// 执行脚本的后端：树遍历解释器或字节码虚拟机
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Engine {
+    #[default]
+    Tree,
+    Vm,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Engine, String> {
+        match s {
+            "tree" => Ok(Engine::Tree),
+            "vm" => Ok(Engine::Vm),
+            _ => Err(format!("unknown engine: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScriptError {
+    Parse(String),
+    Compile(String),
+    Runtime(String),
+    // 超出执行预算或被取消
+    Budget(Limit),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Parse(msg) => write_lines(f, "parser error", msg),
+            ScriptError::Compile(msg) => write_lines(f, "compile error", msg),
+            ScriptError::Runtime(msg) => write!(f, "ERROR: {}", msg),
+            ScriptError::Budget(limit) => write!(f, "ERROR: budget exceeded: {}", limit),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub engine: Engine,
+    // 执行或编译前先对 AST 做常量折叠等优化
+    pub optimize: bool,
+    // 表达式的最大嵌套层数和运行时的最大调用深度
+    pub max_nesting: usize,
+    pub max_depth: usize,
+    pub budget: Budget,
+}
+
+impl Options {
+    pub fn new(engine: Engine) -> Options {
+        Options {
+            engine,
+            optimize: false,
+            max_nesting: MAX_NESTING,
+            max_depth: MAX_CALL_DEPTH,
+            budget: Budget::default(),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options::new(Engine::default())
+    }
+}
+
+// 脚本模式预先定义的全局变量，在虚拟机中按这个顺序占用全局变量的前几个下标
+const GLOBALS: [&str; 2] = ["argc", "arg"];
+
+// 执行一段脚本，args 是传给脚本的命令行参数，脚本里通过 argc() 和 arg(i) 访问
+pub fn run(source: &str, args: &[String], options: Options) -> Result<Rc<dyn Object>, ScriptError> {
+    let pgm = parse(source, &options)?;
+    let lines: Vec<&str> = source.lines().collect();
+    if options.engine == Engine::Vm {
+        return execute(compile_program(&pgm)?, args, options, &lines);
+    }
+    let env = Rc::new(RefCell::new(Environment::new()));
+    define_args(&env, args);
+    let mut evaluator = Evaluator::new();
+    evaluator.max_depth = options.max_depth;
+    evaluator.set_budget(options.budget);
+    let result = evaluator.eval(&pgm, &env);
+    if let Some(e) = result.downcast_ref::<object::BudgetExceeded>() {
+        return Err(ScriptError::Budget(e.limit));
+    }
+    match result.downcast_ref::<object::Error>() {
+        // 错误信息后面跟着调用栈
+        Some(e) => Err(runtime_error(&e.message, &e.trace, &lines)),
+        None => Ok(result),
+    }
+}
+
+// 在虚拟机上执行编译好的字节码，例如从 .mkc 文件读入的
+pub fn run_bytecode(
+    bytecode: Bytecode,
+    args: &[String],
+    options: Options,
+) -> Result<Rc<dyn Object>, ScriptError> {
+    execute(bytecode, args, options, &[])
+}
+
+// lines 是按行拆开的源码，用于在调用栈中显示调用处的代码，没有源码时为空
+fn execute(
+    bytecode: Bytecode,
+    args: &[String],
+    options: Options,
+    lines: &[&str],
+) -> Result<Rc<dyn Object>, ScriptError> {
+    let env = Rc::new(RefCell::new(Environment::new()));
+    define_args(&env, args);
+    let globals = GLOBALS
+        .iter()
+        .map(|name| env.borrow().get(name).unwrap())
+        .collect();
+    let mut vm = VM::new_with_globals(bytecode, globals);
+    vm.set_max_depth(options.max_depth);
+    vm.set_budget(options.budget);
+    match vm.run() {
+        Ok(()) => Ok(vm.last_popped_stack_elem()),
+        Err(VmError {
+            limit: Some(limit), ..
+        }) => Err(ScriptError::Budget(limit)),
+        Err(e) => Err(runtime_error(&e.to_string(), &e.trace, lines)),
+    }
+}
+
+fn runtime_error(message: &str, trace: &[TraceFrame], lines: &[&str]) -> ScriptError {
+    let message = format!("{}\n{}", message, object::format_trace(trace, lines));
+    ScriptError::Runtime(message.trim_end().to_string())
+}
+
+// 把脚本编译成虚拟机执行的字节码
+pub fn compile(source: &str, options: Options) -> Result<Bytecode, ScriptError> {
+    compile_program(&parse(source, &options)?)
+}
+
+fn parse(source: &str, options: &Options) -> Result<Program, ScriptError> {
+    let l = Lexer::new(strip_shebang(source));
+    let mut p = Parser::new(l);
+    p.set_max_depth(options.max_nesting);
+    let pgm = p.parse_program().map_err(ScriptError::Parse)?;
+    // 在执行之前报告未定义的名字和重复的参数名
+    let mut resolver = Resolver::new();
+    for name in GLOBALS {
+        resolver.define_global(name);
+    }
+    resolver.resolve(&pgm).map_err(ScriptError::Compile)?;
+    if options.optimize {
+        return Ok(optimizer::optimize(pgm));
+    }
+    Ok(pgm)
+}
+
+fn compile_program(pgm: &Program) -> Result<Bytecode, ScriptError> {
+    let (mut symbol_table, constants) = Compiler::new().into_state();
+    for name in GLOBALS {
+        symbol_table.define(name);
+    }
+    let mut compiler = Compiler::new_with_state(symbol_table, constants);
+    compiler.compile(pgm).map_err(ScriptError::Compile)?;
+    Ok(compiler.bytecode())
+}
+
+// 去掉 #! 开头的第一行，保留换行使后面的行号不变
+pub fn strip_shebang(source: &str) -> String {
+    if source.starts_with("#!") {
+        match source.find('\n') {
+            Some(i) => source[i..].to_string(),
+            None => String::new(),
+        }
+    } else {
+        source.to_string()
+    }
+}
+
+fn define_args(env: &Rc<RefCell<Environment>>, args: &[String]) {
+    let args = Rc::new(args.to_vec());
+    let argc = args.clone();
+    env.borrow_mut().set(
+        String::from("argc"),
+        Rc::new(Builtin {
+            name: String::from("argc"),
+            func: Rc::new(move |_| {
+                Rc::new(object::Integer {
+                    value: argc.len() as i64,
+                })
+            }),
+        }),
+    );
+    env.borrow_mut().set(
+        String::from("arg"),
+        Rc::new(Builtin {
+            name: String::from("arg"),
+            func: Rc::new(move |params| {
+                if params.len() != 1 {
+                    return new_error(format!(
+                        "wrong number of arguments: want=1, got={}",
+                        params.len()
+                    ));
+                }
+                let index = match params[0].downcast_ref::<object::Integer>() {
+                    Some(i) => i.value,
+                    None => {
+                        return new_error(format!(
+                            "argument to `arg` must be INTEGER, got {:?}",
+                            params[0].type_of()
+                        ))
+                    }
+                };
+                match usize::try_from(index).ok().and_then(|i| args.get(i)) {
+                    Some(v) => Rc::new(object::Str { value: v.clone() }),
+                    None => new_error(format!("arg index out of range: {}", index)),
+                }
+            }),
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::mkc;
+
+    // 两种引擎，每种分别关闭和开启优化
+    fn all_engines() -> Vec<Options> {
+        let mut all = Vec::new();
+        for engine in [Engine::Tree, Engine::Vm] {
+            for optimize in [false, true] {
+                all.push(Options {
+                    engine,
+                    optimize,
+                    ..Options::default()
+                });
+            }
+        }
+        all
+    }
+
+    #[test]
+    fn test_run_script() {
+        let source = "#!/usr/bin/env rust-repl\nlet add = fn(a, b) { a + b };\nadd(1, 2)";
+        for engine in [Engine::Tree, Engine::Vm] {
+            for optimize in [false, true] {
+                let result = run(
+                    source,
+                    &[],
+                    Options {
+                        engine,
+                        optimize,
+                        ..Options::default()
+                    },
+                )
+                .unwrap();
+                assert_eq!(result.inspect(), "3");
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_script_args() {
+        let args = vec![String::from("foo"), String::from("bar")];
+        for engine in [Engine::Tree, Engine::Vm] {
+            assert_eq!(
+                run("argc()", &args, Options::new(engine))
+                    .unwrap()
+                    .inspect(),
+                "2"
+            );
+            assert_eq!(
+                run("arg(1)", &args, Options::new(engine))
+                    .unwrap()
+                    .inspect(),
+                "bar"
+            );
+        }
+        assert_eq!(
+            run("arg(2)", &args, Options::new(Engine::Tree)).err(),
+            Some(ScriptError::Runtime(String::from(
+                "arg index out of range: 2\n  at arg (line 1, column 4)\n    arg(2)"
+            )))
+        );
+        assert_eq!(
+            run("arg(2)", &args, Options::new(Engine::Vm)).err(),
+            Some(ScriptError::Runtime(String::from(
+                "arg index out of range: 2 (line 1, column 4)\n  at arg (line 1, column 4)\n    arg(2)"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_run_forward_references() {
+        // 两种引擎对后面才定义的名字按同样的作用域规则解析
+        let source = "let f = fn() {\n  let i = 0; let out = 0;\n  while (i < 2) { if (i == 1) { out = g(b); } let b = i + 10; i += 1; }\n  out\n};\nlet g = fn(x) { x * 2 };\nf()";
+        for engine in [Engine::Tree, Engine::Vm] {
+            let result = run(source, &[], Options::new(engine)).unwrap();
+            assert_eq!(result.inspect(), "20", "{:?}", engine);
+        }
+    }
+
+    #[test]
+    fn test_run_captured_variables() {
+        let tests = [
+            (
+                "let g = fn() { let a = 1; let f = fn() { a }; a = 2; f() }; g()",
+                "2",
+            ),
+            ("let g = fn() { let f = fn() { b }; let b = 5; f() }; g()", "5"),
+            (
+                "let counter = fn() { let n = 0; fn() { n += 1; n } };\nlet c = counter(); c(); c() * 10 + counter()()",
+                "21",
+            ),
+        ];
+        for engine in [Engine::Tree, Engine::Vm] {
+            for (source, expected) in tests {
+                let result = run(source, &[], Options::new(engine)).unwrap();
+                assert_eq!(result.inspect(), expected, "{:?}: {}", engine, source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_script_errors() {
+        assert_eq!(
+            run("let x 5;", &[], Options::new(Engine::Tree)).err(),
+            Some(ScriptError::Parse(String::from(
+                "expected next token to be ASSIGN, got INT instead"
+            )))
+        );
+        for engine in [Engine::Tree, Engine::Vm] {
+            assert_eq!(
+                run("let f = fn(a, a) { a };\nf(b)", &[], Options::new(engine)).err(),
+                Some(ScriptError::Compile(String::from(
+                    "duplicate parameter name: a (line 1, column 15)\nidentifier not found: b (line 2, column 3)"
+                )))
+            );
+        }
+        assert_eq!(
+            run("1 + true", &[], Options::new(Engine::Tree)).err(),
+            Some(ScriptError::Runtime(String::from(
+                "type mismatch: INTEGER + BOOLEAN"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_run_stack_trace() {
+        let source = "let f = fn() { 1 / 0 };\nlet g = fn() { f() + 1 };\ng()";
+        let trace = "\n  at f (line 2, column 17)\n    let g = fn() { f() + 1 };\n  at g (line 3, column 2)\n    g()";
+        assert_eq!(
+            run(source, &[], Options::new(Engine::Tree)).err(),
+            Some(ScriptError::Runtime(format!("division by zero{}", trace)))
+        );
+        assert_eq!(
+            run(source, &[], Options::new(Engine::Vm)).err(),
+            Some(ScriptError::Runtime(format!(
+                "division by zero (line 1, column 18){}",
+                trace
+            )))
+        );
+    }
+
+    #[test]
+    fn test_run_optimized_errors() {
+        let source = "let f = fn(x) {\n  if (1 < 2) { x / (1 - 1) } else { 0 }\n};\nf(5)";
+        let options = Options {
+            engine: Engine::Tree,
+            optimize: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            run(source, &[], options).err(),
+            Some(ScriptError::Runtime(String::from(
+                "division by zero\n  at f (line 4, column 2)\n    f(5)"
+            )))
+        );
+        let options = Options {
+            engine: Engine::Vm,
+            optimize: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            run(source, &[], options).err(),
+            Some(ScriptError::Runtime(String::from(
+                "division by zero (line 2, column 18)\n  at f (line 4, column 2)\n    f(5)"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_run_limits() {
+        for engine in [Engine::Tree, Engine::Vm] {
+            let options = Options {
+                max_nesting: 10,
+                max_depth: 20,
+                ..Options::new(engine)
+            };
+            assert_eq!(
+                run("((((((((((((1))))))))))))", &[], options.clone()).err(),
+                Some(ScriptError::Parse(String::from(
+                    "maximum recursion depth exceeded: expressions nested more than 10 levels deep (line 1, column 11)"
+                )))
+            );
+            let err = run("let f = fn(n) { n * f(n - 1) };\nf(10)", &[], options)
+                .err()
+                .unwrap();
+            assert!(
+                err.to_string()
+                    .starts_with("ERROR: maximum recursion depth exceeded, call chain: f (x21)"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    // 递归的深度不受调用方线程栈大小的限制，默认大小的线程上也不会栈溢出
+    #[test]
+    fn test_run_small_stack() {
+        let run_on_small_stack = |source: String, options: Options| {
+            std::thread::Builder::new()
+                .stack_size(2 << 20)
+                .spawn(move || match run(&source, &[], options) {
+                    Ok(value) => value.inspect(),
+                    Err(e) => e.to_string(),
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+        let deep = "let d = fn(n) { if (n == 0) { 0 } else { 1 + d(n - 1) } };\nd(5000)";
+        let loops = format!(
+            "let x = 0;\n{}x = x + 1;{}\nx",
+            "while (x < 1) { ".repeat(1000),
+            " }".repeat(1000)
+        );
+        for engine in [Engine::Tree, Engine::Vm] {
+            for optimize in [false, true] {
+                let options = Options {
+                    optimize,
+                    max_nesting: usize::MAX,
+                    max_depth: 10000,
+                    ..Options::new(engine)
+                };
+                assert_eq!(
+                    run_on_small_stack(deep.to_string(), options.clone()),
+                    "5000"
+                );
+                assert_eq!(run_on_small_stack(loops.clone(), options.clone()), "1");
+                let source = format!("{}1{}", "(-".repeat(1000), ")".repeat(1000));
+                assert_eq!(run_on_small_stack(source, options.clone()), "1");
+
+                let err = run_on_small_stack(deep.to_string(), Options::new(engine));
+                assert!(
+                    err.starts_with("ERROR: maximum recursion depth exceeded, call chain: d"),
+                    "{}",
+                    err
+                );
+                let source = format!("{}1{}", "(".repeat(1000000), ")".repeat(1000000));
+                let err = run_on_small_stack(source, options);
+                assert!(
+                    err.contains("maximum recursion depth exceeded: expressions nested"),
+                    "{}",
+                    err
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_budget() {
+        let args = vec!["x".repeat(100)];
+        for engine in [Engine::Tree, Engine::Vm] {
+            let options = Options {
+                budget: Budget {
+                    max_steps: Some(10000),
+                    max_collection_size: Some(50),
+                    ..Budget::default()
+                },
+                ..Options::new(engine)
+            };
+            let err = run("let f = fn() { f() }; f()", &args, options.clone()).err();
+            assert_eq!(err, Some(ScriptError::Budget(Limit::Steps(10000))));
+            assert_eq!(
+                err.unwrap().to_string(),
+                "ERROR: budget exceeded: more than 10000 steps executed"
+            );
+            assert_eq!(
+                run("arg(0)", &args, options).err(),
+                Some(ScriptError::Budget(Limit::CollectionSize(50)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_loops() {
+        let source = "let f = fn(n) {\n  while (n > 0) {\n    if (n > 9) { break; }\n    return n;\n  }\n  -1\n};\nf(3) + f(10)";
+        for options in all_engines() {
+            assert_eq!(run(source, &[], options.clone()).unwrap().inspect(), "2");
+            assert_eq!(
+                run("if (true) { break; }", &[], options).err(),
+                Some(ScriptError::Parse(String::from(
+                    "break outside of a loop (line 1, column 13)"
+                )))
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_logical() {
+        let source = "let n = 0;\nlet hit = fn() { n += 1; true };\nlet a = false && hit();\nlet b = true || hit();\nlet c = 1 <= 2 && hit();\nif (!a && b && c) { n } else { -1 }";
+        for options in all_engines() {
+            assert_eq!(run(source, &[], options).unwrap().inspect(), "1");
+        }
+    }
+
+    #[test]
+    fn test_run_bitwise() {
+        let source = "let hash = fn(n) {\n  let h = 5381;\n  for (c in 0..n) { h = (h << 5) + h ^ c; }\n  h % 1000003\n};\nlet flags = 1 << 3 | 1;\nhash(3) + (flags & ~1) + 2 ** 10";
+        for options in all_engines() {
+            assert_eq!(
+                run(source, &[], options.clone()).unwrap().inspect(),
+                "377419"
+            );
+            // 两种引擎附带的位置信息格式不同，只比较错误本身
+            let err = run("let n = 3;\nn % (n - 3)", &[], options).err().unwrap();
+            assert!(
+                err.to_string().starts_with("ERROR: modulo by zero"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_big_integers() {
+        let source = "let total = 1;\nfor (i in 0..30) { total *= 10; }\ntotal + 1n - 100000000000000000000n";
+        for options in all_engines() {
+            assert_eq!(
+                run(source, &[], options.clone()).unwrap().inspect(),
+                "999999999900000000000000000001"
+            );
+            assert_eq!(
+                run("1 + 99999999999999999999", &[], options).err(),
+                Some(ScriptError::Parse(String::from(
+                    "integer literal out of range: 99999999999999999999 (line 1, column 5)"
+                )))
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_interpolation() {
+        let args = vec![String::from("monkey"), String::from("x")];
+        let source =
+            "let items = fn() { argc() * 2 };\n\"hello ${arg(0)}, you have ${items()} items\"";
+        for options in all_engines() {
+            assert_eq!(
+                run(source, &args, options.clone()).unwrap().inspect(),
+                "hello monkey, you have 4 items"
+            );
+            let limited = Options {
+                budget: Budget {
+                    max_collection_size: Some(10),
+                    ..Budget::default()
+                },
+                ..options
+            };
+            let err = run(
+                "let s = \"\"; for (i in 0..11) { s = \"${s}${i}\"; }",
+                &args,
+                limited,
+            )
+            .err();
+            assert_eq!(err, Some(ScriptError::Budget(Limit::CollectionSize(10))));
+        }
+    }
+
+    #[test]
+    fn test_run_floats() {
+        let args = vec![
+            String::from("2"),
+            String::from(" 3.5 "),
+            String::from("5e-1"),
+        ];
+        let source = "let sum = 0;\nfor (i in 0..argc()) { sum += float(arg(i)); }\nlet avg = sum / argc();\nint(avg * 10) + avg";
+        for options in all_engines() {
+            assert_eq!(
+                run(source, &args, options.clone()).unwrap().inspect(),
+                "22.0"
+            );
+            let err = run("int(arg(1))", &args, options).err().unwrap();
+            assert!(err
+                .to_string()
+                .starts_with("ERROR: cannot convert \" 3.5 \" to INTEGER"));
+        }
+    }
+
+    #[test]
+    fn test_run_for() {
+        let args = vec![String::from("héllo")];
+        let source = "let nth = fn(s, n) {\n  for (i, c in s) {\n    if (i == n) { return c; }\n  }\n};\nnth(arg(0), 1)";
+        for options in all_engines() {
+            assert_eq!(run(source, &args, options.clone()).unwrap().inspect(), "é");
+            assert_eq!(
+                run("for (c in arg(0)) { } c", &args, options)
+                    .unwrap()
+                    .inspect(),
+                "o"
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_mkc() {
+        let bytecode = compile(
+            "let add = fn(a, b) { a + b };\nadd(argc(), 2)",
+            Options::default(),
+        )
+        .unwrap();
+        let data = mkc::serialize(&bytecode).unwrap();
+        let loaded = mkc::deserialize(&data).unwrap();
+        let result = run_bytecode(loaded, &[String::from("x")], Options::default()).unwrap();
+        assert_eq!(result.inspect(), "3");
+    }
+
+    #[test]
+    fn test_engine_from_str() {
+        assert_eq!("vm".parse(), Ok(Engine::Vm));
+        assert_eq!("tree".parse(), Ok(Engine::Tree));
+        assert_eq!(
+            "jit".parse::<Engine>(),
+            Err(String::from("unknown engine: jit"))
+        );
+    }
+}
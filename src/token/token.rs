@@ -27,7 +27,37 @@ pub(crate) enum TokenType {
     ELSE,
     RETURN,
     SLASH,
-    ASTERISK
+    ASTERISK,
+    WHILE,
+    BREAK,
+    CONTINUE,
+    FOR,
+    IN,
+    DOT_DOT,
+    DOT_DOT_EQ,
+    CONST,
+    PLUS_ASSIGN,
+    MINUS_ASSIGN,
+    ASTERISK_ASSIGN,
+    SLASH_ASSIGN,
+    AND,
+    OR,
+    LT_EQ,
+    GT_EQ,
+    PERCENT,
+    POWER,
+    AMPERSAND,
+    PIPE,
+    CARET,
+    TILDE,
+    SHL,
+    SHR,
+    FLOAT,
+    STRING,
+    // 带插值的字符串按 ${ 和 } 切成几段："a${ 是开头，}b${ 是中间，}c" 是结尾
+    TEMPLATE_HEAD,
+    TEMPLATE_MIDDLE,
+    TEMPLATE_TAIL,
 }
 
 impl TokenType {
@@ -40,19 +70,40 @@ impl TokenType {
             "if" => TokenType::IF,
             "else" => TokenType::ELSE,
             "return" => TokenType::RETURN,
+            "while" => TokenType::WHILE,
+            "break" => TokenType::BREAK,
+            "continue" => TokenType::CONTINUE,
+            "for" => TokenType::FOR,
+            "in" => TokenType::IN,
+            "const" => TokenType::CONST,
             _ => TokenType::IDENT,
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(Eq, Debug, Clone)]
 pub struct Token {
     pub(crate) typ: TokenType,
     pub literal: String,
+    // token 在源码中的行号和列号，从 1 开始；不是由 Lexer 产生的 token 为 0
+    pub line: usize,
+    pub column: usize,
 }
 
 impl Token {
     pub(crate) fn new(typ: TokenType, literal: String) -> Token {
-        Token { typ, literal }
+        Token {
+            typ,
+            literal,
+            line: 0,
+            column: 0,
+        }
+    }
+}
+
+// 位置信息不参与比较
+impl PartialEq for Token {
+    fn eq(&self, other: &Token) -> bool {
+        self.typ == other.typ && self.literal == other.literal
     }
 }
@@ -0,0 +1,1202 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+
+use crate::code::code::{find_position, read_u16, Opcode, Position};
+use crate::compiler::compiler::Bytecode;
+use crate::evaluator::budget::{Budget, Limit, Meter};
+use crate::evaluator::builtins;
+use crate::evaluator::evaluator::{is_truthy, recursion_error, MAX_CALL_DEPTH};
+use crate::object::bigint;
+use crate::object::float;
+use crate::object::object::{self, Closure, CompiledFunction, Object, ObjectType, TraceFrame};
+
+// 栈的大小要能容纳调用深度达到上限时所有帧的参数和局部变量
+pub const STACK_SIZE: usize = 65536;
+
+// 运行时错误，position 是出错指令在源码中的位置，执行预算耗尽时 limit 是被超出的预算
+#[derive(Debug, PartialEq, Eq)]
+pub struct VmError {
+    pub message: String,
+    pub position: Option<Position>,
+    pub limit: Option<Limit>,
+    // 出错时的调用栈，最内层的调用在前，和树遍历解释器的一样
+    pub trace: Vec<TraceFrame>,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some(p) => write!(f, "{} (line {}, column {})", self.message, p.line, p.column),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+// 一次函数调用：ip 指向下一条要执行的指令，base_pointer 是局部变量在栈上的起点
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    base_pointer: usize,
+    // 调用处的位置，顶层代码所在的帧没有
+    site: Option<Position>,
+}
+
+pub struct VM {
+    constants: Vec<Rc<dyn Object>>,
+    stack: Vec<Rc<dyn Object>>,
+    // 还没有执行过赋值的全局变量是 None
+    globals: Vec<Option<Rc<dyn Object>>>,
+    global_names: Vec<String>,
+    frames: Vec<Frame>,
+    // 最大调用深度，不包括顶层代码所在的帧
+    max_depth: usize,
+    meter: Meter,
+    // 执行预算耗尽时记下被超出的预算，随错误一起返回
+    exceeded: Option<Limit>,
+    // 内置函数出错时记下它的调用帧，放在调用栈的最内层
+    failed_builtin: Option<TraceFrame>,
+    last_popped: Option<Rc<dyn Object>>,
+    true_obj: Rc<dyn Object>,
+    false_obj: Rc<dyn Object>,
+    null_obj: Rc<dyn Object>,
+}
+
+impl VM {
+    pub fn new(bytecode: Bytecode) -> VM {
+        VM::new_with_globals(bytecode, Vec::new())
+    }
+
+    // REPL 和脚本模式会预先放入全局变量，后续编译的代码按下标访问
+    pub fn new_with_globals(bytecode: Bytecode, globals: Vec<Rc<dyn Object>>) -> VM {
+        let main = CompiledFunction {
+            instructions: bytecode.instructions,
+            positions: bytecode.positions,
+            num_locals: 0,
+            num_params: 0,
+            name: None,
+            text: String::new(),
+        };
+        let closure = Rc::new(Closure {
+            func: Rc::new(main),
+            free: Vec::new(),
+        });
+        VM {
+            constants: bytecode.constants,
+            stack: Vec::new(),
+            globals: globals.into_iter().map(Some).collect(),
+            global_names: bytecode.global_names,
+            frames: vec![Frame {
+                closure,
+                ip: 0,
+                base_pointer: 0,
+                site: None,
+            }],
+            max_depth: MAX_CALL_DEPTH,
+            meter: Meter::default(),
+            exceeded: None,
+            failed_builtin: None,
+            last_popped: None,
+            true_obj: Rc::new(object::Boolean { value: true }),
+            false_obj: Rc::new(object::Boolean { value: false }),
+            null_obj: Rc::new(object::Null {}),
+        }
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.meter = Meter::new(budget);
+    }
+
+    pub fn global(&self, index: usize) -> Option<Rc<dyn Object>> {
+        self.globals.get(index).cloned().flatten()
+    }
+
+    // 最后一个被弹出栈的值，也就是程序最后一个表达式语句的结果
+    pub fn last_popped_stack_elem(&self) -> Rc<dyn Object> {
+        match &self.last_popped {
+            Some(obj) => obj.clone(),
+            None => self.null_obj.clone(),
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), VmError> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let func = frame.closure.func.clone();
+            let ins = &func.instructions;
+            let ip = frame.ip;
+            if ip >= ins.len() {
+                return Ok(());
+            }
+            let op = match Opcode::from_u8(ins[ip]) {
+                Some(op) => op,
+                None => {
+                    return Err(VmError {
+                        message: format!("unknown opcode: {}", ins[ip]),
+                        position: find_position(&func.positions, ip),
+                        limit: None,
+                        trace: self.trace(),
+                    })
+                }
+            };
+            // 跳转目标不在指令开头时，操作数可能超出指令序列的末尾
+            let def = op.definition();
+            if ip + 1 + def.operand_widths.iter().sum::<usize>() > ins.len() {
+                return Err(VmError {
+                    message: format!("truncated {} at offset {}", def.name, ip),
+                    position: find_position(&func.positions, ip),
+                    limit: None,
+                    trace: self.trace(),
+                });
+            }
+            let mut operands = [0usize; 2];
+            let mut offset = ip + 1;
+            for (i, width) in def.operand_widths.iter().enumerate() {
+                operands[i] = match width {
+                    2 => read_u16(&ins[offset..]) as usize,
+                    _ => ins[offset] as usize,
+                };
+                offset += width;
+            }
+            frame.ip = offset;
+            let result = match self.meter.tick() {
+                Ok(()) => self.execute(op, operands),
+                Err(limit) => {
+                    self.exceeded = Some(limit);
+                    Err(format!("budget exceeded: {}", limit))
+                }
+            };
+            if let Err(message) = result {
+                return Err(VmError {
+                    message,
+                    position: find_position(&func.positions, ip),
+                    limit: self.exceeded.take(),
+                    trace: self.trace(),
+                });
+            }
+        }
+    }
+
+    fn execute(&mut self, op: Opcode, operands: [usize; 2]) -> Result<(), String> {
+        match op {
+            Opcode::OpConstant => self.push(self.constants[operands[0]].clone())?,
+            Opcode::OpPop => {
+                self.last_popped = Some(self.pop()?);
+            }
+            Opcode::OpAdd
+            | Opcode::OpSub
+            | Opcode::OpMul
+            | Opcode::OpDiv
+            | Opcode::OpEqual
+            | Opcode::OpNotEqual
+            | Opcode::OpGreaterThan
+            | Opcode::OpLessThan
+            | Opcode::OpLessEqual
+            | Opcode::OpGreaterEqual
+            | Opcode::OpMod
+            | Opcode::OpPow
+            | Opcode::OpBitAnd
+            | Opcode::OpBitOr
+            | Opcode::OpBitXor
+            | Opcode::OpShl
+            | Opcode::OpShr
+            | Opcode::OpRange
+            | Opcode::OpRangeInclusive => {
+                let right = self.pop()?;
+                let left = self.pop()?;
+                let result = self.execute_binary_operation(op, left, right)?;
+                self.push(result)?;
+            }
+            Opcode::OpTrue => self.push(self.true_obj.clone())?,
+            Opcode::OpFalse => self.push(self.false_obj.clone())?,
+            Opcode::OpNull => self.push(self.null_obj.clone())?,
+            Opcode::OpMinus => {
+                let right = self.pop()?;
+                match right.downcast_ref::<object::Integer>() {
+                    Some(i) => match i.value.checked_neg() {
+                        Some(value) => self.push(Rc::new(object::Integer { value }))?,
+                        None => self.push(bigint::normalize(-BigInt::from(i.value)))?,
+                    },
+                    None => match (
+                        right.downcast_ref::<object::Float>(),
+                        bigint::to_bigint(&right),
+                    ) {
+                        (Some(f), _) => self.push(Rc::new(object::Float { value: -f.value }))?,
+                        (_, Some(value)) => self.push(bigint::normalize(-value))?,
+                        _ => return Err(format!("unknown operator: -{:?}", right.type_of())),
+                    },
+                }
+            }
+            Opcode::OpBitNot => {
+                let right = self.pop()?;
+                match bigint::to_bigint(&right) {
+                    Some(value) => self.push(bigint::normalize(!value))?,
+                    None => return Err(format!("unknown operator: ~{:?}", right.type_of())),
+                }
+            }
+            Opcode::OpTemplate => {
+                let parts = self.pop_n(operands[0])?;
+                let value: String = parts.iter().map(|p| p.inspect()).collect();
+                let result: Rc<dyn Object> = Rc::new(object::Str { value });
+                if let Err(limit) = self.meter.check_size(&result) {
+                    self.exceeded = Some(limit);
+                    return Err(format!("budget exceeded: {}", limit));
+                }
+                self.push(result)?;
+            }
+            Opcode::OpBang => {
+                let right = self.pop()?;
+                self.push(self.native_bool(!is_truthy(&right)))?;
+            }
+            Opcode::OpJumpNotTruthy => {
+                let condition = self.pop()?;
+                if !is_truthy(&condition) {
+                    self.current_frame().ip = operands[0];
+                }
+            }
+            Opcode::OpJump => self.current_frame().ip = operands[0],
+            Opcode::OpGetGlobal => match self.global(operands[0]) {
+                Some(val) => self.push(val)?,
+                // 例如只在没有执行的分支里定义的变量
+                None => {
+                    let name = match self.global_names.get(operands[0]) {
+                        Some(name) => name.clone(),
+                        None => format!("<global {}>", operands[0]),
+                    };
+                    return Err(format!("identifier not found: {}", name));
+                }
+            },
+            Opcode::OpSetGlobal => {
+                let val = self.pop()?;
+                let index = operands[0];
+                if index >= self.globals.len() {
+                    self.globals.resize(index + 1, None);
+                }
+                self.globals[index] = Some(val);
+            }
+            Opcode::OpGetLocal => {
+                let val = self.local(operands[0])?.clone();
+                self.push(val)?;
+            }
+            Opcode::OpSetLocal => {
+                let val = self.pop()?;
+                *self.local(operands[0])? = val;
+            }
+            Opcode::OpGetBuiltin => match builtins::get(builtins::NAMES[operands[0]]) {
+                Some(builtin) => self.push(builtin)?,
+                None => return Err(format!("unknown builtin: {}", operands[0])),
+            },
+            Opcode::OpGetFree => {
+                let val = match self.current_frame().closure.free.get(operands[0]) {
+                    Some(val) => val.clone(),
+                    None => return Err(format!("invalid free variable: {}", operands[0])),
+                };
+                self.push(val)?;
+            }
+            Opcode::OpNewCell => {
+                let local = self.local(operands[0])?;
+                let value = local.clone();
+                *local = Rc::new(object::Cell {
+                    value: RefCell::new(value),
+                });
+            }
+            Opcode::OpDeref => {
+                let cell = self.pop()?;
+                let value = cell_of(&cell)?.value.borrow().clone();
+                self.push(value)?;
+            }
+            Opcode::OpSetCell => {
+                let cell = self.pop()?;
+                let value = self.pop()?;
+                *cell_of(&cell)?.value.borrow_mut() = value;
+            }
+            Opcode::OpClosure => self.push_closure(operands[0], operands[1])?,
+            Opcode::OpCall => self.call_function(operands[0])?,
+            Opcode::OpTailCall => self.tail_call(operands[0])?,
+            Opcode::OpReturnValue => {
+                let val = self.pop()?;
+                self.return_from_frame(val);
+            }
+            Opcode::OpReturn => self.return_from_frame(self.null_obj.clone()),
+            Opcode::OpIter => {
+                let iterable = self.pop()?;
+                match iterable.iter() {
+                    Some(entries) => self.push(Rc::new(object::Iter {
+                        entries: RefCell::new(entries),
+                    }))?,
+                    None => return Err(format!("not iterable: {:?}", iterable.type_of())),
+                }
+            }
+            Opcode::OpIterNext => {
+                let iter = self.peek(0)?;
+                let next = match iter.downcast_ref::<object::Iter>() {
+                    Some(iter) => iter.entries.borrow_mut().next(),
+                    None => return Err(format!("not an iterator: {:?}", iter.type_of())),
+                };
+                match next {
+                    Some((key, value)) => {
+                        if operands[1] == 2 {
+                            self.push(key)?;
+                        }
+                        self.push(value)?;
+                    }
+                    None => self.current_frame().ip = operands[0],
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_binary_operation(
+        &self,
+        op: Opcode,
+        left: Rc<dyn Object>,
+        right: Rc<dyn Object>,
+    ) -> Result<Rc<dyn Object>, String> {
+        if let (Some(l), Some(r)) = (
+            left.downcast_ref::<object::Integer>(),
+            right.downcast_ref::<object::Integer>(),
+        ) {
+            let (l, r) = (l.value, r.value);
+            let value = match op {
+                Opcode::OpAdd => l.checked_add(r),
+                Opcode::OpSub => l.checked_sub(r),
+                Opcode::OpMul => l.checked_mul(r),
+                Opcode::OpDiv => {
+                    if r == 0 {
+                        return Err(String::from("division by zero"));
+                    }
+                    l.checked_div(r)
+                }
+                Opcode::OpMod => {
+                    if r == 0 {
+                        return Err(String::from("modulo by zero"));
+                    }
+                    Some(l.wrapping_rem(r))
+                }
+                Opcode::OpPow => match u32::try_from(r) {
+                    Ok(exp) => l.checked_pow(exp),
+                    Err(_) => return Err(format!("exponent out of range: {}", r)),
+                },
+                Opcode::OpBitAnd => Some(l & r),
+                Opcode::OpBitOr => Some(l | r),
+                Opcode::OpBitXor => Some(l ^ r),
+                Opcode::OpShl | Opcode::OpShr => {
+                    if !(0..64).contains(&r) {
+                        return Err(format!("shift amount out of range: {}", r));
+                    }
+                    if op == Opcode::OpShl {
+                        bigint::checked_shl(l, r)
+                    } else {
+                        Some(l >> r)
+                    }
+                }
+                Opcode::OpEqual => return Ok(self.native_bool(l == r)),
+                Opcode::OpNotEqual => return Ok(self.native_bool(l != r)),
+                Opcode::OpGreaterThan => return Ok(self.native_bool(l > r)),
+                Opcode::OpLessEqual => return Ok(self.native_bool(l <= r)),
+                Opcode::OpGreaterEqual => return Ok(self.native_bool(l >= r)),
+                Opcode::OpRange | Opcode::OpRangeInclusive => {
+                    return Ok(Rc::new(object::Range {
+                        start: l,
+                        end: r,
+                        inclusive: op == Opcode::OpRangeInclusive,
+                    }))
+                }
+                _ => return Ok(self.native_bool(l < r)),
+            };
+            return match value {
+                Some(value) => Ok(Rc::new(object::Integer { value })),
+                // 溢出时换成大整数重新计算
+                None => self.big_integer_operation(op, &l.into(), &r.into()),
+            };
+        }
+        // 有一边是小数时按小数计算
+        if left.type_of() == ObjectType::FLOAT || right.type_of() == ObjectType::FLOAT {
+            if let (Some(l), Some(r)) = (float::to_f64(&left), float::to_f64(&right)) {
+                if let Some(result) = self.float_operation(op, l, r) {
+                    return result;
+                }
+                return Err(format!(
+                    "unknown operator: {:?} {} {:?}",
+                    left.type_of(),
+                    operator(op),
+                    right.type_of()
+                ));
+            }
+        }
+        // 至少一边是大整数
+        if let (Some(l), Some(r)) = (bigint::to_bigint(&left), bigint::to_bigint(&right)) {
+            return self.big_integer_operation(op, &l, &r);
+        }
+        if let (Some(l), Some(r)) = (
+            left.downcast_ref::<object::Boolean>(),
+            right.downcast_ref::<object::Boolean>(),
+        ) {
+            match op {
+                Opcode::OpEqual => return Ok(self.native_bool(l.value == r.value)),
+                Opcode::OpNotEqual => return Ok(self.native_bool(l.value != r.value)),
+                _ => {}
+            }
+        }
+        // 错误信息与树遍历解释器保持一致
+        let kind = if left.type_of() != right.type_of() {
+            "type mismatch"
+        } else {
+            "unknown operator"
+        };
+        Err(format!(
+            "{}: {:?} {} {:?}",
+            kind,
+            left.type_of(),
+            operator(op),
+            right.type_of()
+        ))
+    }
+
+    // 小数不支持的运算返回 None
+    fn float_operation(
+        &self,
+        op: Opcode,
+        l: f64,
+        r: f64,
+    ) -> Option<Result<Rc<dyn Object>, String>> {
+        let operator = operator(op);
+        if let Some(b) = float::compare(operator, l, r) {
+            return Some(Ok(self.native_bool(b)));
+        }
+        let result = float::infix(operator, l, r)?;
+        Some(result.map(|value| Rc::new(object::Float { value }) as Rc<dyn Object>))
+    }
+
+    fn big_integer_operation(
+        &self,
+        op: Opcode,
+        l: &BigInt,
+        r: &BigInt,
+    ) -> Result<Rc<dyn Object>, String> {
+        let operator = operator(op);
+        match bigint::compare(operator, l, r) {
+            Some(b) => Ok(self.native_bool(b)),
+            None => bigint::infix(operator, l, r).map(bigint::normalize),
+        }
+    }
+
+    fn push_closure(&mut self, index: usize, num_free: usize) -> Result<(), String> {
+        let func = match self.constants[index]
+            .clone()
+            .downcast_rc::<CompiledFunction>()
+        {
+            Ok(func) => func,
+            Err(obj) => return Err(format!("not a function: {:?}", obj.type_of())),
+        };
+        let free = self.pop_n(num_free)?;
+        self.push(Rc::new(Closure { func, free }))
+    }
+
+    fn call_function(&mut self, num_args: usize) -> Result<(), String> {
+        let callee = self.peek(num_args)?;
+        if let Ok(closure) = callee.clone().downcast_rc::<Closure>() {
+            if closure.func.num_params != num_args {
+                return Err(format!(
+                    "wrong number of arguments: want={}, got={}",
+                    closure.func.num_params, num_args
+                ));
+            }
+            if self.frames.len() > self.max_depth {
+                let calls: Vec<String> = self.frames[1..]
+                    .iter()
+                    .map(|f| function_name(&f.closure.func))
+                    .collect();
+                return Err(recursion_error(&calls, &function_name(&closure.func)));
+            }
+            let base_pointer = self.stack.len() - num_args;
+            for _ in num_args..closure.func.num_locals {
+                self.push(self.null_obj.clone())?;
+            }
+            let site = self.call_site();
+            self.frames.push(Frame {
+                closure,
+                ip: 0,
+                base_pointer,
+                site,
+            });
+            Ok(())
+        } else if let Some(b) = callee.downcast_ref::<object::Builtin>() {
+            let args = self.pop_n(num_args)?;
+            self.pop()?;
+            let result = (b.func)(args);
+            if let Some(e) = result.downcast_ref::<object::Error>() {
+                self.failed_builtin = Some(trace_frame(b.name.clone(), self.call_site()));
+                return Err(e.message.clone());
+            }
+            if let Err(limit) = self.meter.check_size(&result) {
+                self.exceeded = Some(limit);
+                return Err(format!("budget exceeded: {}", limit));
+            }
+            self.push(result)
+        } else {
+            Err(format!("not a function: {:?}", callee.type_of()))
+        }
+    }
+
+    // 尾调用：把被调函数和参数移到当前帧的位置，复用当前帧执行被调函数，调用栈不会增长
+    fn tail_call(&mut self, num_args: usize) -> Result<(), String> {
+        let callee = self.peek(num_args)?;
+        let closure = match callee.clone().downcast_rc::<Closure>() {
+            Ok(closure) => closure,
+            Err(_) => {
+                // 内置函数没有调用帧，调用后直接返回结果
+                self.call_function(num_args)?;
+                let val = self.pop()?;
+                self.return_from_frame(val);
+                return Ok(());
+            }
+        };
+        if closure.func.num_params != num_args {
+            return Err(format!(
+                "wrong number of arguments: want={}, got={}",
+                closure.func.num_params, num_args
+            ));
+        }
+        // 顶层代码没有可以复用的帧，只有不合法的 .mkc 文件会在这里出现尾调用
+        if self.frames.len() == 1 {
+            return self.call_function(num_args);
+        }
+        let base_pointer = self.current_frame().base_pointer;
+        let start = self.stack.len() - 1 - num_args;
+        let moved: Vec<Rc<dyn Object>> = self.stack.drain(start..).collect();
+        self.stack.truncate(base_pointer - 1);
+        self.stack.extend(moved);
+        for _ in num_args..closure.func.num_locals {
+            self.push(self.null_obj.clone())?;
+        }
+        // 和树遍历解释器一样，复用的帧记下最后一次调用的位置
+        let site = self.call_site();
+        let frame = self.current_frame();
+        frame.closure = closure;
+        frame.ip = 0;
+        frame.site = site;
+        Ok(())
+    }
+
+    // 顶层的 return 直接结束程序，返回值作为程序的结果
+    fn return_from_frame(&mut self, val: Rc<dyn Object>) {
+        if self.frames.len() == 1 {
+            let frame = self.current_frame();
+            frame.ip = frame.closure.func.instructions.len();
+            self.last_popped = Some(val);
+            return;
+        }
+        let frame = self.frames.pop().unwrap();
+        // 同时弹出参数、局部变量和被调用的函数本身
+        self.stack.truncate(frame.base_pointer - 1);
+        self.stack.push(val);
+    }
+
+    // 刚执行的调用指令在源码中的位置，调用指令是 1 字节的操作码加 1 字节的参数个数
+    fn call_site(&self) -> Option<Position> {
+        let frame = self.frames.last().unwrap();
+        find_position(&frame.closure.func.positions, frame.ip - 2)
+    }
+
+    fn trace(&mut self) -> Vec<TraceFrame> {
+        let frames = self.frames[1..]
+            .iter()
+            .rev()
+            .map(|f| trace_frame(function_name(&f.closure.func), f.site));
+        self.failed_builtin
+            .take()
+            .into_iter()
+            .chain(frames)
+            .collect()
+    }
+
+    fn current_frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn push(&mut self, obj: Rc<dyn Object>) -> Result<(), String> {
+        if self.stack.len() >= STACK_SIZE {
+            return Err(String::from("stack overflow"));
+        }
+        self.stack.push(obj);
+        Ok(())
+    }
+
+    // 栈上的值和局部变量的下标都来自字节码，不合法的 .mkc 文件可能越界，这时返回错误
+    fn pop(&mut self) -> Result<Rc<dyn Object>, String> {
+        self.stack.pop().ok_or_else(stack_underflow)
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Rc<dyn Object>>, String> {
+        match self.stack.len().checked_sub(n) {
+            Some(start) => Ok(self.stack.split_off(start)),
+            None => Err(stack_underflow()),
+        }
+    }
+
+    // 栈顶往下第 distance 个值
+    fn peek(&self, distance: usize) -> Result<Rc<dyn Object>, String> {
+        match self.stack.len().checked_sub(distance + 1) {
+            Some(i) => Ok(self.stack[i].clone()),
+            None => Err(stack_underflow()),
+        }
+    }
+
+    fn local(&mut self, index: usize) -> Result<&mut Rc<dyn Object>, String> {
+        let slot = self.current_frame().base_pointer + index;
+        self.stack
+            .get_mut(slot)
+            .ok_or_else(|| format!("invalid local variable: {}", index))
+    }
+
+    fn native_bool(&self, value: bool) -> Rc<dyn Object> {
+        if value {
+            self.true_obj.clone()
+        } else {
+            self.false_obj.clone()
+        }
+    }
+}
+
+fn operator(op: Opcode) -> &'static str {
+    match op {
+        Opcode::OpAdd => "+",
+        Opcode::OpSub => "-",
+        Opcode::OpMul => "*",
+        Opcode::OpDiv => "/",
+        Opcode::OpEqual => "==",
+        Opcode::OpNotEqual => "!=",
+        Opcode::OpGreaterThan => ">",
+        Opcode::OpLessEqual => "<=",
+        Opcode::OpGreaterEqual => ">=",
+        Opcode::OpMod => "%",
+        Opcode::OpPow => "**",
+        Opcode::OpBitAnd => "&",
+        Opcode::OpBitOr => "|",
+        Opcode::OpBitXor => "^",
+        Opcode::OpShl => "<<",
+        Opcode::OpShr => ">>",
+        Opcode::OpRange => "..",
+        Opcode::OpRangeInclusive => "..=",
+        _ => "<",
+    }
+}
+
+fn function_name(func: &CompiledFunction) -> String {
+    match &func.name {
+        Some(name) => name.clone(),
+        None => String::from("<anonymous>"),
+    }
+}
+
+fn stack_underflow() -> String {
+    String::from("stack underflow")
+}
+
+fn cell_of(obj: &Rc<dyn Object>) -> Result<&object::Cell, String> {
+    obj.downcast_ref::<object::Cell>()
+        .ok_or_else(|| format!("not a cell: {:?}", obj.type_of()))
+}
+
+fn trace_frame(function: String, site: Option<Position>) -> TraceFrame {
+    let (line, column) = site.map_or((0, 0), |p| (p.line, p.column));
+    TraceFrame {
+        function,
+        line,
+        column,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::code::make;
+    use crate::compiler::compiler::Compiler;
+    use crate::evaluator::budget::CancelHandle;
+    use crate::lexer::lexer::Lexer;
+    use crate::parser::parser::Parser;
+
+    fn run(input: &str) -> Result<Rc<dyn Object>, VmError> {
+        let mut p = Parser::new(Lexer::new(input.to_string()));
+        let pgm = p.parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile(&pgm).unwrap();
+        let mut vm = VM::new(compiler.bytecode());
+        vm.run()?;
+        Ok(vm.last_popped_stack_elem())
+    }
+
+    fn assert_results(tests: &[(&str, &str)]) {
+        for (input, expected) in tests {
+            match run(input) {
+                Ok(result) => assert_eq!(result.inspect(), *expected, "{}", input),
+                Err(e) => panic!("{}: {}", input, e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_and_conditionals() {
+        assert_results(&[
+            ("1 + 2 * 3", "7"),
+            ("(5 + 10 * 2 + 15 / 3) * 2 + -10", "50"),
+            ("-9223372036854775807 - 1", "-9223372036854775808"),
+            ("1 < 2 == true", "true"),
+            ("!!5", "true"),
+            ("true != false", "true"),
+            ("if (1 > 2) { 10 }", "null"),
+            ("if (false) { 10 } else { 20 }", "20"),
+            ("let a = 1; let b = a + 1; b * 3", "6"),
+            ("return 5; 10", "5"),
+            ("17 % 5 + -7 % 3", "1"),
+            ("2 ** 3 ** 2", "512"),
+            ("-2 ** 2", "-4"),
+            ("6 & 3 | 8 ^ 1", "11"),
+            ("~5", "-6"),
+            ("1 << 62 >> 60", "4"),
+            ("-9223372036854775807 - 2", "-9223372036854775809"),
+            (
+                "let m = -9223372036854775807 - 1; -m",
+                "9223372036854775808",
+            ),
+            (
+                "let m = -9223372036854775807 - 1; m / -1",
+                "9223372036854775808",
+            ),
+            ("3037000500 * 3037000500 - 3037000500 * 3037000500", "0"),
+            ("~18446744073709551616n ^ 1", "-18446744073709551618"),
+            ("2 ** 64 >= 18446744073709551616n", "true"),
+            ("(1 << 62) << 2", "18446744073709551616"),
+            ("let n = 63; 1 << n", "9223372036854775808"),
+            ("let big = 2 ** 70; big / 2 ** 10 == 2 ** 60", "true"),
+            (
+                "let h = 5381; h = (h << 5) + h ^ 97; h & 4294967295",
+                "177604",
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_results(&[
+            (r#""hello""#, "hello"),
+            (
+                r#"let name = "monkey"; let n = 3; "hello ${name}, you have ${n * 2} items""#,
+                "hello monkey, you have 6 items",
+            ),
+            (r#""${"a${"b${1 + 1}"}c"}""#, "ab2c"),
+            (
+                r#""${fn(x) { x }(1.5)} ${true} ${if (false) { 1 }}""#,
+                "1.5 true null",
+            ),
+            (
+                r#"let s = ""; for (c in "abc") { s = "${c}${s}"; } s"#,
+                "cba",
+            ),
+            (r#"let f = fn(n) { "<${n}>" }; "${f(1)}${f(2)}""#, "<1><2>"),
+        ]);
+    }
+
+    #[test]
+    fn test_floats() {
+        assert_results(&[
+            ("0.1 + 0.2", "0.30000000000000004"),
+            ("1 + .5", "1.5"),
+            ("7 / 2.0", "3.5"),
+            ("-2.5 * 2", "-5.0"),
+            ("7.5 % 2", "1.5"),
+            ("1 == 1.0", "true"),
+            ("1.5 < 2 && 2 ** 64 > 1e18", "true"),
+            ("int(-2.7) + int(2.7)", "0"),
+            ("float(1) / 4", "0.25"),
+            ("let f = fn(x) { x * 1.5 }; f(2)", "3.0"),
+        ]);
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        assert_results(&[
+            ("2 <= 2", "true"),
+            ("3 >= 4", "false"),
+            ("true && 0", "true"),
+            ("1 < 2 && 2 < 1", "false"),
+            ("false || 1 >= 1", "true"),
+            ("false || false", "false"),
+            ("let n = 0; false && (n = 1); true || (n = 2); n", "0"),
+            ("let n = 0; true && (n = 1); false || (n += 2); n", "3"),
+            (
+                "let f = fn(x) { x > 0 && x < 10 || x == 42 }; f(5) && f(42) && !f(11)",
+                "true",
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_results(&[
+            ("while (false) { 10 }", "null"),
+            ("while (true) { break; 10 } 5", "5"),
+            ("let f = fn(n) { while (n > 0) { return n * 2; } 0 }; f(3) + f(0)", "6"),
+            (
+                "let f = fn(n) { while (true) { if (n > 2) { break; } return n; } 10 }; f(1) + f(5)",
+                "11",
+            ),
+            ("let f = fn() { while (false) { } }; f()", "null"),
+            ("if (true) { while (true) { break } }", "null"),
+        ]);
+
+        let pgm = Parser::new(Lexer::new(String::from(
+            "while (true) { continue; return 1; }",
+        )))
+        .parse_program()
+        .unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile(&pgm).unwrap();
+        let mut vm = VM::new(compiler.bytecode());
+        vm.set_budget(Budget {
+            max_steps: Some(1000),
+            ..Budget::default()
+        });
+        assert_eq!(vm.run().err().unwrap().limit, Some(Limit::Steps(1000)));
+    }
+
+    #[test]
+    fn test_for_loops() {
+        assert_results(&[
+            ("2..5", "2..5"),
+            ("for (x in 1..=3) { } x", "3"),
+            ("for (i, x in 10..20) { if (x == 15) { break; } } i", "5"),
+            (
+                "let find = fn(n) { for (x in 0..100) { if (x * x > n) { return x; } } -1 }; find(50)",
+                "8",
+            ),
+            ("for (x in 1..3) { continue; 10 } x", "2"),
+            ("let x = 7; for (x in 5..1) { } x", "7"),
+            ("let f = fn() { for (k, v in 3..5) { } k * v }; f()", "4"),
+            ("if (true) { for (x in 0..10) { break; } }", "null"),
+        ]);
+        for (input, expected) in [
+            ("for (x in 5) { }", "not iterable: INTEGER"),
+            ("1..true", "type mismatch: INTEGER .. BOOLEAN"),
+        ] {
+            assert_eq!(run(input).err().unwrap().message, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_assignment() {
+        assert_results(&[
+            ("let x = 1; x = x + 1; x", "2"),
+            ("let x = 10; x += 5; x -= 3; x *= 2; x /= 4; x", "6"),
+            ("let x = 1; let y = x = 5; x + y", "10"),
+            ("let sum = 0; for (i in 1..=10) { sum += i; } sum", "55"),
+            (
+                "let i = 0; let odd = 0; while (i < 10) { i += 1; if (i / 2 * 2 == i) { continue; } odd += 1; } odd",
+                "5",
+            ),
+            ("let n = 1; let f = fn() { n *= 3 }; f(); f(); n", "9"),
+            ("const x = 1; let x = 2; x = 3; x", "3"),
+            ("let f = fn(a) { a += 1; let b = a; b *= a; b }; f(2)", "9"),
+            (
+                "let counter = fn() { let n = 0; fn() { n += 1 } }; let c = counter(); c(); c(); c()",
+                "3",
+            ),
+            (
+                "let g = fn() { let a = 1; let f = fn() { a }; a = 2; f() }; g()",
+                "2",
+            ),
+            (
+                "let make = fn(n) { let get = fn() { n }; let set = fn(v) { n = v }; set(n + 5); get() }; make(1) + make(10)",
+                "21",
+            ),
+            (
+                "let f = fn() { let fs = 0; for (i in 0..3) { let g = fn() { i * 10 }; fs += g(); } fs }; f()",
+                "30",
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_functions_and_closures() {
+        assert_results(&[
+            ("let f = fn() { }; f()", "null"),
+            ("let f = fn(a, b) { let c = a + b; c * 2 }; f(1, 2)", "6"),
+            ("let f = fn() { return 1; 2 }; f()", "1"),
+            (
+                "let adder = fn(a) { fn(b) { fn(c) { a + b + c } } }; adder(1)(2)(3)",
+                "6",
+            ),
+            (
+                "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+                "610",
+            ),
+            (
+                "let wrapper = fn() { let count = fn(x) { if (x == 0) { 0 } else { count(x - 1) } }; count(3) }; wrapper()",
+                "0",
+            ),
+            ("puts(1)", "null"),
+            ("fn(x) { x + 1 }", "fn(x) {\n(x + 1)\n}"),
+            ("let f = fn(a, b) { fn() { a } }; f(1, 2)", "fn() {\na\n}"),
+        ]);
+    }
+
+    #[test]
+    fn test_forward_references() {
+        assert_results(&[
+            (
+                "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };\nlet odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };\neven(10)",
+                "true",
+            ),
+            (
+                "let f = fn() { let i = 0; let out = 0; while (i < 2) { if (i == 1) { out = b; } let b = i + 10; i += 1; } out }; f()",
+                "10",
+            ),
+            ("let f = fn() { g() }; let g = fn() { 5 }; f()", "5"),
+        ]);
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let tests = vec![
+            (
+                "1 + true",
+                "type mismatch: INTEGER + BOOLEAN (line 1, column 3)",
+            ),
+            (
+                "true + false",
+                "unknown operator: BOOLEAN + BOOLEAN (line 1, column 6)",
+            ),
+            ("-true", "unknown operator: -BOOLEAN (line 1, column 1)"),
+            (
+                "let a = 1;\n\nlet b = a / 0;",
+                "division by zero (line 3, column 11)",
+            ),
+            ("1 % 0", "modulo by zero (line 1, column 3)"),
+            ("2n ** 100000000", "integer too large (line 1, column 4)"),
+            (
+                "1 ..= 9223372036854775808n",
+                "range bounds must fit in 64 bits (line 1, column 3)",
+            ),
+            ("2 ** -1", "exponent out of range: -1 (line 1, column 3)"),
+            (
+                "let n = 64; 1 << n",
+                "shift amount out of range: 64 (line 1, column 15)",
+            ),
+            ("~true", "unknown operator: ~BOOLEAN (line 1, column 1)"),
+            ("1.0 / 0", "division by zero (line 1, column 5)"),
+            (
+                "1.5 & 1",
+                "unknown operator: FLOAT & INTEGER (line 1, column 5)",
+            ),
+            ("-true", "unknown operator: -BOOLEAN (line 1, column 1)"),
+            (
+                "let f = fn(a) { a };\nf()",
+                "wrong number of arguments: want=1, got=0 (line 2, column 2)",
+            ),
+            (
+                "let a = 1; a(2)",
+                "not a function: INTEGER (line 1, column 13)",
+            ),
+            (
+                "if (false) { let z = 1 } puts(z)",
+                "identifier not found: z (line 1, column 31)",
+            ),
+            (
+                "let f = fn(x) {\n  x - true\n};\nf(1)",
+                "type mismatch: INTEGER - BOOLEAN (line 2, column 5)",
+            ),
+        ];
+        for (input, expected) in tests {
+            match run(input) {
+                Ok(result) => panic!("{}: expected error, got {}", input, result.inspect()),
+                Err(e) => assert_eq!(e.to_string(), expected, "{}", input),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let frame = |function: &str, line, column| TraceFrame {
+            function: function.to_string(),
+            line,
+            column,
+        };
+        let err = run("let f = fn() { 1 / 0 };\nlet g = fn() { f() + 1 };\ng()")
+            .err()
+            .unwrap();
+        assert_eq!(err.trace, vec![frame("f", 2, 17), frame("g", 3, 2)]);
+        // 尾调用复用帧，只留下最后一次调用的位置
+        let err = run("let f = fn() { 1 / 0 };\nlet g = fn() { f() };\ng()")
+            .err()
+            .unwrap();
+        assert_eq!(err.trace, vec![frame("f", 2, 17)]);
+        let err = run("let f = fn(x) { int(x) };\nf(\"a\") + 1")
+            .err()
+            .unwrap();
+        assert_eq!(err.trace, vec![frame("int", 1, 20), frame("f", 2, 2)]);
+        assert_eq!(run("1 / 0").err().unwrap().trace, vec![]);
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        assert_results(&[
+            (
+                "let countdown = fn(n) { if (n == 0) { 0 } else { countdown(n - 1) } }; countdown(1000000)",
+                "0",
+            ),
+            (
+                "let sum = fn(n, acc) { if (n == 0) { return acc; } sum(n - 1, acc + n) }; sum(1000000, 0)",
+                "500000500000",
+            ),
+            ("let f = fn(x) { puts(x) }; f(1)", "null"),
+            (
+                "let adder = fn(a) { fn(b) { a + b } }; let f = fn(x) { adder(x)(2) }; f(1) + f(2)",
+                "7",
+            ),
+        ]);
+        let err = run("let f = fn() { f(1) }; f()").err().unwrap();
+        assert_eq!(err.message, "wrong number of arguments: want=0, got=1");
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let err = run("let f = fn(n) { 1 + f(n + 1) }; f(0)").err().unwrap();
+        assert_eq!(
+            err.message,
+            "maximum recursion depth exceeded, call chain: f (x1001)"
+        );
+        let err = run("let g = fn(n) { 1 + g(n) }; let f = fn() { g(1) + 1 }; fn() { f() + 1 }()")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.message,
+            "maximum recursion depth exceeded, call chain: <anonymous> -> f -> g (x999)"
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        let pgm = Parser::new(Lexer::new(String::from("let f = fn(n) { f(n + 1) }; f(0)")))
+            .parse_program()
+            .unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile(&pgm).unwrap();
+        let bytecode = compiler.bytecode();
+
+        let mut vm = VM::new(Bytecode {
+            instructions: bytecode.instructions.clone(),
+            positions: bytecode.positions.clone(),
+            constants: bytecode.constants.clone(),
+            global_names: bytecode.global_names.clone(),
+        });
+        vm.set_budget(Budget {
+            max_steps: Some(100),
+            ..Budget::default()
+        });
+        let err = vm.run().err().unwrap();
+        assert_eq!(err.limit, Some(Limit::Steps(100)));
+        assert_eq!(err.message, "budget exceeded: more than 100 steps executed");
+
+        // 从另一个线程取消死循环
+        let cancel = CancelHandle::new();
+        let mut vm = VM::new(bytecode);
+        vm.set_budget(Budget {
+            cancel: Some(cancel.clone()),
+            ..Budget::default()
+        });
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            cancel.cancel();
+        });
+        let err = vm.run().err().unwrap();
+        canceller.join().unwrap();
+        assert_eq!(err.limit, Some(Limit::Cancelled));
+    }
+
+    // 编译器不会生成这样的指令，但不合法的 .mkc 文件可以
+    #[test]
+    fn test_invalid_bytecode() {
+        let func: Rc<dyn Object> = Rc::new(CompiledFunction {
+            instructions: [
+                make(Opcode::OpGetFree, &[0]),
+                make(Opcode::OpReturnValue, &[]),
+            ]
+            .concat(),
+            positions: Vec::new(),
+            num_locals: 0,
+            num_params: 0,
+            name: None,
+            text: String::new(),
+        });
+        let tests = vec![
+            (vec![make(Opcode::OpPop, &[])], "stack underflow"),
+            (
+                vec![vec![Opcode::OpConstant as u8, 0]],
+                "truncated OpConstant at offset 0",
+            ),
+            (
+                vec![make(Opcode::OpNull, &[]), make(Opcode::OpTemplate, &[2])],
+                "stack underflow",
+            ),
+            (vec![make(Opcode::OpCall, &[3])], "stack underflow"),
+            (vec![make(Opcode::OpIterNext, &[0, 1])], "stack underflow"),
+            (vec![make(Opcode::OpClosure, &[0, 1])], "stack underflow"),
+            (
+                vec![make(Opcode::OpGetLocal, &[0])],
+                "invalid local variable: 0",
+            ),
+            (
+                vec![make(Opcode::OpNewCell, &[1])],
+                "invalid local variable: 1",
+            ),
+            (
+                vec![make(Opcode::OpNull, &[]), make(Opcode::OpDeref, &[])],
+                "not a cell: NULL",
+            ),
+            (
+                vec![make(Opcode::OpClosure, &[0, 0]), make(Opcode::OpCall, &[0])],
+                "invalid free variable: 0",
+            ),
+        ];
+        for (ins, expected) in tests {
+            let mut vm = VM::new(Bytecode {
+                instructions: ins.concat(),
+                positions: Vec::new(),
+                constants: vec![func.clone()],
+                global_names: Vec::new(),
+            });
+            assert_eq!(vm.run().err().unwrap().message, expected);
+        }
+
+        // 顶层的尾调用按普通调用执行
+        let yes: Rc<dyn Object> = Rc::new(CompiledFunction {
+            instructions: [make(Opcode::OpTrue, &[]), make(Opcode::OpReturnValue, &[])].concat(),
+            positions: Vec::new(),
+            num_locals: 0,
+            num_params: 0,
+            name: None,
+            text: String::new(),
+        });
+        let mut vm = VM::new(Bytecode {
+            instructions: [
+                make(Opcode::OpClosure, &[0, 0]),
+                make(Opcode::OpTailCall, &[0]),
+                make(Opcode::OpPop, &[]),
+            ]
+            .concat(),
+            positions: Vec::new(),
+            constants: vec![yes],
+            global_names: Vec::new(),
+        });
+        vm.run().unwrap();
+        assert_eq!(vm.last_popped_stack_elem().inspect(), "true");
+    }
+}